@@ -0,0 +1,216 @@
+use std::sync::Mutex;
+
+use library::Library;
+use mp3playerjack::{PlayerCommand, PlayerStatus};
+use decks::{Decks, DeckState};
+
+const DEFAULT_TRANSITION_BEATS : f64 = 32.0;
+const PHRASE_BEATS : f64 = 32.0;
+
+#[derive(Debug, Deserialize)]
+pub enum AutoDJCommand {
+    Start(usize), //playlist
+    Pause,
+    Skip,
+    Stop,
+    TransitionBeats(f64),
+}
+
+enum Phase {
+    Waiting,
+    Fading(f64, f64), //start sample, length in samples on the outgoing deck
+}
+
+pub struct AutoDJ {
+    queue: Vec<usize>,
+    next: usize,
+    current: usize,
+    cued: bool, // the idle deck holds the next track
+    phase: Option<Phase>,
+    paused: bool,
+    transition_beats: f64,
+}
+
+fn playlist_tracks(library: &Library, id: usize, out: &mut Vec<usize>) {
+    if let Some(playlist) = library.playlists.get(id) {
+        out.extend(playlist.track_keys.iter().cloned());
+        for sub in playlist.sub_playlists.iter() {
+            playlist_tracks(library, *sub, out);
+        }
+    }
+}
+
+impl AutoDJ {
+    pub fn new() -> AutoDJ {
+        AutoDJ {
+            queue: Vec::new(),
+            next: 0,
+            current: 0,
+            cued: false,
+            phase: None,
+            paused: false,
+            transition_beats: DEFAULT_TRANSITION_BEATS,
+        }
+    }
+
+    fn idle(&self) -> usize {
+        1 - self.current
+    }
+
+    fn status(&self, decks: &Decks) {
+        decks.status(PlayerStatus::AutoDJ(self.phase.is_some(), self.paused, self.queue.len() - self.next));
+    }
+
+    fn load_next(&mut self, deck: usize, decks: &Decks, library: &Mutex<Library>) -> bool {
+        let library = library.lock().unwrap();
+        while self.next < self.queue.len() {
+            let id = self.queue[self.next];
+            self.next += 1;
+            if library.tracks.contains_key(&id) {
+                decks.send(deck, PlayerCommand::Open(library.get(id)));
+                return true;
+            }
+        }
+        false
+    }
+
+    // Sample on the outgoing deck where the transition starts: the last cue in the second half of the
+    // track snapped to the beat grid, or the last phrase that leaves room for the whole transition
+    fn outro(&self, state: &DeckState) -> Option<f64> {
+        let track = state.track.as_ref()?;
+        let total = state.total_samples();
+        let beat = if track.bpm() > 0.0 { 60.0 / track.bpm() * state.sample_rate as f64 } else { state.sample_rate as f64 / 2.0 };
+        let len = self.transition_beats * beat;
+        let cue = track.cues.iter().map(|cue| cue.start as f64)
+            .filter(|&start| start > total / 2.0 && start + len <= total)
+            .fold(None, |acc : Option<f64>, start| Some(acc.map_or(start, |a| a.max(start))));
+        let (outro, grid) = match cue {
+            Some(start) => (start, beat),
+            None => (total - len, beat * PHRASE_BEATS),
+        };
+        let first_beat = track.first_beat as f64;
+        if outro <= first_beat {
+            return Some(outro.max(0.0));
+        }
+        Some(first_beat + ((outro - first_beat) / grid).floor() * grid)
+    }
+
+    fn start_fade(&mut self, decks: &Decks) {
+        let idle = self.idle();
+        let (outgoing, incoming) = (&decks.states[self.current], &decks.states[idle]);
+        if !self.cued || incoming.track.is_none() {
+            self.finish(decks);
+            return;
+        }
+        if let Some(ref track) = incoming.track {
+            if track.bpm() > 0.0 && outgoing.bpm() > 0.0 {
+                decks.send(idle, PlayerCommand::Speed(outgoing.bpm() / track.bpm()));
+            }
+        }
+        let beat = if outgoing.bpm() > 0.0 { 60.0 / outgoing.track.as_ref().unwrap().bpm() * outgoing.sample_rate as f64 } else { outgoing.sample_rate as f64 / 2.0 };
+        decks.send(idle, PlayerCommand::Volume(0.0));
+        decks.send(idle, PlayerCommand::Play(true));
+        self.phase = Some(Phase::Fading(outgoing.sample_pos, self.transition_beats * beat));
+    }
+
+    fn finish(&mut self, decks: &Decks) {
+        decks.send(self.current, PlayerCommand::Volume(1.0));
+        decks.send(self.idle(), PlayerCommand::Volume(1.0));
+        self.phase = None;
+        self.status(decks);
+    }
+
+    pub fn handle(&mut self, cmd: AutoDJCommand, decks: &mut Decks, library: &Mutex<Library>) {
+        match cmd {
+            AutoDJCommand::Start(playlist) => {
+                let mut queue = Vec::new();
+                playlist_tracks(&library.lock().unwrap(), playlist, &mut queue);
+                if queue.len() == 0 {
+                    decks.status(PlayerStatus::Print(format!("Auto DJ: playlist {} has no tracks", playlist)));
+                    return;
+                }
+                self.queue = queue;
+                self.next = 0;
+                self.paused = false;
+                self.phase = Some(Phase::Waiting);
+                self.current = if decks.states[1].playing && !decks.states[0].playing { 1 } else { 0 };
+                if !decks.states[self.current].playing {
+                    let current = self.current;
+                    self.load_next(current, decks, library);
+                    decks.send(current, PlayerCommand::Volume(1.0));
+                    decks.send(current, PlayerCommand::Play(true));
+                }
+                decks.set_master(self.current);
+                let idle = self.idle();
+                self.cued = self.load_next(idle, decks, library);
+                self.status(decks);
+            },
+            AutoDJCommand::Pause => {
+                if self.phase.is_some() {
+                    self.paused = !self.paused;
+                    self.status(decks);
+                }
+            },
+            AutoDJCommand::Skip => {
+                if let Some(Phase::Waiting) = self.phase {
+                    self.start_fade(decks);
+                }
+            },
+            AutoDJCommand::Stop => {
+                if self.phase.is_some() {
+                    self.finish(decks);
+                }
+            },
+            AutoDJCommand::TransitionBeats(beats) => {
+                if beats > 0.0 {
+                    self.transition_beats = beats;
+                }
+            },
+        }
+    }
+
+    // Called for every status of any deck
+    pub fn update(&mut self, deck: usize, decks: &mut Decks, library: &Mutex<Library>) {
+        if self.paused || deck != self.current {
+            return;
+        }
+        let fading = match self.phase {
+            Some(Phase::Waiting) => None,
+            Some(Phase::Fading(start, len)) => Some((start, len)),
+            None => return,
+        };
+        let pos = decks.states[self.current].sample_pos;
+        match fading {
+            None => {
+                if let Some(outro) = self.outro(&decks.states[self.current]) {
+                    if pos >= outro && decks.states[self.current].playing {
+                        self.start_fade(decks);
+                    }
+                }
+            },
+            Some((start, len)) => {
+                let progress = if len > 0.0 && decks.states[self.current].playing {
+                    ((pos - start) / len).max(0.0).min(1.0)
+                } else { 1.0 };
+                let idle = self.idle();
+                if progress < 1.0 {
+                    // equal power crossfade
+                    let angle = progress * ::std::f64::consts::FRAC_PI_2;
+                    decks.send(self.current, PlayerCommand::Volume(angle.cos()));
+                    decks.send(idle, PlayerCommand::Volume(angle.sin()));
+                }
+                else {
+                    decks.send(self.current, PlayerCommand::Play(false));
+                    decks.send(self.current, PlayerCommand::Volume(1.0));
+                    decks.send(idle, PlayerCommand::Volume(1.0));
+                    self.current = idle;
+                    decks.set_master(idle);
+                    let idle = self.idle();
+                    self.cued = self.load_next(idle, decks, library);
+                    self.phase = if self.cued { Some(Phase::Waiting) } else { None };
+                    self.status(decks);
+                }
+            },
+        }
+    }
+}
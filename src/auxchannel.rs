@@ -0,0 +1,210 @@
+extern crate jack;
+
+use std::f64::consts::PI;
+use std::sync::mpsc;
+use std::sync::mpsc::{Receiver, Sender, SyncSender, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+use mp3playerjack::{PlayerCommand, PlayerStatus};
+
+use jack::prelude::{AsyncClient, AudioInPort, AudioInSpec, AudioOutPort, AudioOutSpec, Client, ClosureProcessHandler,
+                    JackControl, ProcessScope, client_options, PortFlags, PortSpec};
+
+const PRINT : bool = true;
+const EQ_LOW_FREQ : f64 = 250.0;
+const EQ_MID_FREQ : f64 = 1000.0;
+const EQ_HIGH_FREQ : f64 = 4000.0;
+const TALKOVER_THRESHOLD : f32 = 0.05;
+const TALKOVER_HOLD_MS : u64 = 500;
+const TALKOVER_DUCK : f64 = 0.3;
+
+macro_rules! printinfo {
+    ($tx:expr, $($args:expr),*) => {{
+        if PRINT {
+            println!($($args),*);
+        }
+        else {
+           $tx.try_send(PlayerStatus::Print(format!($($args),*))).is_ok();
+        }
+    }};
+}
+
+#[derive(Debug, Deserialize)]
+pub enum AuxCommand {
+    Gain(f64),
+    Eq(f64, f64, f64), //low, mid, high in dB
+    Mute(bool),
+    Talkover(bool),
+}
+
+#[derive(Clone, Copy)]
+struct Biquad {
+    b0: f32, b1: f32, b2: f32, a1: f32, a2: f32,
+    z1: f32, z2: f32,
+}
+
+enum Band {
+    LowShelf,
+    Peak,
+    HighShelf,
+}
+
+impl Biquad {
+    // Filter coefficients from the RBJ audio EQ cookbook
+    fn new(band: Band, freq: f64, gain_db: f64, sample_rate: f64) -> Biquad {
+        let a = 10f64.powf(gain_db / 40.0);
+        let w0 = 2.0 * PI * freq / sample_rate;
+        let (sin, cos) = (w0.sin(), w0.cos());
+        let alpha = sin / 2.0 * 2f64.sqrt(); // Q and shelf slope of 1/sqrt(2)
+        let (b0, b1, b2, a0, a1, a2) = match band {
+            Band::Peak => (
+                1.0 + alpha * a, -2.0 * cos, 1.0 - alpha * a,
+                1.0 + alpha / a, -2.0 * cos, 1.0 - alpha / a),
+            Band::LowShelf => {
+                let s = 2.0 * a.sqrt() * alpha;
+                (a * ((a + 1.0) - (a - 1.0) * cos + s), 2.0 * a * ((a - 1.0) - (a + 1.0) * cos), a * ((a + 1.0) - (a - 1.0) * cos - s),
+                 (a + 1.0) + (a - 1.0) * cos + s, -2.0 * ((a - 1.0) + (a + 1.0) * cos), (a + 1.0) + (a - 1.0) * cos - s)
+            },
+            Band::HighShelf => {
+                let s = 2.0 * a.sqrt() * alpha;
+                (a * ((a + 1.0) + (a - 1.0) * cos + s), -2.0 * a * ((a - 1.0) + (a + 1.0) * cos), a * ((a + 1.0) + (a - 1.0) * cos - s),
+                 (a + 1.0) - (a - 1.0) * cos + s, 2.0 * ((a - 1.0) - (a + 1.0) * cos), (a + 1.0) - (a - 1.0) * cos - s)
+            },
+        };
+        Biquad {
+            b0: (b0 / a0) as f32, b1: (b1 / a0) as f32, b2: (b2 / a0) as f32,
+            a1: (a1 / a0) as f32, a2: (a2 / a0) as f32,
+            z1: 0.0, z2: 0.0,
+        }
+    }
+
+    // Takes over new coefficients but keeps the filter state to avoid clicks
+    fn retune(&mut self, other: &Biquad) {
+        let (z1, z2) = (self.z1, self.z2);
+        *self = *other;
+        self.z1 = z1;
+        self.z2 = z2;
+    }
+
+    fn process(&mut self, x: f32) -> f32 {
+        let y = self.b0 * x + self.z1;
+        self.z1 = self.b1 * x - self.a1 * y + self.z2;
+        self.z2 = self.b2 * x - self.a2 * y;
+        y
+    }
+}
+
+fn eq(low: f64, mid: f64, high: f64, sample_rate: f64) -> [Biquad; 3] {
+    [
+        Biquad::new(Band::LowShelf, EQ_LOW_FREQ, low, sample_rate),
+        Biquad::new(Band::Peak, EQ_MID_FREQ, mid, sample_rate),
+        Biquad::new(Band::HighShelf, EQ_HIGH_FREQ, high, sample_rate),
+    ]
+}
+
+pub fn run(rx: Receiver<AuxCommand>, tx_player: Sender<PlayerCommand>, tx: SyncSender<PlayerStatus>) {
+    let (tp, rp) = mpsc::sync_channel::<(f32, [Biquad; 3])>(4);
+    let (tl, rl) = mpsc::sync_channel::<f32>(4);
+
+    let client = Client::new("rustydj_aux", client_options::NO_START_SERVER).unwrap().0;
+    let l_in = client.register_port("in_l", AudioInSpec::default()).unwrap();
+    let r_in = client.register_port("in_r", AudioInSpec::default()).unwrap();
+    let mut l_chan = client.register_port("out_l", AudioOutSpec::default()).unwrap();
+    let mut r_chan = client.register_port("out_r", AudioOutSpec::default()).unwrap();
+    let in_names = [l_in.name().to_string(), r_in.name().to_string()];
+    let port_names = [l_chan.name().to_string(), r_chan.name().to_string()];
+    let sample_rate = client.sample_rate() as f64;
+
+    let mut gain = 1.0;
+    let mut filters = [eq(0.0, 0.0, 0.0, sample_rate), eq(0.0, 0.0, 0.0, sample_rate)];
+
+    let process = ClosureProcessHandler::new(move |_client: &Client, ps: &ProcessScope| -> JackControl {
+        while let Ok((gain_, eq_)) = rp.try_recv() {
+            gain = gain_;
+            for chan in filters.iter_mut() {
+                for (filter, new) in chan.iter_mut().zip(eq_.iter()) {
+                    filter.retune(new);
+                }
+            }
+        }
+
+        let l_buffer = AudioInPort::new(&l_in, ps);
+        let r_buffer = AudioInPort::new(&r_in, ps);
+        let mut l_out = AudioOutPort::new(&mut l_chan, ps);
+        let mut r_out = AudioOutPort::new(&mut r_chan, ps);
+        let mut peak : f32 = 0.0;
+        for (((l_elem, r_elem), l_src), r_src) in l_out.iter_mut().zip(r_out.iter_mut()).zip(l_buffer.iter()).zip(r_buffer.iter()) {
+            let mut l = *l_src;
+            let mut r = *r_src;
+            peak = peak.max(l.abs()).max(r.abs());
+            for filter in filters[0].iter_mut() {
+                l = filter.process(l);
+            }
+            for filter in filters[1].iter_mut() {
+                r = filter.process(r);
+            }
+            *l_elem = l * gain;
+            *r_elem = r * gain;
+        }
+        tl.try_send(peak).is_ok();
+
+        JackControl::Continue
+    });
+    let active_client = AsyncClient::new(client, (), process).unwrap();
+    for (sink,src) in active_client.ports(None, Some(AudioOutSpec::default().jack_port_type()), PortFlags::from_bits(0x4|0x1).unwrap()).iter().zip(port_names.iter()) {
+        printinfo!(tx, "Connect {} -> {}", src, sink);
+        active_client.connect_ports_by_name(src, sink).unwrap();
+    }
+    for (src,sink) in active_client.ports(None, Some(AudioInSpec::default().jack_port_type()), PortFlags::from_bits(0x4|0x2).unwrap()).iter().zip(in_names.iter()) {
+        printinfo!(tx, "Connect {} -> {}", src, sink);
+        active_client.connect_ports_by_name(src, sink).unwrap();
+    }
+
+    let mut gain = 1.0;
+    let mut muted = false;
+    let mut eq_db = (0.0, 0.0, 0.0);
+    let mut talkover = false;
+    let mut ducking = false;
+    let mut last_voice = Instant::now();
+
+    macro_rules! params {
+        () => {{
+            tp.send((if muted { 0.0 } else { gain as f32 }, eq(eq_db.0, eq_db.1, eq_db.2, sample_rate))).unwrap();
+            tx.try_send(PlayerStatus::Aux(gain, muted, talkover, ducking)).is_ok();
+        }};
+    }
+
+    macro_rules! duck {
+        ($on:expr) => {{
+            let on = $on;
+            if on != ducking {
+                ducking = on;
+                tx_player.send(PlayerCommand::Duck(if on { TALKOVER_DUCK } else { 1.0 })).is_ok();
+                tx.try_send(PlayerStatus::Aux(gain, muted, talkover, ducking)).is_ok();
+            }
+        }};
+    }
+
+    loop {
+        while let Ok(peak) = rl.try_recv() {
+            if peak > TALKOVER_THRESHOLD && !muted {
+                last_voice = Instant::now();
+            }
+        }
+        duck!(talkover && !muted && last_voice.elapsed() < Duration::from_millis(TALKOVER_HOLD_MS));
+
+        let cmd = match rx.recv_timeout(Duration::from_millis(20)) {
+            Ok(cmd) => cmd,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(_) => break,
+        };
+        match cmd {
+            AuxCommand::Gain(gain_) => gain = gain_,
+            AuxCommand::Eq(low, mid, high) => eq_db = (low, mid, high),
+            AuxCommand::Mute(mute) => muted = mute,
+            AuxCommand::Talkover(on) => talkover = on,
+        }
+        params!();
+    }
+    active_client.deactivate().unwrap();
+}
@@ -1,190 +1,1365 @@
+extern crate serde;
+extern crate serde_json;
+
 use std::sync::mpsc;
-use std::cell::Cell;
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::fs::File;
+use std::mem;
+use std::cmp::max;
+use std::sync::{Arc, Mutex};
 
-use library::Library;
-use mp3playerjack::PlayerCommand;
+use mp3playerjack::{PlayerCommand, PlayerStatus};
+use link::LinkMode;
 use ui::UICommand;
+use decks::DECKS;
+use script::{Output, Script};
+use hid::{HidDecoder, HidDevice};
+
+use self::serde::de;
+use self::serde::de::{Deserialize, DeserializeSeed, Deserializer, EnumAccess, IntoDeserializer, MapAccess, SeqAccess,
+                      VariantAccess, Visitor};
+use self::serde::forward_to_deserialize_any;
+use self::serde_json::Value;
 
 const PRINT: bool = true;
+const MAPPING_DIR: &'static str = "controllers";
+const BLINK_MS: u64 = 250; // half period of LEDs blinked in software
+const AT_CUE: f64 = 1152.0; // samples from the cue point a paused deck still counts as sitting on it
+const TAKEOVER_SLACK: f64 = 0.02; // share of a control's range it may be away from the parameter and still pick it up
+const TAKEOVER_SENT: usize = 8; // values sent recently that the parameter may still be catching up with
+const LEARN_MESSAGES: usize = 4; // CC messages looked at before guessing the encoding of a knob
+const TICK_MS: u64 = 20; // interval of the script tick
+
+macro_rules! printinfo {
+    ($txui:expr, $($args:expr),*) => {{
+        if PRINT {
+            println!($($args),*);
+        }
+        else {
+           $txui.send(UICommand::Print(format!($($args),*))).is_ok();
+        }
+    }};
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum Kind {
+    Note,
+    CC,
+    PitchBend,
+    Program,
+    HID, // a field of the mapping's HID device, number is the field's
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Absolute,
+    Relative, // ticks around the middle, 0x40 for 7 bits
+    Twos, // ticks as a two's complement of the width of the value
+    Direction, // odd values step back, even values forward
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum Curve {
+    Linear,
+    Centered, // the middle of the control maps to center, each half scales on its own
+    Pitch, // Centered over the current pitch range, around 1.0
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum Action {
+    Player(Value), // PlayerCommand with "$value", "$int", "$on" and "$index" placeholders
+    UI(Value), // UICommand, same placeholders
+    PitchRange, // cycles through the pitch ranges of the mapping
+    Shift,
+    DeckSelect, // cycles through the decks of the MIDI channel
+}
+
+fn default_encoding() -> Encoding { Encoding::Absolute }
+fn default_curve() -> Curve { Curve::Linear }
+fn default_range() -> (f64, f64) { (0.0, 1.0) }
+fn default_scale() -> f64 { 1.0 }
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Binding {
+    pub kind: Kind,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub number: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last: Option<u8>, // binds number ..= last, "$index" counts from number
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lsb: Option<u8>, // 14 bit CC: number carries the MSB, lsb the LSB
+    #[serde(default = "default_encoding")]
+    pub encoding: Encoding,
+    #[serde(default)]
+    pub deadzone: i32, // relative ticks swallowed around the center
+    #[serde(default = "default_scale")]
+    pub scale: f64, // per relative tick
+    #[serde(default = "default_curve")]
+    pub curve: Curve,
+    #[serde(default = "default_range")]
+    pub range: (f64, f64), // absolute values map onto min, max
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub center: Option<f64>,
+    #[serde(default)]
+    pub press: bool, // ignore releases
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shift: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub takeover: Option<bool>, // overrides the soft takeover of the mapping
+    pub action: Action,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum State {
+    Playing,
+    Loaded,
+    AtCue, // paused on the cue point
+    HotCue(usize), // set
+    Master,
+    Loop, // a loop is active
+    Sync, // tempo locked to the master deck
+    Link, // in a Link session with other peers
+    Shift,
+    Sampler(usize), // slot playing
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Led {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deck: Option<usize>, // None follows the deck of the channel, or the selected deck
+    pub state: State, // lit while true
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blink: Option<State>, // blinks while true and state is not
+    pub on: Vec<u8>,
+    pub off: Vec<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub flash: Option<Vec<u8>>, // lets the device blink the LED itself instead of alternating on and off
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Mapping {
+    pub name: String,
+    pub aliases: Vec<String>, // matched against the JACK port aliases
+    #[serde(default)]
+    pub pitch_ranges: Vec<(f64, f64)>,
+    pub bindings: Vec<Binding>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub feedback: Vec<Led>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub decks: Vec<Vec<usize>>, // decks each MIDI channel switches between, player actions go to the one selected
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub shift_channels: Vec<(u8, u8)>, // channel, channel whose shift it follows, for pads sending on channels of their own
+    #[serde(default)]
+    pub soft_takeover: bool, // absolute controls only act once they cross the value of their parameter
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub script: Option<String>, // Rhai file next to the mapping, run before the bindings
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hid: Option<HidDevice>,
+    #[serde(skip)]
+    pub path: Option<String>,
+}
+
+impl Mapping {
+    pub fn load(path: &str) -> Result<Mapping, String> {
+        let file = File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+        let mut mapping : Mapping = serde_json::from_reader(file).map_err(|e| format!("{}: {}", path, e))?;
+        mapping.check().map_err(|e| format!("{}: {}", path, e))?;
+        mapping.path = Some(path.to_string());
+        Ok(mapping)
+    }
+
+    // Catches action templates that do not make a command and HID fields that cannot be read when the mapping loads,
+    // rather than when the control is used
+    fn check(&self) -> Result<(), String> {
+        if let Some(field) = self.hid.as_ref().and_then(|hid| hid.fields.iter().find(|f| !f.valid())) {
+            return Err(format!("HID field {} does not fit in two bytes", field.number));
+        }
+        let args = Args { value: 0.0, on: false, index: 0 };
+        for binding in self.bindings.iter() {
+            let checked = match binding.action {
+                Action::Player(ref template) => fill::<PlayerCommand>(template, args).map(|_| ()).map_err(|e| (template, e)),
+                Action::UI(ref template) => fill::<UICommand>(template, args).map(|_| ()).map_err(|e| (template, e)),
+                _ => Ok(()),
+            };
+            checked.map_err(|(template, e)| format!("invalid action {}: {}", template, e))?;
+        }
+        Ok(())
+    }
+
+    // Empty mapping for a device without one, saved next to the others once something is learned
+    pub fn blank(name: &str) -> Mapping {
+        let file_name : String = name.chars()
+            .map(|c| if c.is_alphanumeric() { c.to_ascii_lowercase() } else { '-' }).collect();
+        Mapping {
+            name: name.to_string(),
+            aliases: vec![name.to_string()],
+            pitch_ranges: Vec::new(),
+            bindings: Vec::new(),
+            feedback: Vec::new(),
+            decks: Vec::new(),
+            shift_channels: Vec::new(),
+            soft_takeover: false,
+            script: None,
+            hid: None,
+            path: Some(format!("{}/{}.json", MAPPING_DIR, file_name)),
+        }
+    }
+
+    pub fn matches(&self, aliases: &[String]) -> bool {
+        aliases.iter().any(|a| self.aliases.iter().any(|m| a.contains(m.as_str())))
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        let path = self.path.clone().ok_or(format!("{}: mapping has no file", self.name))?;
+        fs::create_dir_all(MAPPING_DIR).is_ok();
+        let file = File::create(&path).map_err(|e| format!("{}: {}", path, e))?;
+        serde_json::to_writer_pretty(file, self).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    pub fn load_all() -> Vec<Mapping> {
+        let mut paths : Vec<_> = match fs::read_dir(MAPPING_DIR) {
+            Ok(entries) => entries.filter_map(|e| e.ok()).map(|e| e.path())
+                .filter(|p| p.extension().map_or(false, |ext| ext == "json")).collect(),
+            Err(_) => return Vec::new(),
+        };
+        paths.sort();
+        paths.iter().filter_map(|p| match Mapping::load(&p.to_string_lossy()) {
+            Ok(mapping) => Some(mapping),
+            Err(e) => {
+                println!("Could not load controller mapping {}", e);
+                None
+            },
+        }).collect()
+    }
+}
+
+// A decoded MIDI message
+#[derive(Clone)]
+struct Message {
+    kind: Kind,
+    channel: u8,
+    number: u8,
+    value: u16,
+    bits: u8,
+    on: bool,
+}
+
+fn parse(bytes: &[u8]) -> Option<Message> {
+    let status = *bytes.get(0)?;
+    let channel = status & 0x0f;
+    let data = |n: usize| bytes.get(n).cloned().unwrap_or(0);
+    match status >> 4 {
+        0x9 | 0x8 => Some(Message { kind: Kind::Note, channel: channel, number: data(1), value: data(2) as u16, bits: 7,
+                                    on: status >> 4 == 0x9 && data(2) > 0 }),
+        0xB => Some(Message { kind: Kind::CC, channel: channel, number: data(1), value: data(2) as u16, bits: 7, on: data(2) > 0 }),
+        0xC => Some(Message { kind: Kind::Program, channel: channel, number: data(1), value: data(1) as u16, bits: 7, on: true }),
+        0xE => Some(Message { kind: Kind::PitchBend, channel: channel, number: 0,
+                              value: (data(2) as u16) << 7 | data(1) as u16, bits: 14, on: true }),
+        _ => None,
+    }
+}
+
+pub fn print_midi(bytes : &[u8]) -> String {
+    let status = bytes[0];
+    let opcode = status >> 4;
+    let channel = status & 0x0f;
+
+    let mut outstr = String::new();
+    outstr.push_str("MIDI [");
+    for byte in bytes.iter() {
+        outstr.push_str(&format!("{:02X}", byte));
+    }
+    outstr.push_str("] ");
+    outstr.push_str(&format!("OP: {:X}, CN: {:X}, Rest: ", opcode, channel));
+    for byte in bytes[1 .. ].iter() {
+        outstr.push_str(&format!("{:02X}", byte));
+    }
+    outstr.push_str("| ");
+    outstr
+}
+
+pub fn substitute(template: &Value, value: f64, on: bool, index: u8) -> Value {
+    match *template {
+        Value::String(ref s) if s == "$value" => Value::from(value),
+        Value::String(ref s) if s == "$int" => Value::from(value.round() as i64),
+        Value::String(ref s) if s == "$on" => Value::from(on),
+        Value::String(ref s) if s == "$index" => Value::from(index),
+        Value::Array(ref items) => Value::Array(items.iter().map(|v| substitute(v, value, on, index)).collect()),
+        Value::Object(ref fields) => Value::Object(fields.iter().map(|(k, v)| (k.clone(), substitute(v, value, on, index))).collect()),
+        ref other => other.clone(),
+    }
+}
+
+// Placeholder values of one message
+#[derive(Debug, Clone, Copy)]
+pub struct Args {
+    pub value: f64,
+    pub on: bool,
+    pub index: u8,
+}
+
+// Deserializes an action template into its command with the placeholders filled in, without building a new JSON value
+pub fn fill<'de, T: Deserialize<'de>>(template: &'de Value, args: Args) -> Result<T, serde_json::Error> {
+    T::deserialize(Filled { template: template, args: args })
+}
+
+struct Filled<'de> {
+    template: &'de Value,
+    args: Args,
+}
+
+struct Items<'de> {
+    items: ::std::slice::Iter<'de, Value>,
+    args: Args,
+}
+
+struct Fields<'de> {
+    fields: serde_json::map::Iter<'de>,
+    value: Option<&'de Value>,
+    args: Args,
+}
+
+struct Variant<'de> {
+    name: &'de str,
+    content: &'de Value,
+    args: Args,
+}
+
+impl<'de> Deserializer<'de> for Filled<'de> {
+    type Error = serde_json::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        let args = self.args;
+        match *self.template {
+            Value::String(ref s) if s == "$value" => visitor.visit_f64(args.value),
+            Value::String(ref s) if s == "$int" => visitor.visit_i64(args.value.round() as i64),
+            Value::String(ref s) if s == "$on" => visitor.visit_bool(args.on),
+            Value::String(ref s) if s == "$index" => visitor.visit_u8(args.index),
+            Value::Array(ref items) => visitor.visit_seq(Items { items: items.iter(), args: args }),
+            Value::Object(ref fields) => visitor.visit_map(Fields { fields: fields.iter(), value: None, args: args }),
+            ref other => other.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match *self.template {
+            Value::Null => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    // Unit variants are plain strings, the others objects with the variant as their only key
+    fn deserialize_enum<V: Visitor<'de>>(self, name: &'static str, variants: &'static [&'static str], visitor: V)
+                                         -> Result<V::Value, Self::Error> {
+        match *self.template {
+            Value::Object(ref fields) if fields.len() == 1 => {
+                let (name, content) = fields.iter().next().unwrap();
+                visitor.visit_enum(Variant { name: name, content: content, args: self.args })
+            },
+            ref other => other.deserialize_enum(name, variants, visitor),
+        }
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string bytes byte_buf unit unit_struct seq tuple
+        tuple_struct map struct identifier ignored_any
+    }
+}
+
+impl<'de> SeqAccess<'de> for Items<'de> {
+    type Error = serde_json::Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>, Self::Error> {
+        match self.items.next() {
+            Some(item) => seed.deserialize(Filled { template: item, args: self.args }).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.items.len())
+    }
+}
+
+impl<'de> MapAccess<'de> for Fields<'de> {
+    type Error = serde_json::Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, Self::Error> {
+        match self.fields.next() {
+            Some((key, value)) => {
+                self.value = Some(value);
+                seed.deserialize(IntoDeserializer::<serde_json::Error>::into_deserializer(key.as_str())).map(Some)
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Self::Error> {
+        match self.value.take() {
+            Some(value) => seed.deserialize(Filled { template: value, args: self.args }),
+            None => Err(de::Error::custom("value without a key")),
+        }
+    }
+}
+
+impl<'de> EnumAccess<'de> for Variant<'de> {
+    type Error = serde_json::Error;
+    type Variant = Filled<'de>;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Filled<'de>), Self::Error> {
+        let variant = seed.deserialize(IntoDeserializer::<serde_json::Error>::into_deserializer(self.name))?;
+        Ok((variant, Filled { template: self.content, args: self.args }))
+    }
+}
+
+impl<'de> VariantAccess<'de> for Filled<'de> {
+    type Error = serde_json::Error;
+
+    fn unit_variant(self) -> Result<(), Self::Error> {
+        <()>::deserialize(self)
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, Self::Error> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, Self::Error> {
+        self.deserialize_any(visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(self, _fields: &'static [&'static str], visitor: V) -> Result<V::Value, Self::Error> {
+        self.deserialize_any(visitor)
+    }
+}
+
+#[derive(Debug, Default)]
+struct DeckFeedback {
+    playing: bool,
+    loaded: bool,
+    pos: f64,
+    cue: f64,
+    hotcues: Vec<usize>,
+    tempo: f64,
+    volume: f64,
+    looping: bool,
+    synced: bool,
+}
+
+// Cloned into the snapshot of every controller each cycle, clone_from reuses the hot cue buffers
+impl Clone for DeckFeedback {
+    fn clone(&self) -> DeckFeedback {
+        let mut deck_state = DeckFeedback::default();
+        deck_state.clone_from(self);
+        deck_state
+    }
+
+    fn clone_from(&mut self, other: &DeckFeedback) {
+        self.playing = other.playing;
+        self.loaded = other.loaded;
+        self.pos = other.pos;
+        self.cue = other.cue;
+        self.hotcues.clone_from(&other.hotcues);
+        self.tempo = other.tempo;
+        self.volume = other.volume;
+        self.looping = other.looping;
+        self.synced = other.synced;
+    }
+}
+
+// What the controller LEDs show and where soft takeover picks up, kept up to date from the player status
+#[derive(Debug)]
+pub struct Feedback {
+    decks: Vec<DeckFeedback>,
+    selected: usize,
+    master: usize,
+    link: bool,
+    samplers: HashMap<usize, bool>,
+}
+
+impl Clone for Feedback {
+    fn clone(&self) -> Feedback {
+        let mut feedback = Feedback::new();
+        feedback.clone_from(self);
+        feedback
+    }
+
+    fn clone_from(&mut self, other: &Feedback) {
+        self.decks.clone_from(&other.decks);
+        self.selected = other.selected;
+        self.master = other.master;
+        self.link = other.link;
+        self.samplers.clone_from(&other.samplers);
+    }
+}
+
+pub type SharedFeedback = Arc<Mutex<Feedback>>;
+
+impl Feedback {
+    pub fn new() -> Feedback {
+        Feedback {
+            decks: vec![DeckFeedback { tempo: 1.0, volume: 1.0, ..DeckFeedback::default() }; DECKS],
+            selected: 0,
+            master: 0,
+            link: false,
+            samplers: HashMap::new(),
+        }
+    }
+
+    pub fn update(&mut self, status: &PlayerStatus) {
+        match *status {
+            PlayerStatus::Deck(deck, ref status) => {
+                if let Some(deck_state) = self.decks.get_mut(deck) {
+                    match **status {
+                        PlayerStatus::Playing(playing) => deck_state.playing = playing,
+                        PlayerStatus::TrackInfo(ref track, _, _) => deck_state.loaded = track.is_some(),
+                        PlayerStatus::Pos(_, pos) => deck_state.pos = pos,
+                        PlayerStatus::Cue(cue) => deck_state.cue = cue,
+                        PlayerStatus::HotCues(ref set) => deck_state.hotcues = set.iter().map(|&(idx, _)| idx).collect(),
+                        PlayerStatus::Tempo(tempo) => deck_state.tempo = tempo,
+                        PlayerStatus::Volume(volume) => deck_state.volume = volume,
+                        PlayerStatus::Loop(looping) => deck_state.looping = looping.is_some(),
+                        PlayerStatus::Sync(synced) => deck_state.synced = synced,
+                        _ => (),
+                    }
+                }
+            },
+            PlayerStatus::SelectedDeck(deck) => self.selected = deck,
+            PlayerStatus::Master(deck) => self.master = deck,
+            PlayerStatus::Link(mode, peers, _, _) => self.link = mode != LinkMode::Off && peers > 0,
+            PlayerStatus::Sampler(slot, _, playing) => { self.samplers.insert(slot, playing); },
+            _ => (),
+        }
+    }
+
+    // Deck and current value of the parameter a command sets, for the parameters soft takeover knows about
+    fn parameter(&self, cmd: &PlayerCommand) -> Option<(usize, f64)> {
+        let (deck, cmd) = match *cmd {
+            PlayerCommand::Deck(deck, ref cmd) => (deck, &**cmd),
+            ref cmd => (self.selected, cmd),
+        };
+        let deck_state = self.decks.get(deck)?;
+        match *cmd {
+            PlayerCommand::Speed(_) => Some((deck, deck_state.tempo)),
+            PlayerCommand::Volume(_) => Some((deck, deck_state.volume)),
+            _ => None,
+        }
+    }
+
+    pub fn selected(&self) -> usize {
+        self.selected
+    }
+
+    pub fn state(&self, deck: usize, state: State, shift: bool) -> bool {
+        let deck_state = match self.decks.get(deck) {
+            Some(deck_state) => deck_state,
+            None => return false,
+        };
+        match state {
+            State::Playing => deck_state.playing,
+            State::Loaded => deck_state.loaded,
+            State::AtCue => deck_state.loaded && !deck_state.playing && (deck_state.pos - deck_state.cue).abs() < AT_CUE,
+            State::HotCue(idx) => deck_state.hotcues.contains(&idx),
+            State::Master => self.master == deck,
+            State::Loop => deck_state.looping,
+            State::Sync => deck_state.synced,
+            State::Link => self.link,
+            State::Shift => shift,
+            State::Sampler(slot) => self.samplers.get(&slot).cloned().unwrap_or(false),
+        }
+    }
+}
+
+// Software blink phase for a time in milliseconds
+pub fn blink_phase(ms: u64) -> bool {
+    ms / BLINK_MS % 2 == 0
+}
+
+// Index into learn_targets() of the action the next control moved on any controller gets bound to
+pub type Learn = Arc<Mutex<Option<usize>>>;
+
+// An action a control can be learned onto
+#[derive(Debug, Clone)]
+pub struct LearnTarget {
+    pub label: String,
+    action: Action,
+    continuous: bool, // faders, knobs and wheels, whose encoding is guessed from a few messages
+    curve: Curve,
+    scale: f64, // per tick if the control turns out to be relative
+    press: bool,
+}
+
+fn target(label: String, action: Action, continuous: bool, curve: Curve, scale: f64, press: bool) -> LearnTarget {
+    LearnTarget { label: label, action: action, continuous: continuous, curve: curve, scale: scale, press: press }
+}
+
+fn template(json: &str) -> Value {
+    serde_json::from_str(json).unwrap()
+}
+
+pub fn learn_targets() -> Vec<LearnTarget> {
+    let mut targets = Vec::new();
+    for deck in 0..DECKS {
+        let player = |cmd: &str| Action::Player(template(&format!(r#"{{"Deck": [{}, {}]}}"#, deck, cmd)));
+        let label = |name: &str| format!("deck {} {}", deck + 1, name);
+        targets.push(target(label("play/pause"), player(r#""PlayPause""#), false, Curve::Linear, 1.0, true));
+        targets.push(target(label("cue"), player(r#"{"Cue": "$on"}"#), false, Curve::Linear, 1.0, false));
+        for i in 0..8 {
+            targets.push(target(label(&format!("hot cue {}", i + 1)), player(&format!(r#"{{"HotCue": [{}, "$on"]}}"#, i)),
+                                false, Curve::Linear, 1.0, false));
+        }
+        targets.push(target(label("jog touch"), player(r#"{"JogTouch": "$on"}"#), false, Curve::Linear, 1.0, false));
+        targets.push(target(label("jog"), player(r#"{"Jog": "$value"}"#), true, Curve::Linear, 1.0 / 128.0, false));
+        targets.push(target(label("pitch"), player(r#"{"Speed": "$value"}"#), true, Curve::Pitch, 1.0, false));
+        targets.push(target(label("volume"), player(r#"{"Volume": "$value"}"#), true, Curve::Linear, 1.0 / 128.0, false));
+    }
+    for i in 0..8 {
+        targets.push(target(format!("sampler pad {}", i + 1),
+                            Action::Player(template(&format!(r#"{{"Sampler": {{"Trigger": [{}, "$on"]}}}}"#, i))),
+                            false, Curve::Linear, 1.0, false));
+    }
+    targets.push(target("browse".to_string(), Action::UI(template(r#"{"Scroll": "$int"}"#)), true, Curve::Linear, 1.0, false));
+    targets.push(target("load".to_string(), Action::UI(template(r#""Enter""#)), false, Curve::Linear, 1.0, true));
+    targets.push(target("back".to_string(), Action::UI(template(r#""Back""#)), false, Curve::Linear, 1.0, true));
+    targets.push(target("shift".to_string(), Action::Shift, false, Curve::Linear, 1.0, false));
+    targets.push(target("pitch range".to_string(), Action::PitchRange, false, Curve::Linear, 1.0, true));
+    targets
+}
+
+// Relative encoders repeat values around their center while an absolute control never sends the same value twice in a row
+fn guess_encoding(values: &[u16]) -> Encoding {
+    let repeats = values.windows(2).any(|w| w[0] == w[1]);
+    if repeats && values.iter().all(|&v| v >= 0x38 && v <= 0x48) {
+        Encoding::Relative
+    }
+    else if repeats && values.iter().all(|&v| v != 0 && (v < 0x10 || v >= 0x70)) {
+        Encoding::Twos
+    }
+    else {
+        Encoding::Absolute
+    }
+}
+
+// Mapping with a freshly learned binding, saved by a thread that may do file IO
+pub struct Learned {
+    pub mapping: Mapping,
+    pub label: String,
+}
 
+impl Learned {
+    pub fn save(&self, txui: &mpsc::Sender<UICommand>) {
+        let saved = self.mapping.save();
+        txui.send(UICommand::Learned(self.label.clone(), self.mapping.name.clone(), saved.err())).is_ok();
+    }
+}
 
-pub enum Controller {
-    DNSC2000 {shift: bool, pitch_range_div: u32},
-    LPK25,
+pub struct Controller {
+    pub mapping: Mapping,
+    pub saver: Option<mpsc::Sender<Learned>>, // None saves learned bindings right away
+    shift: HashMap<u8, bool>, //channel, held
+    pitch_range: usize,
+    msb: HashMap<(u8, u8), u8>, //channel, cc
+    learn: Learn,
+    targets: Vec<LearnTarget>,
+    learning: Option<usize>,
+    learned: Vec<Message>, // messages of the control being learned
+    lit: Vec<Option<Vec<u8>>>, // last message sent to each LED
+    takeover: HashMap<usize, Takeover>, //binding
+    layers: HashMap<u8, usize>, //channel, index into its decks
+    script: Option<Script>,
+    pending: Vec<Vec<u8>>, // MIDI the script sent, goes out with the LEDs
+    ticked: u64, // ms of the last script tick
+    hid: Option<HidDecoder>,
+}
+
+// Soft takeover of one absolute control over the parameter it last set
+#[derive(Default)]
+struct Takeover {
+    deck: usize,
+    picked: bool,
+    last: Option<f64>, // value of the control last time it moved
+    sent: VecDeque<f64>,
 }
 
 
 impl Controller {
-    pub fn new(aliases : Vec<String>) -> Option<Self> {
-        if aliases.iter().any(|a| {a.contains("DN-SC2000")}) {
-            return Some(Controller::DNSC2000{shift: false, pitch_range_div: 1<<4});
+    pub fn new(aliases : Vec<String>, learn: Learn) -> Option<Self> {
+        Mapping::load_all().into_iter()
+            .find(|mapping| mapping.matches(&aliases))
+            .map(|mapping| Controller::from_mapping(mapping, learn))
+    }
+
+    pub fn from_mapping(mapping: Mapping, learn: Learn) -> Self {
+        let script = mapping.script.as_ref().map(|file| Script::new(&format!("{}/{}", MAPPING_DIR, file)));
+        let hid = mapping.hid.as_ref().map(HidDecoder::new);
+        Controller {
+            mapping: mapping,
+            saver: None,
+            shift: HashMap::new(),
+            pitch_range: 0,
+            msb: HashMap::new(),
+            learn: learn,
+            targets: learn_targets(),
+            learning: None,
+            learned: Vec::new(),
+            lit: Vec::new(),
+            takeover: HashMap::new(),
+            layers: HashMap::new(),
+            script: script,
+            pending: Vec::new(),
+            ticked: 0,
+            hid: hid,
+        }
+    }
+
+    // Whether shift is held on a MIDI channel
+    fn shifted(&self, channel: u8) -> bool {
+        let channel = self.mapping.shift_channels.iter().find(|&&(c, _)| c == channel).map_or(channel, |&(_, from)| from);
+        self.shift.get(&channel).cloned().unwrap_or(false)
+    }
+
+    // Deck a MIDI channel currently controls, None if the mapping leaves it to the selected deck
+    fn deck(&self, channel: u8) -> Option<usize> {
+        let decks = self.mapping.decks.get(channel as usize)?;
+        decks.get(self.layers.get(&channel).cloned().unwrap_or(0)).cloned()
+    }
+
+    // Whether an absolute control may set its parameter, only once it picked up the current value
+    fn takes_over(&mut self, index: usize, binding: &Binding, cmd: &PlayerCommand, value: f64, feedback: &Feedback) -> bool {
+        if binding.encoding != Encoding::Absolute || !binding.takeover.unwrap_or(self.mapping.soft_takeover) {
+            return true;
+        }
+        let (deck, current) = match feedback.parameter(cmd) {
+            Some(parameter) => parameter,
+            None => return true,
+        };
+        let (min, max) = match binding.curve {
+            Curve::Pitch => self.pitch_range(),
+            _ => binding.range,
+        };
+        let slack = (max - min).abs() * TAKEOVER_SLACK;
+        let takeover = self.takeover.entry(index).or_insert_with(Takeover::default);
+        // The control moved on to another deck, or something else moved the parameter
+        if takeover.deck != deck || (takeover.picked && takeover.sent.iter().all(|sent| (sent - current).abs() > slack)) {
+            takeover.deck = deck;
+            takeover.picked = false;
+            takeover.sent.clear();
+        }
+        if !takeover.picked {
+            let crossed = takeover.last.map_or(false, |last| (last - current).signum() != (value - current).signum());
+            takeover.picked = crossed || (value - current).abs() <= slack;
         }
-        if aliases.iter().any(|a| {a.contains("LPK25")}) {
-            return Some(Controller::LPK25);
+        takeover.last = Some(value);
+        if takeover.picked {
+            if takeover.sent.len() == TAKEOVER_SENT {
+                takeover.sent.pop_front();
+            }
+            takeover.sent.push_back(value);
         }
-        None
+        takeover.picked
     }
-    
-    pub fn handle_midi(&mut self, bytes: &[u8], tx : &mpsc::Sender<PlayerCommand>, txui: &mpsc::Sender<UICommand>) {
-        macro_rules! printinfo {
-            ($($args:expr),*) => {{
-                if PRINT {
-                    println!($($args),*);
+
+    // Messages that bring the LEDs in line with the feedback state, only for LEDs that changed.
+    // With decks per channel an LED without a deck of its own is sent on each of those channels,
+    // showing the deck of the channel.
+    pub fn feedback(&mut self, feedback: &Feedback, phase: bool) -> Vec<Vec<u8>> {
+        let channels = max(self.mapping.decks.len(), 1);
+        self.lit.resize(self.mapping.feedback.len() * channels, None);
+        let mut out : Vec<Vec<u8>> = self.pending.drain(..).collect();
+        for (i, led) in self.mapping.feedback.iter().enumerate() {
+            let layered = led.deck.is_none() && self.mapping.decks.len() > 0;
+            for channel in 0..(if layered { channels } else { 1 }) {
+                let deck = if layered { self.deck(channel as u8) } else { None };
+                let deck = led.deck.or(deck).unwrap_or(feedback.selected);
+                // shift of the channel the LED is on
+                let shift = self.shifted(if layered { channel as u8 } else { led.on.get(0).map_or(0, |status| status & 0x0F) });
+                let msg = if feedback.state(deck, led.state, shift) {
+                    &led.on
+                }
+                else if led.blink.map_or(false, |blink| feedback.state(deck, blink, shift)) {
+                    match led.flash {
+                        Some(ref flash) => flash,
+                        None => if phase { &led.on } else { &led.off },
+                    }
                 }
                 else {
-                   txui.send(UICommand::Print(format!($($args),*))).is_ok();
+                    &led.off
+                };
+                let mut msg = msg.clone();
+                if layered && msg.len() > 0 && msg[0] < 0xF0 {
+                    msg[0] = msg[0] & 0xF0 | channel as u8;
+                }
+                let lit = &mut self.lit[i * channels + channel];
+                if lit.as_ref() != Some(&msg) {
+                    *lit = Some(msg.clone());
+                    out.push(msg);
                 }
-            }};
-        }
-
-        fn print_midi(bytes : &[u8]) -> String {
-            let status = bytes[0];
-            let opcode = status >> 4;
-            let channel = status & 0x0f;
-            
-            let mut outstr = String::new();
-            outstr.push_str("MIDI [");
-            for byte in bytes.iter() {
-                outstr.push_str(&format!("{:02X}", byte));
-            }
-            outstr.push_str("] ");
-            outstr.push_str(&format!("OP: {:X}, CN: {:X}, Rest: ", opcode, channel)); 
-            for byte in bytes[1 .. ].iter() {
-                outstr.push_str(&format!("{:02X}", byte));
             }
-            outstr.push_str("| ");
-            outstr
-        }
-
-        let status = bytes[0];
-        let opcode = status >> 4;
-        //let channel = status & 0x0f;
-        match self {
-            &mut Controller::LPK25 => {
-                match opcode {
-                    0x9 | 0x8 => {
-                        let note = bytes[1];
-                        let on = opcode == 0x9;
-                        let mut has_matched = true;
-                        match note {
-                            0x30 => tx.send(PlayerCommand::HotCue(0, on)).unwrap(),
-                            0x32 => tx.send(PlayerCommand::HotCue(1, on)).unwrap(),
-                            0x34 => tx.send(PlayerCommand::HotCue(2, on)).unwrap(),
-                            0x35 => tx.send(PlayerCommand::HotCue(3, on)).unwrap(),
-                            0x37 => tx.send(PlayerCommand::HotCue(4, on)).unwrap(),
-                            0x39 => tx.send(PlayerCommand::HotCue(5, on)).unwrap(),
-                            0x3B => tx.send(PlayerCommand::HotCue(6, on)).unwrap(),
-                            0x3D => tx.send(PlayerCommand::HotCue(7, on)).unwrap(),
-                            0x3E => txui.send(UICommand::Enter).unwrap(),
-                            0x3F => tx.send(PlayerCommand::PlayPause).unwrap(),
-                            _ => has_matched = false,
-                        };
-                        if on && !has_matched {
-                            match note {
-                                _ => printinfo!("MIDI CC: {:X}| ", note), 
-                            };
-                        }
-                    },
-                    0x8 => {
-                    },
-                    _ => printinfo!("{}", print_midi(bytes)),
+        }
+        out
+    }
+
+    // Binding for the control a message came from once enough of it was seen
+    fn learn(&mut self, msg: &Message, target: &LearnTarget) -> Option<Binding> {
+        let mut binding = Binding {
+            kind: msg.kind,
+            channel: Some(msg.channel),
+            number: Some(msg.number),
+            last: None,
+            lsb: None,
+            encoding: Encoding::Absolute,
+            deadzone: 0,
+            scale: 1.0,
+            curve: target.curve,
+            range: default_range(),
+            center: None,
+            press: target.press,
+            shift: if self.shifted(msg.channel) { Some(true) } else { None },
+            takeover: None,
+            action: target.action.clone(),
+        };
+        match msg.kind {
+            Kind::PitchBend => {
+                binding.number = None;
+                Some(binding)
+            },
+            Kind::CC if target.continuous => {
+                self.learned.retain(|m| m.channel == msg.channel && m.number == msg.number);
+                self.learned.push(msg.clone());
+                if self.learned.len() < LEARN_MESSAGES {
+                    return None;
                 }
+                let values : Vec<u16> = self.learned.drain(..).map(|m| m.value).collect();
+                binding.encoding = guess_encoding(&values);
+                if binding.encoding != Encoding::Absolute {
+                    binding.scale = target.scale;
+                }
+                Some(binding)
             },
-            &mut Controller::DNSC2000{ref mut shift, ref mut pitch_range_div} => {
-                const SKIP_SPEED : f64 = 4.0;
-                const PITCH_RANGES : u32 = 6;
-
-                match opcode {
-                    0x9 | 0x8 => {
-                        let note = bytes[1];
-                        let on = opcode == 0x9;
-                        let mut has_matched = true;
-                        match note {
-                            0xC => tx.send(PlayerCommand::SpeedDiff(if on {SKIP_SPEED} else {1.0})).unwrap(),
-                            0xD => tx.send(PlayerCommand::SpeedDiff(if on {-SKIP_SPEED} else {1.0})).unwrap(),
-                            0x17 => tx.send(PlayerCommand::HotCue(0, on)).unwrap(),
-                            0x18 => tx.send(PlayerCommand::HotCue(1, on)).unwrap(),
-                            0x19 => tx.send(PlayerCommand::HotCue(2, on)).unwrap(),
-                            0x20 => tx.send(PlayerCommand::HotCue(3, on)).unwrap(),
-                            0x21 => tx.send(PlayerCommand::HotCue(4, on)).unwrap(),
-                            0x22 => tx.send(PlayerCommand::HotCue(5, on)).unwrap(),
-                            0x23 => tx.send(PlayerCommand::HotCue(6, on)).unwrap(),
-                            0x24 => tx.send(PlayerCommand::HotCue(7, on)).unwrap(),
-                            0x42 => tx.send(PlayerCommand::Cue(on)).unwrap(),
-                            0x60 => *shift = on,
-                            _ => has_matched = false,
-                        };
-                        if on && !has_matched {
-                            match note {
-                                0x10 => tx.send(PlayerCommand::CueMove(true)).unwrap(),
-                                0x11 => tx.send(PlayerCommand::CueMove(false)).unwrap(),
-                                0x43 => tx.send(PlayerCommand::PlayPause).unwrap(),
-                                0x28 => txui.send(UICommand::Enter).unwrap(),
-                                0x30 => txui.send(UICommand::Back).unwrap(),
-                                0x6B => {
-                                    *pitch_range_div <<= 1;
-                                    if (*pitch_range_div & (1 << PITCH_RANGES)) > 0 {
-                                        *pitch_range_div = 1;
-                                    }
-                                    txui.send(UICommand::PitchRange(
-                                            1.0 - (0.5/ *pitch_range_div as f64), 
-                                            1.0 + (1.0/ *pitch_range_div as f64)
-                                            )).unwrap();
-                                },
-                                _ => printinfo!("MIDI CC: {:X}| ", note), 
-                            };
+            _ => if msg.on { Some(binding) } else { None },
+        }
+    }
+
+    fn learned(&mut self, binding: Binding, label: &str, txui: &mpsc::Sender<UICommand>) {
+        self.mapping.bindings.retain(|b| !(b.kind == binding.kind && b.number == binding.number && b.shift == binding.shift &&
+                                           b.channel.map_or(true, |c| Some(c) == binding.channel)));
+        self.mapping.bindings.push(binding);
+        if let Ok(mut learn) = self.learn.lock() {
+            *learn = None;
+        }
+        self.learning = None;
+        let learned = Learned { mapping: self.mapping.clone(), label: label.to_string() };
+        match self.saver {
+            Some(ref saver) => { saver.send(learned).is_ok(); },
+            None => learned.save(txui),
+        }
+    }
+
+    fn pitch_range(&self) -> (f64, f64) {
+        self.mapping.pitch_ranges.get(self.pitch_range).cloned().unwrap_or((0.5, 2.0))
+    }
+
+    // Value of a message for a binding, None if the message only carries half of a 14 bit value
+    fn value(&mut self, binding: &Binding, msg: &Message) -> Option<f64> {
+        let (raw, bits) = match (msg.kind, binding.lsb) {
+            (Kind::CC, Some(lsb)) => {
+                if msg.number != lsb {
+                    self.msb.insert((msg.channel, msg.number), msg.value as u8);
+                    return None;
+                }
+                let msb = self.msb.get(&(msg.channel, binding.number.unwrap_or(0))).cloned().unwrap_or(0);
+                ((msb as u16) << 7 | msg.value, 14)
+            },
+            (Kind::Note, _) => (if msg.on { 127 } else { 0 }, 7),
+            _ => (msg.value, msg.bits),
+        };
+        let middle = 1i32 << (bits - 1);
+        let ticks = |raw: i32| -> f64 {
+            let ticks = if raw > 0 { (raw - binding.deadzone).max(0) } else { (raw + binding.deadzone).min(0) };
+            ticks as f64 * binding.scale
+        };
+        Some(match binding.encoding {
+            Encoding::Relative => ticks(raw as i32 - middle),
+            Encoding::Twos => ticks(if raw as i32 >= middle { raw as i32 - 2 * middle } else { raw as i32 }),
+            Encoding::Direction => if raw & 0x1 == 1 { -binding.scale } else { binding.scale },
+            Encoding::Absolute => {
+                let max = ((1u32 << bits) - 1) as f64;
+                let half = (1u32 << (bits - 1)) as f64;
+                let (min, max_value) = match binding.curve {
+                    Curve::Pitch => self.pitch_range(),
+                    _ => binding.range,
+                };
+                let center = match binding.curve {
+                    Curve::Pitch => 1.0,
+                    _ => binding.center.unwrap_or((min + max_value) / 2.0),
+                };
+                match binding.curve {
+                    Curve::Linear => min + (max_value - min) * raw as f64 / max,
+                    Curve::Centered | Curve::Pitch => {
+                        if (raw as f64) < half {
+                            min + (center - min) * raw as f64 / half
                         }
-                    }, 
-                    0xB => {
-                        let control = bytes[1];
-                        let value = bytes[2];
-                        match control {
-                            0x51 => {
-                                //0x0->0x40->0x7F
-                                let value_fixed = if (value < 0x40) { value + 1 } else { value - 1 }; // no 0x40 on this wheel
-                                let speed_factor = if value < 0x40 {
-                                    (value + 0x40) as f64 / 0x80 as f64  //0.5 -> 1
-                                }
-                                else {
-                                    (value - 0x40) as f64 * (0x40 as f64 / (0x7F-0x40) as f64) / 0x40 as f64 + 1.0
-                                };
-
-                                let normalized = (value_fixed as i32 - 0x40) as f64 / 64.0; // -1 -> 1
-                                tx.send(PlayerCommand::Scratch(normalized)).unwrap();
-                                //tx.send(PlayerCommand::SpeedDiff(speed_factor)).unwrap();
-                            },
-                            0x54 => txui.send(UICommand::Scroll(if value&0x1==1 {-1} else {1})).unwrap(),
-                            _ => {
-                                printinfo!("MIDI RR: {:02X} = {:02X}| ", control, value);                            
-                            },
+                        else {
+                            center + (max_value - center) * (raw as f64 - half) / (max - half)
                         }
                     },
-                    0xD | 0xC => {
-                        //if bytes.len() == 2 {
-                        //    let encoded : u8 = bytes[1]; //0x00->0x40->0x80 
-                        //}
-                    },
-                    0xE => {
-                        if bytes.len() == 3 {
-                            let encoded : u16 = (bytes[2] as u16) << 8 | (bytes[1] as u16);//0x0000->0x4000->0x7F7F 
-                            let speed_factor = if encoded < 0x4000 {
-                                1.0 - (1.0 - ((encoded + 0x4000) as f64 / 0x8000 as f64)) / *pitch_range_div as f64  //0.5 -> 1
-                            }
-                            else {
-                                1.0 + ((encoded - 0x4000) as f64 * (0x4000 as f64 / (0x7F7F-0x4000) as f64) / 0x4000 as f64) 
-                                    / *pitch_range_div as f64
+                }
+            },
+        })
+    }
+
+    fn matches(&self, binding: &Binding, msg: &Message) -> bool {
+        if binding.kind != msg.kind || binding.channel.map_or(false, |c| c != msg.channel) {
+            return false;
+        }
+        if binding.shift.map_or(false, |shift| shift != self.shifted(msg.channel)) {
+            return false;
+        }
+        if binding.press && !msg.on {
+            return false;
+        }
+        match (binding.number, binding.last, binding.lsb) {
+            (None, _, _) => true,
+            (Some(n), Some(last), _) => msg.number >= n && msg.number <= last,
+            (Some(n), None, Some(lsb)) => msg.number == n || msg.number == lsb,
+            (Some(n), None, None) => msg.number == n,
+        }
+    }
+
+    // Runs the script tick, for LED animations
+    pub fn tick(&mut self, ms: u64, feedback: &Feedback, tx : &mpsc::Sender<PlayerCommand>, txui: &mpsc::Sender<UICommand>) {
+        if ms < self.ticked + TICK_MS {
+            return;
+        }
+        self.ticked = ms;
+        let shift = self.shift.values().any(|&held| held);
+        let out = match self.script {
+            Some(ref mut script) => script.tick(ms, feedback, shift),
+            None => return,
+        };
+        self.output(out, tx, txui);
+    }
+
+    // Carries out what the script asked for
+    fn output(&mut self, out: Vec<Output>, tx : &mpsc::Sender<PlayerCommand>, txui: &mpsc::Sender<UICommand>) {
+        for output in out {
+            match output {
+                Output::Player(cmd) => { tx.send(cmd).is_ok(); },
+                Output::UI(cmd) => { txui.send(cmd).is_ok(); },
+                Output::Midi(bytes) => self.pending.push(bytes),
+                Output::Print(message) => printinfo!(txui, "{}: {}", self.mapping.name, message),
+            }
+        }
+    }
+
+    pub fn handle_midi(&mut self, bytes: &[u8], feedback: &Feedback, tx : &mpsc::Sender<PlayerCommand>, txui: &mpsc::Sender<UICommand>) {
+        let msg = match parse(bytes) {
+            Some(msg) => msg,
+            None => return,
+        };
+        let learning = self.learn.try_lock().ok().and_then(|learn| *learn);
+        if learning != self.learning {
+            self.learning = learning;
+            self.learned.clear();
+        }
+        let is_shift = self.mapping.bindings.iter().any(|b| self.matches(b, &msg) && match b.action { Action::Shift => true, _ => false });
+        if let Some(target) = learning.and_then(|i| self.targets.get(i).cloned()) {
+            if !is_shift {
+                if let Some(binding) = self.learn(&msg, &target) {
+                    printinfo!(txui, "{}: learned {} for {}", self.mapping.name, print_midi(bytes), target.label);
+                    self.learned(binding, &target.label, txui);
+                }
+                return;
+            }
+        }
+        let shift = self.shifted(msg.channel);
+        let (handled, out) = match self.script {
+            Some(ref mut script) => script.midi(bytes, feedback, shift),
+            None => (false, Vec::new()),
+        };
+        self.output(out, tx, txui);
+        if handled {
+            return;
+        }
+        let bound = self.dispatch(&msg, feedback, tx, txui);
+        if !bound && msg.on && msg.kind != Kind::PitchBend {
+            printinfo!(txui, "{}", print_midi(bytes));
+        }
+    }
+
+    // Runs an input report of the mapping's HID device through the HID bindings
+    pub fn handle_hid(&mut self, report: &[u8], feedback: &Feedback, tx : &mpsc::Sender<PlayerCommand>, txui: &mpsc::Sender<UICommand>) {
+        let events = match self.hid {
+            Some(ref mut hid) => hid.decode(report),
+            None => return,
+        };
+        for (number, value, bits) in events {
+            let msg = Message { kind: Kind::HID, channel: 0, number: number, value: value, bits: bits, on: value != 0 };
+            self.dispatch(&msg, feedback, tx, txui);
+        }
+    }
+
+    // Carries out the bindings of a message, returns whether there were any
+    fn dispatch(&mut self, msg: &Message, feedback: &Feedback, tx : &mpsc::Sender<PlayerCommand>, txui: &mpsc::Sender<UICommand>) -> bool {
+        // the bindings are put back below, nothing on the way changes them
+        let bindings = mem::replace(&mut self.mapping.bindings, Vec::new());
+        let mut bound = false;
+        for (i, binding) in bindings.iter().enumerate() {
+            if !self.matches(binding, msg) {
+                continue;
+            }
+            bound = true;
+            let value = match self.value(binding, msg) {
+                Some(value) => value,
+                None => continue,
+            };
+            let args = Args { value: value, on: msg.on, index: msg.number.saturating_sub(binding.number.unwrap_or(0)) };
+            match binding.action {
+                Action::Player(ref template) => {
+                    match fill(template, args) {
+                        Ok(cmd) => {
+                            let cmd = match self.deck(msg.channel) {
+                                Some(deck) => PlayerCommand::Deck(deck, Box::new(cmd)),
+                                None => cmd,
                             };
-                            //println!("Speed_factor {}", speed_factor);
-                            //println!("Speed_factor {:X}", encoded);
-                            tx.send(PlayerCommand::Speed(speed_factor)).unwrap();
+                            if self.takes_over(i, binding, &cmd, value, feedback) { tx.send(cmd).is_ok(); }
+                        },
+                        Err(e) => printinfo!(txui, "{}: invalid player action {}: {}", self.mapping.name, template, e),
+                    }
+                },
+                Action::UI(ref template) => {
+                    match fill::<UICommand>(template, args) {
+                        Ok(cmd) => { txui.send(cmd).is_ok(); },
+                        Err(e) => printinfo!(txui, "{}: invalid UI action {}: {}", self.mapping.name, template, e),
+                    }
+                },
+                Action::PitchRange => {
+                    if self.mapping.pitch_ranges.len() > 0 {
+                        self.pitch_range = (self.pitch_range + 1) % self.mapping.pitch_ranges.len();
+                        let (down, up) = self.pitch_range();
+                        txui.send(UICommand::PitchRange(down, up)).is_ok();
+                    }
+                },
+                Action::Shift => { self.shift.insert(msg.channel, msg.on); },
+                Action::DeckSelect => {
+                    let count = self.mapping.decks.get(msg.channel as usize).map_or(0, |decks| decks.len());
+                    if count > 0 {
+                        let layer = self.layers.entry(msg.channel).or_insert(0);
+                        *layer = (*layer + 1) % count;
+                        if let Some(deck) = self.deck(msg.channel) {
+                            tx.send(PlayerCommand::SelectDeck(deck)).is_ok();
                         }
-                    },
-                    0xF => (),
-                    _ => {
-                        print_midi(bytes);
                     }
-                };
-            },
-        };
-    }    
+                },
+            }
+        }
+        self.mapping.bindings = bindings;
+        bound
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fmt::Debug;
+
+    fn controller(file: &str) -> Controller {
+        let mapping = Mapping::load(&format!("{}/{}", MAPPING_DIR, file)).unwrap();
+        Controller::from_mapping(mapping, Arc::new(Mutex::new(None)))
+    }
+
+    fn debug<T: Debug>(cmds: Vec<T>) -> Vec<String> {
+        cmds.iter().map(|cmd| format!("{:?}", cmd)).collect()
+    }
+
+    // Player and UI commands a recorded byte sequence results in
+    fn replay(ctrl: &mut Controller, feedback: &Feedback, messages: &[&[u8]]) -> (Vec<String>, Vec<String>) {
+        let (tx, rx) = mpsc::channel();
+        let (txui, rxui) = mpsc::channel();
+        for bytes in messages.iter() {
+            ctrl.handle_midi(bytes, feedback, &tx, &txui);
+        }
+        drop(tx);
+        drop(txui);
+        (debug(rx.iter().collect::<Vec<PlayerCommand>>()), debug(rxui.iter().collect::<Vec<UICommand>>()))
+    }
+
+    #[test]
+    fn mappings_load() {
+        let count = fs::read_dir(MAPPING_DIR).unwrap()
+            .filter(|e| e.as_ref().unwrap().path().extension().map_or(false, |ext| ext == "json")).count();
+        assert_eq!(Mapping::load_all().len(), count);
+        let learn : Learn = Arc::new(Mutex::new(None));
+        for &(port, name) in [("DN-SC2000 MIDI 1", "Denon DN-SC2000"), ("DDJ-SB MIDI 1", "Pioneer DDJ-SB"),
+                              ("Numark Mixtrack Pro MIDI 1", "Numark Mixtrack"), ("APC MINI MIDI 1", "Akai APC mini"),
+                              ("MPD218 Port A", "Akai MPD218"), ("LPK25 MIDI 1", "Akai LPK25"),
+                              ("PowerMate", "Griffin PowerMate")].iter() {
+            let ctrl = Controller::new(vec![port.to_string()], learn.clone());
+            assert_eq!(ctrl.map(|ctrl| ctrl.mapping.name), Some(name.to_string()));
+        }
+    }
+
+    #[test]
+    fn ddj_sb_transport() {
+        let mut ctrl = controller("ddj-sb.json");
+        let (cmds, _) = replay(&mut ctrl, &Feedback::new(), &[
+            &[0x90, 0x0B, 0x7F], &[0x90, 0x0B, 0x00],
+            &[0x91, 0x0C, 0x7F], &[0x91, 0x0C, 0x00],
+            &[0x91, 0x58, 0x7F],
+        ]);
+        assert_eq!(cmds, vec!["Deck(0, PlayPause)", "Deck(1, Cue(true))", "Deck(1, Cue(false))", "Deck(1, SetMaster(1))"]);
+    }
+
+    #[test]
+    fn ddj_sb_jog() {
+        let mut ctrl = controller("ddj-sb.json");
+        let (cmds, _) = replay(&mut ctrl, &Feedback::new(), &[
+            &[0x90, 0x36, 0x7F], &[0xB0, 0x22, 0x41], &[0xB0, 0x22, 0x3F], &[0x90, 0x36, 0x00],
+            &[0xB1, 0x21, 0x41],
+        ]);
+        assert_eq!(cmds, vec!["Deck(0, JogTouch(true))", "Deck(0, Jog(0.0013888889))", "Deck(0, Jog(-0.0013888889))",
+                              "Deck(0, JogTouch(false))", "Deck(1, Jog(0.0013888889))"]);
+    }
+
+    #[test]
+    fn ddj_sb_mixer() {
+        let mut ctrl = controller("ddj-sb.json");
+        let (cmds, _) = replay(&mut ctrl, &Feedback::new(), &[
+            // 14 bit fader at the bottom is ignored until it picks up the current volume
+            &[0xB0, 0x13, 0x00], &[0xB0, 0x33, 0x00],
+            &[0xB0, 0x13, 0x7F], &[0xB0, 0x33, 0x7F],
+            // tempo slider in the middle is the current tempo
+            &[0xB1, 0x00, 0x40], &[0xB1, 0x20, 0x00],
+        ]);
+        assert_eq!(cmds, vec!["Deck(0, Volume(1.0))", "Deck(1, Speed(1.0))"]);
+    }
+
+    #[test]
+    fn ddj_sb_pads() {
+        let mut ctrl = controller("ddj-sb.json");
+        let (cmds, _) = replay(&mut ctrl, &Feedback::new(), &[
+            &[0x97, 0x02, 0x7F], &[0x97, 0x02, 0x00],
+            &[0x91, 0x3F, 0x7F], &[0x99, 0x01, 0x7F], &[0x99, 0x01, 0x00], &[0x91, 0x3F, 0x00],
+            &[0x90, 0x3F, 0x7F], &[0x99, 0x02, 0x7F], &[0x90, 0x3F, 0x00],
+            &[0x99, 0x31, 0x7F],
+        ]);
+        assert_eq!(cmds, vec!["Deck(0, HotCue(2, true))", "Deck(0, HotCue(2, false))", "Deck(1, DeleteHotCue(1))",
+                              "Deck(1, HotCue(2, true))", "Sampler(Trigger(5, true))"]);
+    }
+
+    #[test]
+    fn ddj_sb_browse() {
+        let mut ctrl = controller("ddj-sb.json");
+        let (cmds, ui) = replay(&mut ctrl, &Feedback::new(), &[
+            &[0xB6, 0x40, 0x01], &[0xB6, 0x40, 0x7F], &[0x96, 0x47, 0x7F], &[0x96, 0x47, 0x00],
+        ]);
+        assert_eq!(cmds, vec!["SelectDeck(1)"]);
+        assert_eq!(ui, vec!["Scroll(1)", "Scroll(-1)", "Enter"]);
+    }
+
+    #[test]
+    fn ddj_sb_leds() {
+        let mut ctrl = controller("ddj-sb.json");
+        let mut feedback = Feedback::new();
+        feedback.update(&PlayerStatus::Deck(1, Box::new(PlayerStatus::Playing(true))));
+        feedback.update(&PlayerStatus::Deck(1, Box::new(PlayerStatus::HotCues(vec![(2, 0.0)]))));
+        let out = ctrl.feedback(&feedback, true);
+        assert!(out.contains(&vec![0x90, 0x0B, 0x00]));
+        assert!(out.contains(&vec![0x91, 0x0B, 0x7F]));
+        assert!(out.contains(&vec![0x99, 0x02, 0x7F]));
+        assert!(out.contains(&vec![0x97, 0x02, 0x00]));
+        assert!(!out.contains(&vec![0x90, 0x02, 0x7F]));
+        assert_eq!(ctrl.feedback(&feedback, true).len(), 0);
+    }
+
+    #[test]
+    fn mixtrack() {
+        let mut ctrl = controller("mixtrack.json");
+        let (cmds, ui) = replay(&mut ctrl, &Feedback::new(), &[
+            // jog through mixtrack.rhai, which speeds it up with the ticks per message
+            &[0x90, 0x42, 0x7F], &[0x90, 0x4E, 0x7F], &[0xB0, 0x19, 0x7F], &[0xB0, 0x19, 0x02],
+            &[0xB0, 0x0E, 0x40], &[0xB0, 0x08, 0x7F],
+            &[0x90, 0x5B, 0x7F], &[0x90, 0x63, 0x7F], &[0x90, 0x5B, 0x7F], &[0x90, 0x63, 0x00],
+            &[0xB0, 0x1A, 0x7E], &[0x90, 0x4B, 0x7F],
+        ]);
+        assert_eq!(cmds, vec!["Deck(1, PlayPause)", "Deck(0, JogTouch(true))", "Deck(0, Jog(-0.015))", "Deck(0, Jog(0.04))",
+                              "Deck(1, Speed(1.0))", "Deck(0, Volume(1.0))",
+                              "Deck(0, HotCue(1, true))", "Deck(0, DeleteHotCue(1))", "SelectDeck(0)"]);
+        assert_eq!(ui, vec!["Scroll(-2)", "Enter"]);
+    }
+
+    #[test]
+    fn apc_mini() {
+        let mut ctrl = controller("apc-mini.json");
+        let (cmds, ui) = replay(&mut ctrl, &Feedback::new(), &[
+            &[0x90, 0x13, 0x7F], &[0x80, 0x13, 0x7F], &[0x90, 0x3A, 0x7F], &[0x90, 0x41, 0x7F],
+            &[0xB0, 0x31, 0x00], &[0xB0, 0x31, 0x7F], &[0xB0, 0x36, 0x40],
+            &[0x90, 0x62, 0x7F], &[0x90, 0x08, 0x7F], &[0x90, 0x62, 0x00], &[0x90, 0x53, 0x7F],
+        ]);
+        assert_eq!(cmds, vec!["Deck(2, HotCue(3, true))", "Deck(2, HotCue(3, false))", "Sampler(Trigger(2, true))",
+                              "Deck(1, PlayPause)", "Deck(1, Volume(1.0))", "Deck(2, Speed(1.0))", "Deck(1, DeleteHotCue(0))"]);
+        assert_eq!(ui, vec!["Scroll(1)"]);
+    }
+
+    #[test]
+    fn powermate() {
+        let mut ctrl = controller("powermate.json");
+        let (tx, rx) = mpsc::channel();
+        let (txui, _rxui) = mpsc::channel();
+        for report in [[0, 0, 0, 0, 0, 0], [0, 2, 0, 0, 0, 0], [1, 0, 0, 0, 0, 0], [0, 0, 0, 0, 0, 0], [0, 0xFD, 0, 0, 0, 0]].iter() {
+            ctrl.handle_hid(report, &Feedback::new(), &tx, &txui);
+        }
+        drop(tx);
+        assert_eq!(debug(rx.iter().collect::<Vec<PlayerCommand>>()), vec!["Jog(0.02)", "PlayPause", "Jog(-0.03)"]);
+    }
+
+    #[test]
+    fn mpd218() {
+        let mut ctrl = controller("mpd218.json");
+        let (cmds, _) = replay(&mut ctrl, &Feedback::new(), &[
+            &[0x99, 0x24, 0x64], &[0x89, 0x24, 0x00], &[0x99, 0x2D, 0x50], &[0xB9, 0x09, 0x7F],
+        ]);
+        assert_eq!(cmds, vec!["Sampler(Trigger(0, true))", "Sampler(Trigger(0, false))", "HotCue(1, true)",
+                              "Deck(1, Volume(1.0))"]);
+    }
+
+    #[test]
+    fn lpk25() {
+        let mut ctrl = controller("lpk25.json");
+        let (cmds, ui) = replay(&mut ctrl, &Feedback::new(), &[
+            &[0x90, 0x32, 0x7F], &[0x80, 0x32, 0x00], &[0x90, 0x3E, 0x7F], &[0x80, 0x3E, 0x00], &[0x90, 0x3F, 0x7F], &[0x80, 0x3F, 0x00],
+        ]);
+        assert_eq!(cmds, vec!["HotCue(1, true)", "HotCue(1, false)", "PlayPause"]);
+        assert_eq!(ui, vec!["Enter"]);
+    }
+
+    #[test]
+    fn fill_templates() {
+        let args = Args { value: 0.75, on: true, index: 3 };
+        for json in [r#"{"Deck": [1, {"HotCue": ["$index", "$on"]}]}"#, r#"{"Volume": "$value"}"#, r#""PlayPause""#,
+                     r#"{"Sampler": {"Load": [2, "kick.wav", null]}}"#, r#"{"Timecode": ["$value", "$value"]}"#,
+                     r#"{"Link": {"Mode": "Lead"}}"#].iter() {
+            let template = template(json);
+            let filled : PlayerCommand = fill(&template, args).unwrap();
+            let substituted : PlayerCommand = serde_json::from_value(substitute(&template, 0.75, true, 3)).unwrap();
+            assert_eq!(format!("{:?}", filled), format!("{:?}", substituted));
+        }
+        assert_eq!(format!("{:?}", fill::<UICommand>(&template(r#"{"Scroll": "$int"}"#), Args { value: -2.4, ..args }).unwrap()),
+                   "Scroll(-2)");
+        assert!(fill::<PlayerCommand>(&template(r#"{"SelectDeck": "$value"}"#), args).is_err());
+        assert!(fill::<PlayerCommand>(&template(r#"{"Nope": 1}"#), args).is_err());
+    }
+
+    #[test]
+    fn learn_hands_mapping_to_saver() {
+        let learn : Learn = Arc::new(Mutex::new(Some(0)));
+        let mut ctrl = Controller::from_mapping(Mapping::blank("Learn Test"), learn.clone());
+        let (saver, rs) = mpsc::channel();
+        ctrl.saver = Some(saver);
+        let (cmds, ui) = replay(&mut ctrl, &Feedback::new(), &[&[0x92, 0x24, 0x7F], &[0x92, 0x24, 0x7F]]);
+        assert_eq!(cmds, vec!["Deck(0, PlayPause)"]);
+        assert!(ui.is_empty());
+        let learned = rs.try_recv().unwrap();
+        assert_eq!(learned.label, "deck 1 play/pause");
+        assert_eq!(learned.mapping.bindings.len(), 1);
+        assert_eq!(*learn.lock().unwrap(), None);
+        assert!(fs::metadata(learned.mapping.path.unwrap()).is_err());
+    }
+
+    #[test]
+    fn loop_and_sync_states() {
+        let mut feedback = Feedback::new();
+        feedback.update(&PlayerStatus::Deck(1, Box::new(PlayerStatus::Loop(Some((0.0, 44100.0))))));
+        feedback.update(&PlayerStatus::Deck(1, Box::new(PlayerStatus::Sync(true))));
+        assert!(feedback.state(1, State::Loop, false) && feedback.state(1, State::Sync, false));
+        assert!(!feedback.state(0, State::Loop, false) && !feedback.state(0, State::Sync, false));
+        feedback.update(&PlayerStatus::Deck(1, Box::new(PlayerStatus::Loop(None))));
+        assert!(!feedback.state(1, State::Loop, false));
+    }
+
+    // Fader on CC 7 with soft takeover, and whether each value it moves to gets through to the volume
+    fn takeover(feedback: &Feedback, values: &[f64]) -> Vec<bool> {
+        let mut ctrl = Controller::from_mapping(Mapping::blank("Takeover Test"), Arc::new(Mutex::new(None)));
+        ctrl.mapping.soft_takeover = true;
+        let binding : Binding = serde_json::from_str(r#"{"kind": "CC", "number": 7, "action": {"Player": {"Volume": "$value"}}}"#).unwrap();
+        values.iter().map(|&value| ctrl.takes_over(0, &binding, &PlayerCommand::Volume(value), value, feedback)).collect()
+    }
+
+    #[test]
+    fn takeover_picks_up_nearby() {
+        let mut feedback = Feedback::new();
+        feedback.update(&PlayerStatus::Deck(0, Box::new(PlayerStatus::Volume(0.5))));
+        assert_eq!(takeover(&feedback, &[0.1, 0.3, 0.49, 0.6, 0.2]), vec![false, false, true, true, true]);
+    }
+
+    #[test]
+    fn takeover_picks_up_crossing() {
+        let mut feedback = Feedback::new();
+        feedback.update(&PlayerStatus::Deck(0, Box::new(PlayerStatus::Volume(0.5))));
+        assert_eq!(takeover(&feedback, &[0.9, 0.7, 0.3]), vec![false, false, true]);
+        // the first value seen has nothing to cross from
+        assert_eq!(takeover(&feedback, &[0.3]), vec![false]);
+    }
+
+    #[test]
+    fn takeover_resets_after_external_change() {
+        let mut ctrl = Controller::from_mapping(Mapping::blank("Takeover Test"), Arc::new(Mutex::new(None)));
+        ctrl.mapping.soft_takeover = true;
+        let binding : Binding = serde_json::from_str(r#"{"kind": "CC", "number": 7, "action": {"Player": {"Volume": "$value"}}}"#).unwrap();
+        let mut feedback = Feedback::new();
+        let mut moved = |value: f64, feedback: &Feedback| ctrl.takes_over(0, &binding, &PlayerCommand::Volume(value), value, feedback);
+        assert!(moved(1.0, &feedback));
+        feedback.update(&PlayerStatus::Deck(0, Box::new(PlayerStatus::Volume(0.8))));
+        // the player catching up with a value sent keeps the control picked up
+        assert!(moved(0.8, &feedback));
+        // something else moved the volume, the fader has to pick it up again
+        feedback.update(&PlayerStatus::Deck(0, Box::new(PlayerStatus::Volume(0.3))));
+        assert!(!moved(0.7, &feedback));
+        assert!(!moved(0.6, &feedback));
+        assert!(moved(0.25, &feedback));
+        // another deck is a different parameter
+        feedback.update(&PlayerStatus::SelectedDeck(1));
+        assert!(!moved(0.5, &feedback));
+    }
+
+    #[test]
+    fn dn_sc2000_deck_select() {
+        let mut ctrl = controller("dn-sc2000.json");
+        let (cmds, _) = replay(&mut ctrl, &Feedback::new(), &[
+            &[0x90, 0x17, 0x7F], &[0x91, 0x17, 0x7F],
+            &[0x90, 0x66, 0x7F], &[0x80, 0x66, 0x00], &[0x90, 0x17, 0x7F], &[0x91, 0x17, 0x7F],
+            &[0x91, 0x66, 0x7F], &[0x91, 0x17, 0x7F],
+            &[0x90, 0x66, 0x7F], &[0x90, 0x17, 0x7F],
+        ]);
+        assert_eq!(cmds, vec!["Deck(0, HotCue(0, true))", "Deck(1, HotCue(0, true))",
+                              "SelectDeck(2)", "Deck(2, HotCue(0, true))", "Deck(1, HotCue(0, true))",
+                              "SelectDeck(3)", "Deck(3, HotCue(0, true))",
+                              "SelectDeck(0)", "Deck(0, HotCue(0, true))"]);
+    }
+
+    #[test]
+    fn dn_sc2000_shift() {
+        let mut ctrl = controller("dn-sc2000.json");
+        let (cmds, _) = replay(&mut ctrl, &Feedback::new(), &[
+            // shift held on the left deck only
+            &[0x90, 0x60, 0x7F],
+            &[0x90, 0x0C, 0x7F], &[0x80, 0x0C, 0x00], &[0x90, 0x0D, 0x7F],
+            &[0x90, 0x18, 0x7F], &[0x80, 0x18, 0x00], &[0x91, 0x18, 0x7F],
+            &[0x80, 0x60, 0x00],
+            &[0x90, 0x18, 0x7F],
+        ]);
+        assert_eq!(cmds, vec!["Deck(0, BeatJump(4.0))", "Deck(0, BeatJump(-4.0))",
+                              "Deck(0, DeleteHotCue(1))", "Deck(1, HotCue(1, true))",
+                              "Deck(0, HotCue(1, true))"]);
+        replay(&mut ctrl, &Feedback::new(), &[&[0x91, 0x60, 0x7F]]);
+        let out = ctrl.feedback(&Feedback::new(), true);
+        assert!(out.contains(&vec![0xB0, 0x4B, 0x3C]));
+        assert!(out.contains(&vec![0xB1, 0x4A, 0x3C]));
+    }
 }
@@ -0,0 +1,273 @@
+use std::sync::mpsc;
+use std::sync::mpsc::{Receiver, Sender, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use library::{Library, Track};
+use mp3playerjack::{Mp3Player, PlayerCommand, PlayerStatus};
+use sampler::SamplerCommand;
+use autodj::AutoDJ;
+use auxchannel::AuxCommand;
+use dvs::DvsCommand;
+use link::LinkCommand;
+use midiclock::ClockCommand;
+
+pub const DECKS : usize = 4;
+
+enum Event {
+    Command(PlayerCommand),
+    Status(usize, PlayerStatus),
+}
+
+pub struct DeckState {
+    pub track: Option<Track>,
+    pub duration: Duration,
+    pub sample_rate: u32,
+    pub sample_pos: f64,
+    pub speed: f64,
+    pub playing: bool,
+}
+
+impl DeckState {
+    pub fn new() -> DeckState {
+        DeckState {
+            track: None,
+            duration: Duration::default(),
+            sample_rate: 0,
+            sample_pos: 0.0,
+            speed: 1.0,
+            playing: false,
+        }
+    }
+
+    pub fn update(&mut self, status: &PlayerStatus) {
+        match *status {
+            PlayerStatus::TrackInfo(ref track, duration, sample_rate) => {
+                self.track = track.clone();
+                self.duration = duration;
+                self.sample_rate = sample_rate;
+            },
+            PlayerStatus::Pos(_, sample_pos) => self.sample_pos = sample_pos,
+            PlayerStatus::Speed(speed) => self.speed = speed,
+            PlayerStatus::Playing(playing) => self.playing = playing,
+            _ => (),
+        }
+    }
+
+    pub fn bpm(&self) -> f64 {
+        self.track.as_ref().map_or(0.0, |track| track.bpm() * self.speed)
+    }
+
+    // Beats since the first beat of the grid
+    pub fn beat(&self) -> Option<f64> {
+        let track = self.track.as_ref()?;
+        if track.bpm() <= 0.0 || self.sample_rate == 0 {
+            return None;
+        }
+        Some((self.sample_pos - track.first_beat as f64) / (60.0 / track.bpm() * self.sample_rate as f64))
+    }
+
+    pub fn total_samples(&self) -> f64 {
+        (self.duration.as_secs() as f64 + self.duration.subsec_nanos() as f64 / 1e9) * self.sample_rate as f64
+    }
+}
+
+pub struct Decks {
+    decks: Vec<Sender<PlayerCommand>>,
+    pub states: Vec<DeckState>,
+    pub selected: usize,
+    pub master: usize,
+    master_bpm: f64,
+    synced: Vec<bool>, // decks following the tempo of the master
+    tx: SyncSender<PlayerStatus>,
+    sampler: Sender<SamplerCommand>,
+    aux: Sender<AuxCommand>,
+    dvs: Sender<DvsCommand>,
+    link: Sender<LinkCommand>,
+    clock: Sender<ClockCommand>,
+}
+
+impl Decks {
+    pub fn send(&self, deck: usize, cmd: PlayerCommand) {
+        if let Some(deck_tx) = self.decks.get(deck) {
+            deck_tx.send(cmd).is_ok();
+        }
+    }
+
+    pub fn status(&self, status: PlayerStatus) {
+        self.tx.send(status).is_ok();
+    }
+
+    pub fn set_master(&mut self, deck: usize) {
+        if deck < self.decks.len() && deck != self.master {
+            self.master = deck;
+            self.status(PlayerStatus::Master(deck));
+            self.update_master_bpm();
+        }
+    }
+
+    fn update_master_bpm(&mut self) {
+        let bpm = self.states[self.master].bpm();
+        if bpm != self.master_bpm {
+            self.master_bpm = bpm;
+            self.sampler.send(SamplerCommand::MasterBpm(bpm)).is_ok();
+            for deck in 0 .. self.decks.len() {
+                self.sync(deck);
+            }
+        }
+    }
+
+    // Sets a synced deck to the tempo of the master, the master itself keeps its own
+    fn sync(&self, deck: usize) {
+        if !self.synced[deck] || deck == self.master || self.master_bpm <= 0.0 {
+            return;
+        }
+        let bpm = self.states[deck].track.as_ref().map_or(0.0, |track| track.bpm());
+        if bpm > 0.0 {
+            self.send(deck, PlayerCommand::Speed(self.master_bpm / bpm));
+        }
+    }
+
+    fn handle_command(&mut self, cmd: PlayerCommand, deck: Option<usize>, autodj: &mut AutoDJ, library: &Mutex<Library>) {
+        match cmd {
+            PlayerCommand::Deck(deck, cmd) => self.handle_command(*cmd, Some(deck), autodj, library),
+            PlayerCommand::SelectDeck(deck) => {
+                if deck < self.decks.len() {
+                    self.selected = deck;
+                    self.status(PlayerStatus::SelectedDeck(deck));
+                }
+            },
+            PlayerCommand::SetMaster(deck) => self.set_master(deck),
+            PlayerCommand::Sync(on) => {
+                let deck = deck.unwrap_or(self.selected);
+                if deck < self.decks.len() {
+                    self.synced[deck] = on;
+                    self.status(PlayerStatus::Deck(deck, Box::new(PlayerStatus::Sync(on))));
+                    self.sync(deck);
+                }
+            },
+            PlayerCommand::Sampler(cmd) => { self.sampler.send(cmd).is_ok(); },
+            PlayerCommand::Aux(cmd) => { self.aux.send(cmd).is_ok(); },
+            PlayerCommand::Dvs(cmd) => { self.dvs.send(cmd).is_ok(); },
+            PlayerCommand::Link(cmd) => { self.link.send(cmd).is_ok(); },
+            PlayerCommand::Clock(cmd) => { self.clock.send(cmd).is_ok(); },
+            PlayerCommand::Duck(duck) => {
+                for deck in 0 .. self.decks.len() {
+                    self.send(deck, PlayerCommand::Duck(duck));
+                }
+            },
+            PlayerCommand::Crossfader(position) => {
+                // equal power
+                let angle = position.max(0.0).min(1.0) * ::std::f64::consts::FRAC_PI_2;
+                for deck in 0 .. self.decks.len() {
+                    self.send(deck, PlayerCommand::Fade(if deck % 2 == 0 { angle.cos() } else { angle.sin() }));
+                }
+                self.status(PlayerStatus::Crossfader(position));
+            },
+            PlayerCommand::AutoDJ(cmd) => autodj.handle(cmd, self, library),
+            PlayerCommand::Double(source) => {
+                let deck = deck.unwrap_or(self.selected);
+                if source != deck && source < self.decks.len() {
+                    let target = self.decks[deck].clone();
+                    self.send(source, PlayerCommand::CloneTo(target));
+                }
+            },
+            cmd => {
+                let deck = deck.unwrap_or(self.selected);
+                self.send(deck, cmd);
+            },
+        }
+    }
+
+    fn handle_status(&mut self, deck: usize, status: PlayerStatus, autodj: &mut AutoDJ, library: &Mutex<Library>) {
+        self.states[deck].update(&status);
+        if let PlayerStatus::TrackInfo(..) = status {
+            self.sync(deck);
+        }
+        if deck == self.master {
+            if let PlayerStatus::TrackInfo(..) | PlayerStatus::Speed(_) = status {
+                self.update_master_bpm();
+            }
+            let state = &self.states[deck];
+            if let (Some(beat), Some(ref track)) = (state.beat(), state.track.as_ref()) {
+                self.link.send(LinkCommand::Master(deck, track.bpm(), state.speed, beat, state.playing)).is_ok();
+            }
+            if state.track.is_some() && state.sample_rate > 0 {
+                let secs = state.sample_pos / state.sample_rate as f64;
+                self.clock.send(ClockCommand::Master(state.bpm(), state.beat().unwrap_or(0.0), secs, state.speed, state.playing)).is_ok();
+            }
+        }
+        let wrapped = PlayerStatus::Deck(deck, Box::new(status));
+        match wrapped {
+            PlayerStatus::Deck(_, ref status) if is_frequent(status) => { self.tx.try_send(wrapped).is_ok(); },
+            _ => self.status(wrapped),
+        }
+        autodj.update(deck, self, library);
+    }
+}
+
+fn is_frequent(status: &PlayerStatus) -> bool {
+    match *status {
+        PlayerStatus::Pos(..) | PlayerStatus::Speed(_) => true,
+        PlayerStatus::Level(level) => level > 0.0,
+        _ => false,
+    }
+}
+
+pub fn run(rx: Receiver<PlayerCommand>, tx: SyncSender<PlayerStatus>, sampler: Sender<SamplerCommand>, aux: Sender<AuxCommand>,
+           dvs: Sender<DvsCommand>,
+           link: Sender<LinkCommand>, clock: Sender<ClockCommand>, library: Arc<Mutex<Library>>) {
+    let (te, re) = mpsc::channel::<Event>();
+
+    let mut deck_txs = Vec::new();
+    for deck in 0 .. DECKS {
+        let (tx_d, rx_d) = mpsc::channel::<PlayerCommand>();
+        let (tx_ds, rx_ds) = mpsc::sync_channel::<PlayerStatus>(20);
+        let mut player = Mp3Player::new(deck);
+        player.set_sampler(sampler.clone());
+        thread::spawn(move || {
+            player.play(rx_d, tx_ds);
+        });
+        let te_ = te.clone();
+        thread::spawn(move || {
+            for status in rx_ds.iter() {
+                if te_.send(Event::Status(deck, status)).is_err() {
+                    return;
+                }
+            }
+        });
+        deck_txs.push(tx_d);
+    }
+
+    thread::spawn(move || {
+        for cmd in rx.iter() {
+            if te.send(Event::Command(cmd)).is_err() {
+                return;
+            }
+        }
+    });
+
+    let mut decks = Decks {
+        decks: deck_txs,
+        states: (0 .. DECKS).map(|_| DeckState::new()).collect(),
+        selected: 0,
+        master: 0,
+        master_bpm: 0.0,
+        synced: vec![false; DECKS],
+        tx: tx,
+        sampler: sampler,
+        aux: aux,
+        dvs: dvs,
+        link: link,
+        clock: clock,
+    };
+    let mut autodj = AutoDJ::new();
+
+    for event in re.iter() {
+        match event {
+            Event::Command(cmd) => decks.handle_command(cmd, None, &mut autodj, &library),
+            Event::Status(deck, status) => decks.handle_status(deck, status, &mut autodj, &library),
+        }
+    }
+}
@@ -0,0 +1,460 @@
+extern crate jack;
+
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::sync::mpsc;
+use std::sync::mpsc::{Receiver, Sender, SyncSender, RecvTimeoutError, TrySendError};
+use std::time::Duration;
+
+use mp3playerjack::{PlayerCommand, PlayerStatus};
+
+use jack::prelude::{AsyncClient, AudioInPort, AudioInSpec, Client, ClosureProcessHandler,
+                    JackControl, ProcessScope, client_options};
+
+const PRINT : bool = true;
+const ZERO_THRESHOLD : f32 = 0.01;
+const SPEED_WINDOW : usize = 8; // zero crossings, two cycles
+const REF_PEAKS_AVG : f32 = 48.0;
+const STOP_SECS : f64 = 0.05; // no crossing for this long means the record stopped
+const LOW_AMPLITUDE : f32 = 0.7; // synthesized level of a 0 bit relative to a 1 bit
+const SPEED_THRESHOLD : f64 = 0.002; // smallest speed change forwarded to the deck
+
+macro_rules! printinfo {
+    ($tx:expr, $($args:expr),*) => {{
+        if PRINT {
+            println!($($args),*);
+        }
+        else {
+           $tx.try_send(PlayerStatus::Print(format!($($args),*))).is_ok();
+        }
+    }};
+}
+
+// Timecode layouts, parameters as published by the xwax project
+pub struct TimecodeDef {
+    pub name: &'static str,
+    pub resolution: u32, // carrier cycles per second
+    pub bits: u32,
+    pub seed: u32,
+    pub taps: u32,
+    pub length: u32, // cycles on the record
+    pub safe: u32, // last cycle before the lead out
+    pub swap: bool, // primary channel is the right one
+    pub polarity: bool, // bits are read on the negative half wave
+}
+
+pub const TIMECODES : [TimecodeDef; 3] = [
+    TimecodeDef { name: "serato_2a", resolution: 1000, bits: 20, seed: 0x59017, taps: 0x361e4,
+        length: 712000, safe: 625000, swap: false, polarity: false },
+    TimecodeDef { name: "serato_2b", resolution: 1000, bits: 20, seed: 0x8f3c6, taps: 0x4f0d8,
+        length: 922000, safe: 905000, swap: false, polarity: false },
+    TimecodeDef { name: "traktor_a", resolution: 2000, bits: 23, seed: 0x134503, taps: 0x041040,
+        length: 1500000, safe: 605000, swap: true, polarity: true },
+];
+
+pub fn timecode(name: &str) -> Option<&'static TimecodeDef> {
+    TIMECODES.iter().find(|def| def.name == name)
+}
+
+fn lfsr(code: u32, taps: u32) -> u32 {
+    (code & taps).count_ones() & 0x1
+}
+
+impl TimecodeDef {
+    fn mask(&self) -> u32 {
+        (1 << self.bits) - 1
+    }
+
+    // New bits are added at the MSB when moving forward
+    fn fwd(&self, current: u32) -> u32 {
+        let l = lfsr(current, self.taps | 0x1);
+        (current >> 1) | (l << (self.bits - 1))
+    }
+
+    // and at the LSB when moving backwards
+    fn rev(&self, current: u32) -> u32 {
+        let l = lfsr(current, (self.taps >> 1) | (0x1 << (self.bits - 1)));
+        ((current << 1) & self.mask()) | l
+    }
+
+    // Bit encoded in every carrier cycle of the record
+    pub fn bitstream(&self) -> Vec<bool> {
+        let mut current = self.seed;
+        (0 .. self.length).map(|_| {
+            let bit = (current >> (self.bits - 1)) & 0x1 == 1;
+            current = self.fwd(current);
+            bit
+        }).collect()
+    }
+
+    // Cycle at which each register state occurs
+    fn lookup(&self) -> HashMap<u32, u32> {
+        let mut lut = HashMap::with_capacity(self.length as usize);
+        let mut current = self.seed;
+        for n in 0 .. self.length {
+            lut.insert(current, n);
+            current = self.fwd(current);
+        }
+        lut
+    }
+}
+
+pub struct Decoder {
+    def: &'static TimecodeDef,
+    lut: HashMap<u32, u32>,
+    sample_rate: f64,
+    zero: (f32, f32),
+    positive: (bool, bool),
+    forwards: bool,
+    bitstream: u32,
+    timecode: u32,
+    valid: u32,
+    ref_level: f32,
+    intervals: Vec<f64>,
+    since_crossing: f64,
+    since_bit: f64,
+    speed: f64,
+    cycle: Option<u32>,
+}
+
+impl Decoder {
+    pub fn new(def: &'static TimecodeDef, sample_rate: u32) -> Decoder {
+        Decoder {
+            def: def,
+            lut: def.lookup(),
+            sample_rate: sample_rate as f64,
+            zero: (0.0, 0.0),
+            positive: (false, false),
+            forwards: true,
+            bitstream: 0,
+            timecode: 0,
+            valid: 0,
+            ref_level: 0.0,
+            intervals: Vec::with_capacity(SPEED_WINDOW),
+            since_crossing: 0.0,
+            since_bit: 0.0,
+            speed: 0.0,
+            cycle: None,
+        }
+    }
+
+    fn crossing(&mut self) {
+        if self.intervals.len() == SPEED_WINDOW {
+            self.intervals.remove(0);
+        }
+        self.intervals.push(self.since_crossing);
+        self.since_crossing = 0.0;
+        let mean = self.intervals.iter().sum::<f64>() / self.intervals.len() as f64;
+        let speed = 0.25 * self.sample_rate / mean / self.def.resolution as f64;
+        self.speed = if self.forwards { speed } else { -speed };
+    }
+
+    fn read_bit(&mut self, m: f32) {
+        let b = if m > self.ref_level { 1 } else { 0 };
+        if self.forwards {
+            self.timecode = self.def.fwd(self.timecode);
+            self.bitstream = (self.bitstream >> 1) | (b << (self.def.bits - 1));
+        }
+        else {
+            self.timecode = self.def.rev(self.timecode);
+            self.bitstream = ((self.bitstream << 1) & self.def.mask()) | b;
+        }
+        if self.timecode == self.bitstream {
+            self.valid += 1;
+        }
+        else {
+            self.timecode = self.bitstream;
+            self.valid = 0;
+        }
+        self.cycle = if self.valid >= self.def.bits {
+            self.lut.get(&self.bitstream).map(|&n| if self.forwards { n } else { n + 1 - self.def.bits })
+        } else { None };
+        self.since_bit = 0.0;
+        self.ref_level += (m - self.ref_level) / REF_PEAKS_AVG;
+    }
+
+    pub fn process(&mut self, l: f32, r: f32) {
+        self.zero.0 += (l - self.zero.0) / 1024.0;
+        self.zero.1 += (r - self.zero.1) / 1024.0;
+        let (l, r) = (l - self.zero.0, r - self.zero.1);
+        let (mut primary, secondary) = if self.def.swap { (r, l) } else { (l, r) };
+        if self.def.polarity {
+            primary = -primary;
+        }
+        self.since_crossing += 1.0;
+        self.since_bit += 1.0;
+
+        if self.positive.0 && primary < -ZERO_THRESHOLD || !self.positive.0 && primary > ZERO_THRESHOLD {
+            self.positive.0 = !self.positive.0;
+            self.crossing();
+        }
+        if self.positive.1 && secondary < -ZERO_THRESHOLD || !self.positive.1 && secondary > ZERO_THRESHOLD {
+            self.positive.1 = !self.positive.1;
+            // the primary leads the secondary by a quarter cycle when the record moves forward
+            let forwards = self.positive.1 != self.positive.0;
+            if forwards != self.forwards {
+                self.forwards = forwards;
+                self.valid = 0;
+                self.intervals.clear();
+            }
+            self.crossing();
+            // the primary peaks while the secondary crosses zero
+            if self.positive.0 {
+                self.read_bit(primary.abs());
+            }
+        }
+
+        if self.since_crossing > STOP_SECS * self.sample_rate {
+            self.speed = 0.0;
+            self.intervals.clear();
+        }
+    }
+
+    // Speed relative to the nominal speed of the record, negative when moving backwards
+    pub fn speed(&self) -> f64 {
+        self.speed
+    }
+
+    // Absolute position on the record in seconds, once enough bits have been read to know it
+    pub fn position(&self) -> Option<f64> {
+        let cycle = self.cycle?;
+        if cycle > self.def.safe {
+            return None;
+        }
+        let since = self.since_bit / self.sample_rate * self.speed * self.def.resolution as f64;
+        Some((cycle as f64 + 0.25 + since) / self.def.resolution as f64)
+    }
+}
+
+// Renders timecode as it would come off the record, starting at start secs and moving at a constant speed
+pub fn synthesize(def: &TimecodeDef, bits: &[bool], start: f64, speed: f64, secs: f64, sample_rate: u32) -> Vec<(f32, f32)> {
+    let samples = (secs * sample_rate as f64) as usize;
+    (0 .. samples).map(|k| {
+        let cycles = (start + speed * k as f64 / sample_rate as f64) * def.resolution as f64;
+        let bit = bits.get(cycles.floor().max(0.0) as usize).cloned().unwrap_or(false);
+        let amplitude = if bit { 0.5 } else { 0.5 * LOW_AMPLITUDE };
+        let phase = 2.0 * PI * cycles;
+        let primary = amplitude * phase.sin() as f32 * if def.polarity { -1.0 } else { 1.0 };
+        let secondary = amplitude * phase.cos() as f32;
+        if def.swap { (secondary, primary) } else { (primary, secondary) }
+    }).collect()
+}
+
+pub fn write_wav(path: &str, sample_rate: u32, samples: &[(f32, f32)]) -> io::Result<()> {
+    fn u16le(v: u16) -> [u8; 2] { [v as u8, (v >> 8) as u8] }
+    fn u32le(v: u32) -> [u8; 4] { [v as u8, (v >> 8) as u8, (v >> 16) as u8, (v >> 24) as u8] }
+
+    let data_len = samples.len() as u32 * 4;
+    let mut out = Vec::with_capacity(44 + data_len as usize);
+    out.extend_from_slice(b"RIFF");
+    out.extend_from_slice(&u32le(36 + data_len));
+    out.extend_from_slice(b"WAVEfmt ");
+    out.extend_from_slice(&u32le(16));
+    out.extend_from_slice(&u16le(1)); //PCM
+    out.extend_from_slice(&u16le(2));
+    out.extend_from_slice(&u32le(sample_rate));
+    out.extend_from_slice(&u32le(sample_rate * 4));
+    out.extend_from_slice(&u16le(4));
+    out.extend_from_slice(&u16le(16));
+    out.extend_from_slice(b"data");
+    out.extend_from_slice(&u32le(data_len));
+    for &(l, r) in samples.iter() {
+        out.extend_from_slice(&u16le((l.max(-1.0).min(1.0) * 32767.0) as i16 as u16));
+        out.extend_from_slice(&u16le((r.max(-1.0).min(1.0) * 32767.0) as i16 as u16));
+    }
+    File::create(path)?.write_all(&out)
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum DvsMode {
+    Off,
+    Relative,
+    Absolute,
+}
+
+#[derive(Debug, Deserialize)]
+pub enum DvsCommand {
+    Deck(usize),
+    Mode(DvsMode),
+    Timecode(String),
+}
+
+pub fn run(rx: Receiver<DvsCommand>, tx_player: Sender<PlayerCommand>, tx: SyncSender<PlayerStatus>) {
+    let (tc, rc) = mpsc::sync_channel::<Decoder>(1);
+    let (tu, ru) = mpsc::sync_channel::<(f64, Option<f64>)>(4);
+    let (tr, rr) = mpsc::sync_channel::<Decoder>(1); // replaced decoders, dropped outside of the process callback
+
+    let client = Client::new("rustydj_dvs", client_options::NO_START_SERVER).unwrap().0;
+    let l_in = client.register_port("timecode_l", AudioInSpec::default()).unwrap();
+    let r_in = client.register_port("timecode_r", AudioInSpec::default()).unwrap();
+    printinfo!(tx, "Connect the timecode signal to {} and {}", l_in.name(), r_in.name());
+    let sample_rate = client.sample_rate() as u32;
+
+    let mut decoder : Option<Decoder> = None;
+    let mut retired : Option<Decoder> = None;
+    let process = ClosureProcessHandler::new(move |_client: &Client, ps: &ProcessScope| -> JackControl {
+        // freeing the lookup table of the old decoder takes too long here, it goes back to be dropped
+        if let Some(old) = retired.take() {
+            if let Err(TrySendError::Full(old)) = tr.try_send(old) {
+                retired = Some(old);
+            }
+        }
+        if retired.is_none() {
+            if let Ok(decoder_) = rc.try_recv() {
+                retired = decoder.replace(decoder_);
+            }
+        }
+        if let Some(ref mut decoder) = decoder {
+            let l_buffer = AudioInPort::new(&l_in, ps);
+            let r_buffer = AudioInPort::new(&r_in, ps);
+            for (l, r) in l_buffer.iter().zip(r_buffer.iter()) {
+                decoder.process(*l, *r);
+            }
+            tu.try_send((decoder.speed(), decoder.position())).is_ok();
+        }
+        JackControl::Continue
+    });
+    let active_client = AsyncClient::new(client, (), process).unwrap();
+
+    let mut deck = 0;
+    let mut mode = DvsMode::Off;
+    let mut def = &TIMECODES[0];
+    let mut last_speed = 0.0;
+    tc.send(Decoder::new(def, sample_rate)).unwrap();
+
+    loop {
+        while rr.try_recv().is_ok() {}
+        while let Ok((speed, position)) = ru.try_recv() {
+            if mode == DvsMode::Off {
+                continue;
+            }
+            let position = if mode == DvsMode::Absolute { position } else { None };
+            if (speed - last_speed).abs() > SPEED_THRESHOLD || position.is_some() {
+                last_speed = speed;
+                tx_player.send(PlayerCommand::Deck(deck, Box::new(PlayerCommand::Timecode(speed, position)))).is_ok();
+            }
+        }
+
+        let cmd = match rx.recv_timeout(Duration::from_millis(5)) {
+            Ok(cmd) => cmd,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(_) => break,
+        };
+        match cmd {
+            DvsCommand::Deck(deck_) => {
+                if mode != DvsMode::Off {
+                    tx_player.send(PlayerCommand::Deck(deck, Box::new(PlayerCommand::SpeedDiff(1.0)))).is_ok();
+                }
+                deck = deck_;
+            },
+            DvsCommand::Mode(mode_) => {
+                if mode_ == DvsMode::Off && mode != DvsMode::Off {
+                    tx_player.send(PlayerCommand::Deck(deck, Box::new(PlayerCommand::SpeedDiff(1.0)))).is_ok();
+                }
+                mode = mode_;
+                last_speed = 0.0;
+            },
+            DvsCommand::Timecode(name) => {
+                match timecode(&name) {
+                    Some(def_) => {
+                        def = def_;
+                        tc.send(Decoder::new(def, sample_rate)).unwrap();
+                    },
+                    None => printinfo!(tx, "Unknown timecode: {}", name),
+                }
+            },
+        }
+        tx.try_send(PlayerStatus::Dvs(deck, mode, def.name.to_string())).is_ok();
+    }
+    active_client.deactivate().unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wav;
+    use std::env;
+
+    const SAMPLE_RATE : u32 = 44100;
+
+    fn decode(def: &'static TimecodeDef, samples: &[(f32, f32)]) -> Decoder {
+        let mut decoder = Decoder::new(def, SAMPLE_RATE);
+        for &(l, r) in samples.iter() {
+            decoder.process(l, r);
+        }
+        decoder
+    }
+
+    #[test]
+    fn lookup_is_unique() {
+        for def in TIMECODES.iter() {
+            assert_eq!(def.lookup().len(), def.length as usize, "{}", def.name);
+        }
+    }
+
+    #[test]
+    fn rev_undoes_fwd() {
+        for def in TIMECODES.iter() {
+            let mut current = def.seed;
+            for _ in 0 .. 1000 {
+                let next = def.fwd(current);
+                assert_eq!(def.rev(next), current, "{}", def.name);
+                current = next;
+            }
+        }
+    }
+
+    #[test]
+    fn decodes_forward() {
+        for def in TIMECODES.iter() {
+            let bits = def.bitstream();
+            let decoder = decode(def, &synthesize(def, &bits, 100.0, 1.0, 0.5, SAMPLE_RATE));
+            assert!((decoder.speed() - 1.0).abs() < 0.01, "{}: speed {}", def.name, decoder.speed());
+            let position = decoder.position().expect(def.name);
+            assert!((position - 100.5).abs() < 0.005, "{}: position {}", def.name, position);
+        }
+    }
+
+    #[test]
+    fn decodes_backwards_and_slow() {
+        for &speed in [-1.0, 0.5, -0.33, 1.5].iter() {
+            let def = &TIMECODES[0];
+            let bits = def.bitstream();
+            let decoder = decode(def, &synthesize(def, &bits, 200.0, speed, 1.0, SAMPLE_RATE));
+            assert!((decoder.speed() - speed).abs() < 0.01, "speed {} decoded as {}", speed, decoder.speed());
+            let position = decoder.position().expect("no position");
+            assert!((position - (200.0 + speed)).abs() < 0.005, "speed {}: position {}", speed, position);
+        }
+    }
+
+    #[test]
+    fn detects_stop() {
+        let def = &TIMECODES[0];
+        let bits = def.bitstream();
+        let mut samples = synthesize(def, &bits, 10.0, 1.0, 0.2, SAMPLE_RATE);
+        samples.extend(synthesize(def, &bits, 10.2, 0.0, 0.2, SAMPLE_RATE));
+        assert_eq!(decode(def, &samples).speed(), 0.0);
+    }
+
+    #[test]
+    fn decodes_wav_file() {
+        let def = timecode("traktor_a").unwrap();
+        let bits = def.bitstream();
+        let path = env::temp_dir().join("rustydj_timecode_test.wav");
+        let path = path.to_str().unwrap();
+        write_wav(path, SAMPLE_RATE, &synthesize(def, &bits, 42.0, 1.0, 0.5, SAMPLE_RATE)).unwrap();
+
+        let mut decoder = Decoder::new(def, SAMPLE_RATE);
+        for frame in wav::decode(path).unwrap() {
+            for (l, r) in frame.samples[0].iter().zip(frame.samples[1].iter()) {
+                decoder.process(l.to_f32(), r.to_f32());
+            }
+        }
+        let position = decoder.position().expect("no position");
+        assert!((position - 42.5).abs() < 0.005, "position {}", position);
+        assert!((decoder.speed() - 1.0).abs() < 0.01);
+    }
+}
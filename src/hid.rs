@@ -0,0 +1,303 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use mp3playerjack::PlayerCommand;
+use ui::UICommand;
+use controller::{Controller, Feedback, Learn, Mapping, SharedFeedback};
+
+const SCAN_MS : u64 = 2000; // how often /sys/class/hidraw is looked at for new devices
+const REPORT_SIZE : usize = 1024; // largest input report read at once
+
+fn default_bits() -> u8 { 8 }
+
+// An HID device a mapping is for, matched by its USB ids
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct HidDevice {
+    pub vendor: u16,
+    pub product: u16,
+    pub fields: Vec<HidField>,
+}
+
+// A value in the input reports, turned into an HID message for the bindings whenever it changes
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct HidField {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub report: Option<u8>, // report id, the first byte of the report, for devices with several reports; without any the first byte is data
+    pub byte: usize, // offset in the report, counting the report id
+    #[serde(default)]
+    pub bit: u8, // offset in the byte
+    #[serde(default = "default_bits")]
+    pub bits: u8, // bit + bits up to 16, little endian, 1 for buttons
+    #[serde(default)]
+    pub relative: bool, // the value is a signed change, sent whenever it is not 0
+    #[serde(default)]
+    pub counter: bool, // the value counts up and down, its signed change is sent
+    pub number: u8, // number of the HID bindings
+}
+
+// What a field reports: number, value, bits of the value
+pub type HidEvent = (u8, u16, u8);
+
+impl HidField {
+    // Fits the two bytes read, bit + bits <= 16
+    pub fn valid(&self) -> bool {
+        self.bits > 0 && self.bit as u32 + self.bits as u32 <= 16
+    }
+
+    fn read(&self, report: &[u8]) -> Option<u16> {
+        let low = *report.get(self.byte)? as u32;
+        let high = if self.bit as u32 + self.bits as u32 > 8 { *report.get(self.byte + 1)? as u32 } else { 0 };
+        let mask = (1u32 << self.bits) - 1;
+        Some(((high << 8 | low) >> self.bit & mask) as u16)
+    }
+}
+
+// Turns input reports into events, comparing each report with the previous one with its id
+pub struct HidDecoder {
+    fields: Vec<HidField>,
+    report_ids: bool, // some field sets a report id, without one the first byte is data
+    last: HashMap<u8, Vec<u8>>,
+}
+
+impl HidDecoder {
+    pub fn new(device: &HidDevice) -> HidDecoder {
+        HidDecoder {
+            fields: device.fields.iter().filter(|f| f.valid()).cloned().collect(),
+            report_ids: device.fields.iter().any(|f| f.report.is_some()),
+            last: HashMap::new(),
+        }
+    }
+
+    pub fn decode(&mut self, report: &[u8]) -> Vec<HidEvent> {
+        let id = if self.report_ids { report.get(0).cloned().unwrap_or(0) } else { 0 };
+        let last = self.last.insert(id, report.to_vec());
+        let mut events = Vec::new();
+        for field in self.fields.iter() {
+            if self.report_ids && field.report.map_or(false, |r| r != id) {
+                continue;
+            }
+            let value = match field.read(report) {
+                Some(value) => value,
+                None => continue,
+            };
+            let previous = last.as_ref().and_then(|last| field.read(last));
+            let mask = ((1u32 << field.bits) - 1) as u16;
+            if field.relative {
+                if value != 0 {
+                    events.push((field.number, value, field.bits));
+                }
+            }
+            else if field.counter {
+                // the first report only sets where the counter starts
+                if let Some(previous) = previous {
+                    let change = value.wrapping_sub(previous) & mask;
+                    if change != 0 {
+                        events.push((field.number, change, field.bits));
+                    }
+                }
+            }
+            else if previous != Some(value) && (previous.is_some() || value != 0) {
+                events.push((field.number, value, field.bits));
+            }
+        }
+        events
+    }
+}
+
+// hidraw devices with their USB ids, from sysfs
+fn devices() -> Vec<(String, u16, u16)> {
+    let entries = match fs::read_dir("/sys/class/hidraw") {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    let mut devices = Vec::new();
+    for entry in entries.filter_map(|e| e.ok()) {
+        let mut uevent = String::new();
+        if File::open(entry.path().join("device/uevent")).and_then(|mut f| f.read_to_string(&mut uevent)).is_err() {
+            continue;
+        }
+        // HID_ID=0003:000017CC:00001110
+        let ids : Vec<u32> = match uevent.lines().find(|l| l.starts_with("HID_ID=")) {
+            Some(line) => line[7 ..].split(':').filter_map(|id| u32::from_str_radix(id, 16).ok()).collect(),
+            None => continue,
+        };
+        if ids.len() == 3 {
+            devices.push((format!("/dev/{}", entry.file_name().to_string_lossy()), ids[1] as u16, ids[2] as u16));
+        }
+    }
+    devices.sort();
+    devices
+}
+
+fn attach(path: String, mapping: Mapping, attached: Arc<Mutex<HashSet<String>>>, tx: mpsc::Sender<PlayerCommand>,
+          txui: mpsc::Sender<UICommand>, learn: Learn, feedback: SharedFeedback) {
+    let mut file = match File::open(&path) {
+        Ok(file) => file,
+        Err(e) => {
+            txui.send(UICommand::Print(format!("Could not attach {}: {}", path, e))).is_ok();
+            return;
+        },
+    };
+    attached.lock().unwrap().insert(path.clone());
+    txui.send(UICommand::Print(format!("Attached {} as {}", path, mapping.name))).is_ok();
+    thread::spawn(move || {
+        let mut ctrl = Controller::from_mapping(mapping, learn);
+        let mut snapshot = Feedback::new();
+        let mut report = [0; REPORT_SIZE];
+        // hidraw hands out one report per read, reading fails once the device is unplugged
+        loop {
+            let len = match file.read(&mut report) {
+                Ok(len) if len > 0 => len,
+                _ => break,
+            };
+            if let Ok(feedback) = feedback.try_lock() {
+                snapshot.clone_from(&feedback);
+            }
+            ctrl.handle_hid(&report[.. len], &snapshot, &tx, &txui);
+        }
+        txui.send(UICommand::Print(format!("Detached {} ({})", path, ctrl.mapping.name))).is_ok();
+        attached.lock().unwrap().remove(&path);
+    });
+}
+
+// Attaches every hidraw device a mapping is for, as they come
+pub fn run(tx: mpsc::Sender<PlayerCommand>, txui: mpsc::Sender<UICommand>, learn: Learn, feedback: SharedFeedback) {
+    let mappings : Vec<Mapping> = Mapping::load_all().into_iter().filter(|m| m.hid.is_some()).collect();
+    if mappings.len() == 0 {
+        return;
+    }
+    let attached = Arc::new(Mutex::new(HashSet::new()));
+    thread::spawn(move || {
+        loop {
+            for (path, vendor, product) in devices() {
+                if attached.lock().unwrap().contains(&path) {
+                    continue;
+                }
+                let mapping = mappings.iter()
+                    .find(|m| m.hid.as_ref().map_or(false, |hid| hid.vendor == vendor && hid.product == product));
+                if let Some(mapping) = mapping {
+                    attach(path, mapping.clone(), attached.clone(), tx.clone(), txui.clone(), learn.clone(), feedback.clone());
+                }
+            }
+            thread::sleep(Duration::from_millis(SCAN_MS));
+        }
+    });
+}
+
+// Reports written one per line as hex bytes, lines starting with # are comments
+pub fn read_reports(path: &str) -> Result<Vec<Vec<u8>>, String> {
+    let file = File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+    let mut reports = Vec::new();
+    for (n, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|e| format!("{}: {}", path, e))?;
+        let line = line.trim();
+        if line.len() == 0 || line.starts_with('#') {
+            continue;
+        }
+        let report : Result<Vec<u8>, _> = line.split_whitespace().map(|b| u8::from_str_radix(b, 16)).collect();
+        reports.push(report.map_err(|e| format!("{}:{}: {}", path, n + 1, e))?);
+    }
+    Ok(reports)
+}
+
+// rustydj hidrecord /dev/hidrawN <file>: writes the reports of a device until it is unplugged or interrupted
+pub fn record(device: &str, path: &str) -> Result<(), String> {
+    let mut input = File::open(device).map_err(|e| format!("{}: {}", device, e))?;
+    let mut output = File::create(path).map_err(|e| format!("{}: {}", path, e))?;
+    let mut report = [0; REPORT_SIZE];
+    loop {
+        let len = match input.read(&mut report) {
+            Ok(len) if len > 0 => len,
+            _ => return Ok(()),
+        };
+        let line : Vec<String> = report[.. len].iter().map(|b| format!("{:02x}", b)).collect();
+        writeln!(output, "{}", line.join(" ")).map_err(|e| format!("{}: {}", path, e))?;
+    }
+}
+
+// rustydj hidreplay <mapping.json> <file>: prints the commands the recorded reports result in
+pub fn replay(mapping: &str, path: &str) -> Result<(), String> {
+    let mapping = Mapping::load(mapping)?;
+    if mapping.hid.is_none() {
+        return Err(format!("{} has no HID fields", mapping.name));
+    }
+    let reports = read_reports(path)?;
+    let mut ctrl = Controller::from_mapping(mapping, Arc::new(Mutex::new(None)));
+    let feedback = Feedback::new();
+    let (tx, rx) = mpsc::channel();
+    let (txui, rxui) = mpsc::channel();
+    for report in reports.iter() {
+        ctrl.handle_hid(report, &feedback, &tx, &txui);
+        for cmd in rx.try_iter() {
+            println!("{:?}", cmd);
+        }
+        for cmd in rxui.try_iter() {
+            println!("UI {:?}", cmd);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn field(byte: usize, bit: u8, bits: u8, number: u8) -> HidField {
+        HidField { report: None, byte: byte, bit: bit, bits: bits, relative: false, counter: false, number: number }
+    }
+
+    #[test]
+    fn report_file() {
+        let path = env::temp_dir().join("rustydj_reports.txt");
+        File::create(&path).unwrap().write_all(b"# PowerMate\n00 02 00 00 00 00\n\n01 fd 00 00 00 00\n").unwrap();
+        let reports = read_reports(path.to_str().unwrap()).unwrap();
+        assert_eq!(reports, vec![vec![0, 2, 0, 0, 0, 0], vec![1, 0xFD, 0, 0, 0, 0]]);
+        File::create(&path).unwrap().write_all(b"00 2\n0g\n").unwrap();
+        assert!(read_reports(path.to_str().unwrap()).unwrap_err().ends_with(":2: invalid digit found in string"));
+    }
+
+    #[test]
+    fn fields() {
+        let mut counter = field(2, 0, 16, 2);
+        counter.counter = true;
+        let mut relative = field(4, 0, 8, 3);
+        relative.relative = true;
+        let mut other = field(1, 0, 8, 4);
+        other.report = Some(2);
+        let mut decoder = HidDecoder::new(&HidDevice { vendor: 0, product: 0,
+                                                       fields: vec![field(1, 3, 1, 1), counter, relative, other] });
+        assert_eq!(decoder.decode(&[1, 0x00, 0xFE, 0xFF, 0x00]), vec![]);
+        assert_eq!(decoder.decode(&[1, 0x08, 0x01, 0x00, 0xFF]), vec![(1, 1, 1), (2, 3, 16), (3, 0xFF, 8)]);
+        assert_eq!(decoder.decode(&[1, 0x08, 0x00, 0x00, 0x00]), vec![(2, 0xFFFF, 16)]);
+        assert_eq!(decoder.decode(&[2, 0x05]), vec![(4, 5, 8)]);
+        assert_eq!(decoder.decode(&[1, 0x00, 0x00, 0x00, 0x00]), vec![(1, 0, 1)]);
+    }
+
+    #[test]
+    fn powermate() {
+        // no report ids, the button is the first byte
+        let mut ctrl = Controller::from_mapping(Mapping::load("controllers/powermate.json").unwrap(), Arc::new(Mutex::new(None)));
+        let (tx, rx) = mpsc::channel();
+        let (txui, _rxui) = mpsc::channel();
+        for report in [[1, 0], [0, 0], [1, 0], [0, 2]].iter() {
+            ctrl.handle_hid(report, &Feedback::new(), &tx, &txui);
+        }
+        drop(tx);
+        let cmds : Vec<String> = rx.iter().map(|cmd| format!("{:?}", cmd)).collect();
+        assert_eq!(cmds, vec!["PlayPause", "PlayPause", "Jog(0.02)"]);
+    }
+
+    #[test]
+    fn rejects_wide_fields() {
+        assert!(field(1, 0, 16, 1).valid() && field(1, 7, 9, 1).valid());
+        assert!(!field(1, 4, 13, 1).valid() && !field(1, 0, 0, 1).valid() && !field(1, 0, 17, 1).valid());
+        let mut decoder = HidDecoder::new(&HidDevice { vendor: 0, product: 0, fields: vec![field(1, 4, 13, 1), field(3, 0, 8, 2)] });
+        assert_eq!(decoder.decode(&[0, 0xF0, 0xFF, 0x01]), vec![(2, 1, 8)]);
+    }
+}
@@ -0,0 +1,194 @@
+extern crate serde_json;
+
+use std::collections::HashMap;
+use std::fs::File;
+
+use mp3playerjack::PlayerCommand;
+use ui::UICommand;
+use controller::substitute;
+
+use self::serde_json::Value;
+
+const CONFIG : &'static str = "keys.json";
+
+// The layout without keys.json. Player commands go to the selected deck unless wrapped in Deck.
+const DEFAULT_KEYS : &'static str = r##"{
+    "q": {"UI": "Quit"},
+    "Up": {"UI": {"Scroll": -1}},
+    "Down": {"UI": {"Scroll": 1}},
+    "PageUp": {"UI": {"Scroll": -10}},
+    "PageDown": {"UI": {"Scroll": 10}},
+    "Enter": {"UI": "Enter"},
+    "Backspace": {"UI": "Back"},
+    "/": {"UI": {"Search": ""}},
+    "z": {"UI": {"LoadDeck": 0}},
+    "x": {"UI": {"LoadDeck": 1}},
+    "Z": {"UI": {"LoadDeck": 2}},
+    "X": {"UI": {"LoadDeck": 3}},
+    "F1": {"Player": {"SelectDeck": 0}},
+    "F2": {"Player": {"SelectDeck": 1}},
+    "F3": {"Player": {"SelectDeck": 2}},
+    "F4": {"Player": {"SelectDeck": 3}},
+    "Space": {"Player": "PlayPause"},
+    "c": {"Player": {"Cue": "$on"}},
+    "1": {"Player": {"HotCue": [0, true]}},
+    "2": {"Player": {"HotCue": [1, true]}},
+    "3": {"Player": {"HotCue": [2, true]}},
+    "4": {"Player": {"HotCue": [3, true]}},
+    "5": {"Player": {"HotCue": [4, true]}},
+    "6": {"Player": {"HotCue": [5, true]}},
+    "7": {"Player": {"HotCue": [6, true]}},
+    "8": {"Player": {"HotCue": [7, true]}},
+    "!": {"Player": {"DeleteHotCue": 0}},
+    "@": {"Player": {"DeleteHotCue": 1}},
+    "#": {"Player": {"DeleteHotCue": 2}},
+    "$": {"Player": {"DeleteHotCue": 3}},
+    "%": {"Player": {"DeleteHotCue": 4}},
+    "^": {"Player": {"DeleteHotCue": 5}},
+    "&": {"Player": {"DeleteHotCue": 6}},
+    "*": {"Player": {"DeleteHotCue": 7}},
+    "o": {"Player": {"Loop": 4}},
+    "O": {"Player": {"Loop": 0}},
+    "[": {"Player": {"LoopScale": 0.5}},
+    "]": {"Player": {"LoopScale": 2}},
+    "y": {"Player": {"Sync": true}},
+    "Y": {"Player": {"Sync": false}},
+    "Left": {"Player": {"BeatJump": -4}},
+    "Right": {"Player": {"BeatJump": 4}},
+    "<": {"Player": {"BeatJump": -1}},
+    ">": {"Player": {"BeatJump": 1}},
+    ",": {"Player": {"Jog": -0.25}},
+    ".": {"Player": {"Jog": 0.25}},
+    "(": {"UI": {"Columns": {"Select": -1}}},
+    ")": {"UI": {"Columns": {"Select": 1}}},
+    "{": {"UI": {"Columns": {"Move": -1}}},
+    "}": {"UI": {"Columns": {"Move": 1}}},
+    "=": {"UI": {"Columns": {"Change": 1}}},
+    "+": {"UI": {"Columns": "Add"}},
+    "-": {"UI": {"Columns": "Remove"}},
+    "s": {"UI": {"Columns": "Sort"}},
+    "k": {"UI": {"Cues": {"Select": -1}}},
+    "j": {"UI": {"Cues": {"Select": 1}}},
+    "m": {"UI": {"Cues": {"Set": null}}},
+    "r": {"UI": {"Cues": {"Name": ""}}},
+    "C": {"UI": {"Cues": {"Colour": 1}}},
+    "h": {"UI": {"Cues": {"Move": -1}}},
+    "H": {"UI": {"Cues": {"Move": 1}}},
+    "Delete": {"UI": {"Cues": "Delete"}},
+    "a": {"UI": "AutoDJStart"},
+    "A": {"Player": {"AutoDJ": "Stop"}},
+    "p": {"Player": {"AutoDJ": "Pause"}},
+    "n": {"Player": {"AutoDJ": "Skip"}},
+    "t": {"Player": {"Aux": {"Talkover": true}}},
+    "T": {"Player": {"Aux": {"Talkover": false}}},
+    "l": {"UI": {"Learn": 0}},
+    "L": {"UI": {"Learn": null}}
+}"##;
+
+#[derive(Debug, Deserialize, Clone)]
+pub enum KeyAction {
+    Player(Value), // PlayerCommand, with "$on" it is sent pressed and then released as keys have no release
+    UI(Value), // UICommand
+}
+
+pub enum KeyCommand {
+    Player(PlayerCommand),
+    UI(UICommand),
+}
+
+// Key names are the character, Space, Enter, Tab, Esc, Backspace, Up, Down, Left, Right, Home, End,
+// PageUp, PageDown, Insert, Delete, F1-F12, Ctrl-<char> and Alt-<char>
+pub struct Keymap {
+    pub keys: HashMap<String, KeyAction>,
+}
+
+impl Keymap {
+    fn parse(json: &str) -> Result<HashMap<String, Option<KeyAction>>, String> {
+        serde_json::from_str(json).map_err(|e| e.to_string())
+    }
+
+    // The default layout with keys.json on top, null in keys.json unbinds a key
+    pub fn load() -> Keymap {
+        let mut keymap = Keymap { keys: HashMap::new() };
+        keymap.extend(Keymap::parse(DEFAULT_KEYS).unwrap());
+        if let Ok(file) = File::open(CONFIG) {
+            match serde_json::from_reader(file) {
+                Ok(keys) => keymap.extend(keys),
+                Err(e) => println!("{}: {}", CONFIG, e),
+            }
+        }
+        keymap
+    }
+
+    fn extend(&mut self, keys: HashMap<String, Option<KeyAction>>) {
+        for (key, action) in keys {
+            match action {
+                Some(action) => self.keys.insert(key, action),
+                None => self.keys.remove(&key),
+            };
+        }
+    }
+
+    // What a key press sends, errors in keys.json come back as UI prints
+    pub fn commands(&self, key: &str) -> Vec<KeyCommand> {
+        let action = match self.keys.get(key) {
+            Some(action) => action,
+            None => return Vec::new(),
+        };
+        let invalid = |json: &Value, e: serde_json::Error| KeyCommand::UI(UICommand::Print(format!("{}: {}: {}", CONFIG, json, e)));
+        match *action {
+            KeyAction::Player(ref template) => {
+                let press = substitute(template, 1.0, true, 0);
+                let release = substitute(template, 0.0, false, 0);
+                let mut values = vec![press.clone()];
+                if release != press {
+                    values.push(release);
+                }
+                values.into_iter().map(|json| match serde_json::from_value(json.clone()) {
+                    Ok(cmd) => KeyCommand::Player(cmd),
+                    Err(e) => invalid(&json, e),
+                }).collect()
+            },
+            KeyAction::UI(ref json) => vec![match serde_json::from_value(json.clone()) {
+                Ok(cmd) => KeyCommand::UI(cmd),
+                Err(e) => invalid(json, e),
+            }],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn debug(commands: Vec<KeyCommand>) -> Vec<String> {
+        commands.into_iter().map(|cmd| match cmd {
+            KeyCommand::Player(cmd) => format!("{:?}", cmd),
+            KeyCommand::UI(cmd) => format!("UI {:?}", cmd),
+        }).collect()
+    }
+
+    #[test]
+    fn defaults() {
+        let keymap = Keymap::load();
+        for key in keymap.keys.keys() {
+            for cmd in debug(keymap.commands(key)) {
+                assert!(!cmd.starts_with("UI Print"), "{}: {}", key, cmd);
+            }
+        }
+        assert_eq!(debug(keymap.commands("3")), vec!["HotCue(2, true)"]);
+        assert_eq!(debug(keymap.commands("c")), vec!["Cue(true)", "Cue(false)"]);
+        assert_eq!(debug(keymap.commands("x")), vec!["UI LoadDeck(1)"]);
+        assert_eq!(debug(keymap.commands("L")), vec!["UI Learn(None)"]);
+        assert_eq!(debug(keymap.commands("Ctrl-c")), Vec::<String>::new());
+    }
+
+    #[test]
+    fn overrides() {
+        let mut keymap = Keymap::load();
+        keymap.extend(Keymap::parse(r#"{"o": {"Player": {"Deck": [1, {"Loop": 8}]}}, "q": null, "w": {"Player": "Nope"}}"#).unwrap());
+        assert_eq!(debug(keymap.commands("o")), vec!["Deck(1, Loop(8.0))"]);
+        assert_eq!(debug(keymap.commands("q")), Vec::<String>::new());
+        assert!(debug(keymap.commands("w"))[0].starts_with("UI Print(\"keys.json: \\\"Nope\\\": unknown variant"));
+    }
+}
@@ -1,6 +1,7 @@
 extern crate minidom;
 extern crate quick_xml;
 extern crate url;
+extern crate serde_json;
 
 use std::fs::File;
 use std::io::BufReader;
@@ -12,6 +13,8 @@ use std::sync::mpsc;
 
 use ui::UICommand;
 
+const CUES : &'static str = "cues.json"; // cues edited in the UI by track id, on top of the rekordbox library
+
 #[derive(Debug, Deserialize)]
 pub enum LibraryCommand {
     GetPlaylist(usize),
@@ -30,6 +33,10 @@ pub struct Cue {
     pub name: String,
     pub start: usize,
     pub type_: u8,
+    #[serde(default)]
+    pub hotcue: Option<usize>, // slot of a hot cue, None for a memory cue
+    #[serde(default)]
+    pub colour: Option<(u8, u8, u8)>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -68,7 +75,94 @@ pub struct Library {
     pub playlists : Vec<Playlist>
 }
 
+// A library search. Words have to be in the artist, title, album, key or bpm, field:value terms in that
+// field, which is one of those or any other info field. Numbers and ranges like bpm:120-128 compare as
+// numbers, keys have to be equal and other fields contain the value. All terms have to match.
+pub struct Filter {
+    terms: Vec<Term>,
+}
+
+enum Term {
+    Word(String),
+    Field(String, String),
+    Range(String, f64, f64),
+}
+
+impl Filter {
+    pub fn parse(query: &str) -> Filter {
+        let terms = query.to_lowercase().split_whitespace().map(|term| {
+            let mut parts = term.splitn(2, ':');
+            match (parts.next(), parts.next()) {
+                (Some(field), Some(value)) if field.len() > 0 && value.len() > 0 => {
+                    let mut bounds = value.splitn(2, '-');
+                    match (bounds.next().and_then(|b| b.parse().ok()), bounds.next().and_then(|b| b.parse().ok())) {
+                        (Some(low), Some(high)) => Term::Range(field.to_string(), low, high),
+                        _ => Term::Field(field.to_string(), value.to_string()),
+                    }
+                },
+                _ => Term::Word(term.to_string()),
+            }
+        }).collect();
+        Filter { terms: terms }
+    }
+
+    pub fn matches(&self, track: &Track) -> bool {
+        self.terms.iter().all(|term| match *term {
+            Term::Word(ref word) => ["artist", "title", "album", "key", "bpm"].iter()
+                .any(|field| track.field(field).map_or(false, |value| value.to_lowercase().contains(word))),
+            Term::Field(ref field, ref wanted) => match track.field(field) {
+                Some(value) => {
+                    let value = value.to_lowercase();
+                    match (value.parse::<f64>(), wanted.parse::<f64>()) {
+                        (Ok(value), Ok(wanted)) => value == wanted,
+                        _ if field == "key" => value == *wanted,
+                        _ => value.contains(wanted),
+                    }
+                },
+                None => false,
+            },
+            Term::Range(ref field, low, high) => track.field(field)
+                .and_then(|value| value.parse::<f64>().ok())
+                .map_or(false, |value| value >= low && value <= high),
+        })
+    }
+}
+
 impl Track {
+    // A field by its lower case name, for searches
+    pub fn field(&self, name: &str) -> Option<String> {
+        match name {
+            "artist" => self.info.get("Artist").cloned(),
+            "title" => self.info.get("Name").cloned(),
+            "album" => self.info.get("Album").cloned(),
+            "key" => self.info.get("Tonality").cloned(),
+            "bpm" => Some(self.bpm().to_string()),
+            _ => self.info.iter().find(|&(k, _)| k.to_lowercase() == name).map(|(_, v)| v.clone()),
+        }
+    }
+
+    // Takes over the hot cues a player reports, added ones get no name. Returns whether anything changed.
+    pub fn sync_hotcues(&mut self, hotcues: &[(usize, f64)]) -> bool {
+        let count = self.cues.len();
+        self.cues.retain(|cue| cue.hotcue.map_or(true, |slot| hotcues.iter().any(|&(idx, _)| idx == slot)));
+        let mut changed = self.cues.len() != count;
+        for &(idx, pos) in hotcues {
+            let start = pos.max(0.0) as usize;
+            match self.cues.iter_mut().find(|cue| cue.hotcue == Some(idx)) {
+                Some(ref mut cue) if cue.start == start => (),
+                Some(cue) => {
+                    cue.start = start;
+                    changed = true;
+                },
+                None => {
+                    self.cues.push(Cue { name: String::new(), start: start, type_: 0, hotcue: Some(idx), colour: None });
+                    changed = true;
+                },
+            }
+        }
+        changed
+    }
+
     pub fn get_headers() -> Vec<&'static str> {
         return vec!["Artist", "Title", "Album", "Bpm", "Key"];
     }
@@ -81,7 +175,15 @@ impl Track {
             "Bpm" => self.bpm().to_string(),
             "Key" => self.key(),
             "Id" => self.id().to_string(),
-            _ => "?".to_string(),
+            "TotalTime" => match self.info.get("TotalTime").and_then(|t| t.parse::<u64>().ok()) {
+                Some(secs) => format!("{:02}:{:02}", secs / 60, secs % 60),
+                None => String::new(),
+            },
+            "Rating" => match self.info.get("Rating").and_then(|r| r.parse::<usize>().ok()) {
+                Some(rating) => "*".repeat(rating / 51), // rekordbox stores 0-255 for 0-5 stars
+                None => String::new(),
+            },
+            _ => self.info.get(typ).cloned().unwrap_or_default(),
         }
     }
 
@@ -140,11 +242,22 @@ impl Library {
                                     first_beat = (track_sub_node.attr("Inizio").unwrap().parse::<f32>().unwrap() 
                                                   * sample_rate as f32) as usize;
                                 },
-                                "POSITION_MARK" => cues.push(Cue {
-                                    name: track_sub_node.attr("Name").unwrap().to_string(),
-                                    start: (track_sub_node.attr("Start").unwrap().parse::<f32>().unwrap() * sample_rate as f32 - 1152.0*2.0) as usize,
-                                    type_: track_sub_node.attr("Type").unwrap().parse().unwrap(),
-                                }),
+                                "POSITION_MARK" => {
+                                    let attr = |name| track_sub_node.attr(name).and_then(|v| v.parse::<i32>().ok());
+                                    cues.push(Cue {
+                                        name: track_sub_node.attr("Name").unwrap().to_string(),
+                                        start: (track_sub_node.attr("Start").unwrap().parse::<f32>().unwrap() * sample_rate as f32 - 1152.0*2.0) as usize,
+                                        type_: track_sub_node.attr("Type").unwrap().parse().unwrap(),
+                                        hotcue: match attr("Num") {
+                                            Some(num) if num >= 0 => Some(num as usize),
+                                            _ => None,
+                                        },
+                                        colour: match (attr("Red"), attr("Green"), attr("Blue")) {
+                                            (Some(r), Some(g), Some(b)) => Some((r as u8, g as u8, b as u8)),
+                                            _ => None,
+                                        },
+                                    })
+                                },
                                 _ => println!("Warning"),
                             }
                         }
@@ -206,6 +319,11 @@ impl Library {
                 _ => (),
             };
         }
+        for (id, edited) in Library::edited_cues() {
+            if let Some(track) = tracks.get_mut(&id) {
+                track.cues = edited;
+            }
+        }
         println!("Done parsing");
 
         Library {
@@ -219,6 +337,35 @@ impl Library {
         self.tracks[&id].clone()
     }
 
+    // Ids of the tracks matching a search, in id order
+    pub fn search(&self, query: &str) -> Vec<usize> {
+        let filter = Filter::parse(query);
+        let mut ids : Vec<usize> = self.tracks.iter().filter(|&(_, track)| filter.matches(track)).map(|(id, _)| *id).collect();
+        ids.sort();
+        ids
+    }
+
+    fn edited_cues() -> HashMap<usize, Vec<Cue>> {
+        match File::open(CUES) {
+            Ok(file) => serde_json::from_reader(file).unwrap_or_else(|e| {
+                println!("{}: {}", CUES, e);
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        }
+    }
+
+    // Replaces the cues of a track and saves them to cues.json
+    pub fn set_cues(&mut self, id: usize, cues: Vec<Cue>) -> Result<(), String> {
+        let mut edited = Library::edited_cues();
+        edited.insert(id, cues.clone());
+        if let Some(track) = self.tracks.get_mut(&id) {
+            track.cues = cues;
+        }
+        let file = File::create(CUES).map_err(|e| format!("{}: {}", CUES, e))?;
+        serde_json::to_writer_pretty(file, &edited).map_err(|e| format!("{}: {}", CUES, e))
+    }
+
     pub fn handle(&self, cmd: LibraryCommand, txui: &mpsc::Sender<UICommand>) {
         println!("[Library] Handle cmd");
         match cmd {
@@ -228,3 +375,53 @@ impl Library {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(id: usize, artist: &str, title: &str, key: &str, bpm: u32, genre: &str) -> Track {
+        let mut info = HashMap::new();
+        info.insert("Artist".to_string(), artist.to_string());
+        info.insert("Name".to_string(), title.to_string());
+        info.insert("Album".to_string(), String::new());
+        info.insert("Tonality".to_string(), key.to_string());
+        info.insert("Genre".to_string(), genre.to_string());
+        Track { id: id, path: String::new(), info: info, bpm: bpm, sample_rate: 44100, cues: Vec::new(), first_beat: 0 }
+    }
+
+    #[test]
+    fn search() {
+        let library = Library {
+            tracks: vec![
+                track(1, "Daft Punk", "Around the World", "8A", 121, "House"),
+                track(2, "Daft Punk", "One More Time", "11A", 123, "House"),
+                track(3, "Aphex Twin", "Windowlicker", "1A", 127, "Electronic"),
+                track(4, "Orbital", "Chime", "8A", 130, "Techno"),
+            ].into_iter().map(|t| (t.id, t)).collect(),
+            root_playlist: None,
+            playlists: Vec::new(),
+        };
+        assert_eq!(library.search(""), vec![1, 2, 3, 4]);
+        assert_eq!(library.search("daft"), vec![1, 2]);
+        assert_eq!(library.search("DAFT world"), vec![1]);
+        assert_eq!(library.search("bpm:120-128"), vec![1, 2, 3]);
+        assert_eq!(library.search("bpm:120-128 key:8A"), vec![1]);
+        assert_eq!(library.search("key:1a"), vec![3]);
+        assert_eq!(library.search("bpm:130"), vec![4]);
+        assert_eq!(library.search("genre:hou"), vec![1, 2]);
+        assert_eq!(library.search("123"), vec![2]);
+        assert_eq!(library.search("rating:5"), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn sync_hotcues() {
+        let cue = |name: &str, start, hotcue| Cue { name: name.to_string(), start: start, type_: 0, hotcue: hotcue, colour: None };
+        let mut t = track(1, "Orbital", "Chime", "8A", 130, "Techno");
+        t.cues = vec![cue("Intro", 100, None), cue("Drop", 2000, Some(0)), cue("Break", 3000, Some(1))];
+        assert!(!t.sync_hotcues(&[(0, 2000.0), (1, 3000.0)]));
+        assert!(t.sync_hotcues(&[(0, 2500.0), (3, 4000.0)]));
+        let cues : Vec<(&str, usize, Option<usize>)> = t.cues.iter().map(|c| (&*c.name, c.start, c.hotcue)).collect();
+        assert_eq!(cues, vec![("Intro", 100, None), ("Drop", 2500, Some(0)), ("", 4000, Some(3))]);
+    }
+}
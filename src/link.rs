@@ -0,0 +1,802 @@
+extern crate net2;
+
+use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::process;
+use std::sync::mpsc::{Receiver, Sender, SyncSender};
+use std::time::{Duration, Instant};
+
+use mp3playerjack::{PlayerCommand, PlayerStatus};
+
+const PRINT : bool = true;
+const MULTICAST : Ipv4Addr = Ipv4Addr::new(224, 76, 78, 75);
+const PORT : u16 = 20808;
+const PROTOCOL_HEADER : &'static [u8] = b"_asdp_v\x01";
+const MEASUREMENT_HEADER : &'static [u8] = b"_link_v\x01";
+const ALIVE : u8 = 1;
+const RESPONSE : u8 = 2;
+const BYEBYE : u8 = 3;
+const PING : u8 = 1;
+const PONG : u8 = 2;
+const TTL : u8 = 5; // seconds
+const BROADCAST_INTERVAL : u64 = 250; // ms
+const TIMELINE : u32 = 0x746d6c6e; // 'tmln'
+const SESSION : u32 = 0x73657373; // 'sess'
+const START_STOP : u32 = 0x73747374; // 'stst'
+const MEASUREMENT_ENDPOINT : u32 = 0x6d657034; // 'mep4'
+const HOST_TIME : u32 = 0x5f5f6874; // '__ht'
+const GHOST_TIME : u32 = 0x5f5f6774; // '__gt'
+const PREV_GHOST_TIME : u32 = 0x5f706774; // '_pgt'
+const MEASUREMENT_POINTS : usize = 100; // offset estimates the median is taken of
+const MEASUREMENT_TIMEOUT : u64 = 50; // ms without a pong before pinging again
+const MEASUREMENT_TRIES : usize = 5;
+const REMEASURE : u64 = 30; // seconds until a session is measured again
+const SESSION_EPS : i64 = 500_000; // micros of ghost time sessions may differ by and still count as the same age
+const PHASE_GAIN : f64 = 0.1; // speed change per beat of phase error
+const MAX_PHASE_NUDGE : f64 = 0.04;
+const DRIFT_SMOOTHING : f64 = 0.1; // share of each new drift reading, Pos only moves a frame at a time
+const RESYNC_BEATS : f64 = 0.05; // lead: reanchor the timeline once the deck drifted this far
+
+macro_rules! printinfo {
+    ($tx:expr, $($args:expr),*) => {{
+        if PRINT {
+            println!($($args),*);
+        }
+        else {
+           $tx.try_send(PlayerStatus::Print(format!($($args),*))).is_ok();
+        }
+    }};
+}
+
+type NodeId = [u8; 8];
+
+// Host clock in microseconds since it started, steps of the system time leave it alone. Timelines are in ghost
+// time, the host time of a peer shifted by the offset of its session, which joining peers measure by ping pong
+// with a peer of the session.
+fn host_micros(clock: Instant) -> i64 {
+    let since = clock.elapsed();
+    since.as_secs() as i64 * 1_000_000 + since.subsec_nanos() as i64 / 1000
+}
+
+fn random_id() -> NodeId {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u32(process::id());
+    Instant::now().hash(&mut hasher);
+    let mut id = [0; 8];
+    for (n, b) in id.iter_mut().enumerate() {
+        *b = (hasher.finish() >> (n * 8)) as u8;
+    }
+    id
+}
+
+// Address of the interface multicast goes out on, for the measurement endpoint other peers ping
+fn local_ip() -> Ipv4Addr {
+    let addr = UdpSocket::bind("0.0.0.0:0").and_then(|socket| {
+        socket.connect((MULTICAST, PORT))?;
+        socket.local_addr()
+    });
+    match addr {
+        Ok(SocketAddr::V4(addr)) if !addr.ip().is_unspecified() => *addr.ip(),
+        _ => Ipv4Addr::new(127, 0, 0, 1),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Timeline {
+    pub micros_per_beat: i64,
+    pub beat_origin: i64, // micro beats
+    pub time_origin: i64, // ghost micros
+}
+
+impl Timeline {
+    pub fn new(bpm: f64, beat: f64, micros: i64) -> Timeline {
+        Timeline {
+            micros_per_beat: (60e6 / bpm) as i64,
+            beat_origin: (beat * 1e6) as i64,
+            time_origin: micros,
+        }
+    }
+
+    pub fn bpm(&self) -> f64 {
+        60e6 / self.micros_per_beat as f64
+    }
+
+    pub fn beat_at(&self, micros: i64) -> f64 {
+        self.beat_origin as f64 / 1e6 + (micros - self.time_origin) as f64 / self.micros_per_beat as f64
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StartStop {
+    pub playing: bool,
+    pub beats: i64, // micro beats
+    pub timestamp: i64, // ghost micros
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub kind: u8,
+    pub ttl: u8,
+    pub node: NodeId,
+    pub session: Option<NodeId>,
+    pub timeline: Option<Timeline>,
+    pub start_stop: Option<StartStop>,
+    pub endpoint: Option<SocketAddrV4>, // where the peer answers pings
+}
+
+// Ping or pong of a clock offset measurement
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ping {
+    pub kind: u8,
+    pub session: Option<NodeId>,
+    pub host_time: Option<i64>, // of the pinging peer, echoed by the pong
+    pub ghost_time: Option<i64>, // of the ponging peer
+    pub prev_ghost_time: Option<i64>, // of the previous pong, echoed
+}
+
+fn put_u32(out: &mut Vec<u8>, v: u32) {
+    out.extend_from_slice(&[(v >> 24) as u8, (v >> 16) as u8, (v >> 8) as u8, v as u8]);
+}
+
+fn put_i64(out: &mut Vec<u8>, v: i64) {
+    put_u32(out, (v >> 32) as u32);
+    put_u32(out, v as u32);
+}
+
+fn put_entry(out: &mut Vec<u8>, key: u32, value: &[u8]) {
+    put_u32(out, key);
+    put_u32(out, value.len() as u32);
+    out.extend_from_slice(value);
+}
+
+fn i64_bytes(v: i64) -> Vec<u8> {
+    let mut out = Vec::new();
+    put_i64(&mut out, v);
+    out
+}
+
+fn get_u32(data: &[u8], pos: usize) -> Option<u32> {
+    let b = data.get(pos .. pos + 4)?;
+    Some((b[0] as u32) << 24 | (b[1] as u32) << 16 | (b[2] as u32) << 8 | b[3] as u32)
+}
+
+fn get_i64(data: &[u8], pos: usize) -> Option<i64> {
+    Some(((get_u32(data, pos)? as u64) << 32 | get_u32(data, pos + 4)? as u64) as i64)
+}
+
+fn get_id(data: &[u8], pos: usize) -> Option<NodeId> {
+    let b = data.get(pos .. pos + 8)?;
+    let mut id = [0; 8];
+    id.copy_from_slice(b);
+    Some(id)
+}
+
+// Key, size and start of the value of each payload entry from pos on
+fn entries(data: &[u8], mut pos: usize) -> Option<Vec<(u32, usize, usize)>> {
+    let mut out = Vec::new();
+    while pos + 8 <= data.len() {
+        let key = get_u32(data, pos)?;
+        let size = get_u32(data, pos + 4)? as usize;
+        out.push((key, size, pos + 8));
+        pos = pos + 8 + size;
+    }
+    Some(out)
+}
+
+impl Message {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = PROTOCOL_HEADER.to_vec();
+        out.push(self.kind);
+        out.push(self.ttl);
+        out.extend_from_slice(&[0, 0]); //group
+        out.extend_from_slice(&self.node);
+        if let Some(ref timeline) = self.timeline {
+            put_u32(&mut out, TIMELINE);
+            put_u32(&mut out, 24);
+            put_i64(&mut out, timeline.micros_per_beat);
+            put_i64(&mut out, timeline.beat_origin);
+            put_i64(&mut out, timeline.time_origin);
+        }
+        if let Some(ref session) = self.session {
+            put_entry(&mut out, SESSION, session);
+        }
+        if let Some(ref start_stop) = self.start_stop {
+            put_u32(&mut out, START_STOP);
+            put_u32(&mut out, 17);
+            out.push(start_stop.playing as u8);
+            put_i64(&mut out, start_stop.beats);
+            put_i64(&mut out, start_stop.timestamp);
+        }
+        if let Some(ref endpoint) = self.endpoint {
+            put_u32(&mut out, MEASUREMENT_ENDPOINT);
+            put_u32(&mut out, 6);
+            out.extend_from_slice(&endpoint.ip().octets());
+            out.extend_from_slice(&[(endpoint.port() >> 8) as u8, endpoint.port() as u8]);
+        }
+        out
+    }
+
+    pub fn decode(data: &[u8]) -> Option<Message> {
+        if !data.starts_with(PROTOCOL_HEADER) {
+            return None;
+        }
+        let pos = PROTOCOL_HEADER.len();
+        let mut msg = Message {
+            kind: *data.get(pos)?,
+            ttl: *data.get(pos + 1)?,
+            node: get_id(data, pos + 4)?,
+            session: None,
+            timeline: None,
+            start_stop: None,
+            endpoint: None,
+        };
+        for (key, size, body) in entries(data, pos + 12)? {
+            match key {
+                TIMELINE if size >= 24 => msg.timeline = Some(Timeline {
+                    micros_per_beat: get_i64(data, body)?,
+                    beat_origin: get_i64(data, body + 8)?,
+                    time_origin: get_i64(data, body + 16)?,
+                }),
+                SESSION if size >= 8 => msg.session = Some(get_id(data, body)?),
+                START_STOP if size >= 17 => msg.start_stop = Some(StartStop {
+                    playing: *data.get(body)? != 0,
+                    beats: get_i64(data, body + 1)?,
+                    timestamp: get_i64(data, body + 9)?,
+                }),
+                MEASUREMENT_ENDPOINT if size >= 6 => {
+                    let b = data.get(body .. body + 6)?;
+                    msg.endpoint = Some(SocketAddrV4::new(Ipv4Addr::new(b[0], b[1], b[2], b[3]), (b[4] as u16) << 8 | b[5] as u16));
+                },
+                _ => (),
+            }
+        }
+        Some(msg)
+    }
+}
+
+impl Ping {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = MEASUREMENT_HEADER.to_vec();
+        out.push(self.kind);
+        if let Some(ref session) = self.session {
+            put_entry(&mut out, SESSION, session);
+        }
+        if let Some(ghost_time) = self.ghost_time {
+            put_entry(&mut out, GHOST_TIME, &i64_bytes(ghost_time));
+        }
+        if let Some(prev_ghost_time) = self.prev_ghost_time {
+            put_entry(&mut out, PREV_GHOST_TIME, &i64_bytes(prev_ghost_time));
+        }
+        if let Some(host_time) = self.host_time {
+            put_entry(&mut out, HOST_TIME, &i64_bytes(host_time));
+        }
+        out
+    }
+
+    pub fn decode(data: &[u8]) -> Option<Ping> {
+        if !data.starts_with(MEASUREMENT_HEADER) {
+            return None;
+        }
+        let pos = MEASUREMENT_HEADER.len();
+        let mut ping = Ping { kind: *data.get(pos)?, session: None, host_time: None, ghost_time: None, prev_ghost_time: None };
+        for (key, size, body) in entries(data, pos + 1)? {
+            match key {
+                SESSION if size >= 8 => ping.session = Some(get_id(data, body)?),
+                HOST_TIME if size >= 8 => ping.host_time = Some(get_i64(data, body)?),
+                GHOST_TIME if size >= 8 => ping.ghost_time = Some(get_i64(data, body)?),
+                PREV_GHOST_TIME if size >= 8 => ping.prev_ghost_time = Some(get_i64(data, body)?),
+                _ => (),
+            }
+        }
+        Some(ping)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum LinkMode {
+    Off,
+    Lead,
+    Follow,
+}
+
+#[derive(Debug, Deserialize)]
+pub enum LinkCommand {
+    Mode(LinkMode),
+    Quantum(f64),
+    #[serde(skip_deserializing)]
+    Master(usize, f64, f64, f64, bool), //deck, track bpm, speed, beat, playing
+}
+
+struct Peer {
+    session: NodeId,
+    expires: Instant,
+    endpoint: Option<SocketAddr>,
+    timeline: Option<Timeline>,
+    start_stop: Option<StartStop>,
+}
+
+// Offset of a session's ghost time from the host clock, measured by pinging one of its peers
+struct Measurement {
+    session: NodeId,
+    endpoint: SocketAddr,
+    data: Vec<f64>, // ghost minus host time estimates
+    prev_ghost_time: Option<i64>,
+    sent: Instant,
+    tries: usize,
+}
+
+impl Measurement {
+    fn new(session: NodeId, endpoint: SocketAddr) -> Measurement {
+        Measurement { session: session, endpoint: endpoint, data: Vec::new(), prev_ghost_time: None, sent: Instant::now(), tries: 0 }
+    }
+
+    fn ping(&self, now: i64) -> Ping {
+        Ping { kind: PING, session: None, host_time: Some(now), ghost_time: None, prev_ghost_time: self.prev_ghost_time }
+    }
+
+    // A pong received at host time now. The ghost time it carries was taken halfway through the round trip,
+    // the ping went out halfway between the previous pong and this one.
+    fn pong(&mut self, pong: &Ping, now: i64) {
+        if let (Some(ghost_time), Some(host_time)) = (pong.ghost_time, pong.host_time) {
+            self.data.push(ghost_time as f64 - (host_time + now) as f64 / 2.0);
+            if let Some(prev_ghost_time) = pong.prev_ghost_time {
+                self.data.push((ghost_time + prev_ghost_time) as f64 / 2.0 - host_time as f64);
+            }
+            self.prev_ghost_time = Some(ghost_time);
+        }
+    }
+
+    fn done(&self) -> bool {
+        self.data.len() >= MEASUREMENT_POINTS
+    }
+
+    fn offset(&self) -> i64 {
+        let mut data = self.data.clone();
+        data.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        data[data.len() / 2] as i64
+    }
+}
+
+// Whether a measured session takes over from the current one: the older one, with more ghost time, or
+// the one with the lower id when they started about the same time
+fn joins(offset: i64, session: &NodeId, current_offset: i64, current: &NodeId) -> bool {
+    let ahead = offset - current_offset;
+    ahead > SESSION_EPS || (ahead.abs() < SESSION_EPS && session < current)
+}
+
+// Membership and shared state of one Link session, as seen from this peer
+pub struct Link {
+    node: NodeId,
+    session: NodeId,
+    clock: Instant, // start of the host clock
+    ghost: i64, // ghost minus host time of the session
+    pub timeline: Timeline,
+    pub start_stop: StartStop,
+    peers: HashMap<NodeId, Peer>,
+    multicast: UdpSocket,
+    unicast: UdpSocket,
+    ping: UdpSocket,
+    endpoint: SocketAddrV4,
+    measurement: Option<Measurement>,
+    measured: HashMap<NodeId, Instant>, // last measurement of each session
+    last_broadcast: Instant,
+}
+
+impl Link {
+    pub fn new(bpm: f64) -> Result<Link, String> {
+        let multicast = net2::UdpBuilder::new_v4()
+            .and_then(|b| { b.reuse_address(true)?; b.bind(("0.0.0.0", PORT)) })
+            .map_err(|e| format!("Link: could not bind port {}: {}", PORT, e))?;
+        multicast.join_multicast_v4(&MULTICAST, &Ipv4Addr::new(0, 0, 0, 0)).map_err(|e| format!("Link: {}", e))?;
+        multicast.set_multicast_loop_v4(true).is_ok();
+        multicast.set_read_timeout(Some(Duration::from_millis(10))).is_ok();
+        let unicast = UdpSocket::bind("0.0.0.0:0").map_err(|e| format!("Link: {}", e))?;
+        unicast.set_nonblocking(true).is_ok();
+        let ping = UdpSocket::bind("0.0.0.0:0").map_err(|e| format!("Link: {}", e))?;
+        ping.set_nonblocking(true).is_ok();
+        let port = ping.local_addr().map_err(|e| format!("Link: {}", e))?.port();
+
+        let node = random_id();
+        // ghost time of a new session starts at 0, with the host clock
+        let clock = Instant::now();
+        Ok(Link {
+            node: node,
+            session: node,
+            clock: clock,
+            ghost: 0,
+            timeline: Timeline::new(bpm, 0.0, 0),
+            start_stop: StartStop { playing: false, beats: 0, timestamp: 0 },
+            peers: HashMap::new(),
+            multicast: multicast,
+            unicast: unicast,
+            ping: ping,
+            endpoint: SocketAddrV4::new(local_ip(), port),
+            measurement: None,
+            measured: HashMap::new(),
+            last_broadcast: Instant::now(),
+        })
+    }
+
+    pub fn peers(&self) -> usize {
+        self.peers.values().filter(|peer| peer.session == self.session).count()
+    }
+
+    // Session clock, the same for all peers of the session
+    pub fn ghost_time(&self) -> i64 {
+        host_micros(self.clock) + self.ghost
+    }
+
+    fn message(&self, kind: u8) -> Message {
+        Message {
+            kind: kind,
+            ttl: TTL,
+            node: self.node,
+            session: Some(self.session),
+            timeline: Some(self.timeline),
+            start_stop: Some(self.start_stop),
+            endpoint: Some(self.endpoint),
+        }
+    }
+
+    fn send(&self, kind: u8, to: SocketAddr) {
+        self.unicast.send_to(&self.message(kind).encode(), to).is_ok();
+    }
+
+    pub fn broadcast(&mut self) {
+        self.send(ALIVE, SocketAddr::from((MULTICAST, PORT)));
+        self.last_broadcast = Instant::now();
+    }
+
+    pub fn leave(&mut self) {
+        self.send(BYEBYE, SocketAddr::from((MULTICAST, PORT)));
+        self.peers.clear();
+    }
+
+    // Switches to a measured session if it wins, or takes the new offset of the current one.
+    // Returns true when the session changed.
+    fn measured(&mut self, session: NodeId, offset: i64) -> bool {
+        self.measured.insert(session, Instant::now());
+        if session == self.session {
+            self.ghost = offset;
+            return false;
+        }
+        if !joins(offset, &session, self.ghost, &self.session) {
+            return false;
+        }
+        self.session = session;
+        self.ghost = offset;
+        if let Some(peer) = self.peers.values().find(|peer| peer.session == session && peer.timeline.is_some()) {
+            self.timeline = peer.timeline.unwrap();
+            if let Some(start_stop) = peer.start_stop {
+                self.start_stop = start_stop;
+            }
+        }
+        true
+    }
+
+    // Answers pings and carries on with the running measurement, or starts one of a session that was not
+    // measured lately. Our own session only needs measuring when we joined it.
+    fn measure(&mut self) -> bool {
+        let mut changed = false;
+        let mut buf = [0; 512];
+        while let Ok((len, from)) = self.ping.recv_from(&mut buf) {
+            let ping = match Ping::decode(&buf[.. len]) {
+                Some(ping) => ping,
+                None => continue,
+            };
+            match ping.kind {
+                PING => {
+                    let pong = Ping { kind: PONG, session: Some(self.session), ghost_time: Some(self.ghost_time()), ..ping };
+                    self.ping.send_to(&pong.encode(), from).is_ok();
+                },
+                PONG => {
+                    let mut finished = None;
+                    if let Some(ref mut measurement) = self.measurement {
+                        if from == measurement.endpoint && ping.session == Some(measurement.session) {
+                            measurement.pong(&ping, host_micros(self.clock));
+                            if measurement.done() {
+                                finished = Some((measurement.session, measurement.offset()));
+                            }
+                            else {
+                                self.ping.send_to(&measurement.ping(host_micros(self.clock)).encode(), measurement.endpoint).is_ok();
+                                measurement.sent = Instant::now();
+                                measurement.tries = 0;
+                            }
+                        }
+                    }
+                    if let Some((session, offset)) = finished {
+                        self.measurement = None;
+                        changed |= self.measured(session, offset);
+                    }
+                },
+                _ => (),
+            }
+        }
+
+        let timeout = Duration::from_millis(MEASUREMENT_TIMEOUT);
+        let mut failed = None;
+        if let Some(ref mut measurement) = self.measurement {
+            if measurement.sent.elapsed() > timeout {
+                if measurement.tries == MEASUREMENT_TRIES {
+                    failed = Some(measurement.session);
+                }
+                else {
+                    self.ping.send_to(&measurement.ping(host_micros(self.clock)).encode(), measurement.endpoint).is_ok();
+                    measurement.sent = Instant::now();
+                    measurement.tries += 1;
+                }
+            }
+        }
+        if let Some(session) = failed {
+            self.measurement = None;
+            self.measured.insert(session, Instant::now());
+        }
+
+        if self.measurement.is_none() {
+            let remeasure = Duration::from_secs(REMEASURE);
+            let (node, session) = (self.node, self.session);
+            let due = self.peers.values().find(|peer| {
+                peer.endpoint.is_some() && !(peer.session == session && session == node)
+                    && self.measured.get(&peer.session).map_or(true, |at| at.elapsed() > remeasure)
+            }).map(|peer| (peer.session, peer.endpoint.unwrap()));
+            if let Some((session, endpoint)) = due {
+                let measurement = Measurement::new(session, endpoint);
+                self.ping.send_to(&measurement.ping(host_micros(self.clock)).encode(), endpoint).is_ok();
+                self.measurement = Some(measurement);
+            }
+        }
+        changed
+    }
+
+    // Handles incoming messages, returns true when the session timeline or start/stop state changed
+    pub fn poll(&mut self, follow: bool) -> bool {
+        let mut changed = false;
+        let mut buf = [0; 512];
+        loop {
+            let received = match self.unicast.recv_from(&mut buf) {
+                Ok(r) => Ok(r),
+                Err(_) => self.multicast.recv_from(&mut buf),
+            };
+            let (len, from) = match received {
+                Ok(r) => r,
+                Err(_) => break,
+            };
+            let msg = match Message::decode(&buf[.. len]) {
+                Some(msg) => msg,
+                None => continue,
+            };
+            if msg.node == self.node {
+                continue;
+            }
+            if msg.kind == BYEBYE {
+                self.peers.remove(&msg.node);
+                continue;
+            }
+            let session = msg.session.unwrap_or(msg.node);
+            if msg.kind == ALIVE && !self.peers.contains_key(&msg.node) {
+                self.send(RESPONSE, from);
+            }
+            self.peers.insert(msg.node, Peer {
+                session: session,
+                expires: Instant::now() + Duration::from_secs(msg.ttl as u64),
+                endpoint: msg.endpoint.map(SocketAddr::V4),
+                timeline: msg.timeline,
+                start_stop: msg.start_stop,
+            });
+            if session == self.session && follow {
+                if let Some(timeline) = msg.timeline {
+                    changed |= timeline != self.timeline;
+                    self.timeline = timeline;
+                }
+                if let Some(start_stop) = msg.start_stop {
+                    if start_stop.timestamp > self.start_stop.timestamp {
+                        changed |= start_stop.playing != self.start_stop.playing;
+                        self.start_stop = start_stop;
+                    }
+                }
+            }
+        }
+        let now = Instant::now();
+        self.peers.retain(|_, peer| peer.expires > now);
+        changed |= self.measure();
+        if self.last_broadcast + Duration::from_millis(BROADCAST_INTERVAL) <= now {
+            self.broadcast();
+        }
+        changed
+    }
+}
+
+fn wrap(x: f64, quantum: f64) -> f64 {
+    let x = x % quantum;
+    if x > quantum / 2.0 { x - quantum } else if x < -quantum / 2.0 { x + quantum } else { x }
+}
+
+pub fn run(rx: Receiver<LinkCommand>, tx_player: Sender<PlayerCommand>, tx: SyncSender<PlayerStatus>) {
+    let mut link : Option<Link> = None;
+    let mut mode = LinkMode::Off;
+    let mut quantum = 4.0;
+    let mut last_speed = 0.0;
+    let mut last_status = Instant::now();
+    let mut master : Option<(usize, f64, f64, f64, bool)> = None;
+    let mut drift = 0.0; // lead: smoothed beats the deck is ahead of the timeline
+
+    loop {
+        let remote = match link {
+            Some(ref mut link) => link.poll(mode == LinkMode::Follow),
+            None => {
+                ::std::thread::sleep(Duration::from_millis(10));
+                false
+            },
+        };
+        let mut changed = remote;
+
+        while let Ok(cmd) = rx.try_recv() {
+            match cmd {
+                LinkCommand::Mode(mode_) => {
+                    if mode_ == LinkMode::Off {
+                        if let Some(ref mut link) = link {
+                            link.leave();
+                        }
+                        link = None;
+                    }
+                    else if link.is_none() {
+                        let bpm = master.map_or(120.0, |m| if m.1 > 0.0 { m.1 * m.2 } else { 120.0 });
+                        match Link::new(bpm) {
+                            Ok(link_) => link = Some(link_),
+                            Err(e) => {
+                                printinfo!(tx, "{}", e);
+                                continue;
+                            },
+                        }
+                    }
+                    mode = mode_;
+                    changed = true;
+                },
+                LinkCommand::Quantum(quantum_) => {
+                    if quantum_ > 0.0 {
+                        quantum = quantum_;
+                    }
+                },
+                LinkCommand::Master(deck, track_bpm, speed, beat, playing) => {
+                    master = Some((deck, track_bpm, speed, beat, playing));
+                    changed = true;
+                },
+            }
+        }
+
+        let link = match link {
+            Some(ref mut link) => link,
+            None => continue,
+        };
+        if let (true, Some((deck, track_bpm, speed, beat, playing))) = (changed, master) {
+            let now = link.ghost_time();
+            match mode {
+                LinkMode::Lead if track_bpm > 0.0 => {
+                    let bpm = track_bpm * speed;
+                    drift = if playing { drift + (beat - link.timeline.beat_at(now) - drift) * DRIFT_SMOOTHING } else { 0.0 };
+                    let started = playing && !link.start_stop.playing;
+                    if (link.timeline.bpm() - bpm).abs() > 0.005 || drift.abs() > RESYNC_BEATS || started {
+                        link.timeline = Timeline::new(bpm, beat, now);
+                        drift = 0.0;
+                        link.broadcast();
+                    }
+                    if playing != link.start_stop.playing {
+                        link.start_stop = StartStop { playing: playing, beats: (beat * 1e6) as i64, timestamp: now };
+                        link.broadcast();
+                    }
+                },
+                LinkMode::Follow if track_bpm > 0.0 && link.peers() > 0 => {
+                    if remote && playing != link.start_stop.playing {
+                        tx_player.send(PlayerCommand::Deck(deck, Box::new(PlayerCommand::Play(link.start_stop.playing)))).is_ok();
+                    }
+                    // match the session tempo and pull the deck into phase within the quantum
+                    let error = wrap(link.timeline.beat_at(now) - beat, quantum);
+                    let nudge = (error * PHASE_GAIN).max(-MAX_PHASE_NUDGE).min(MAX_PHASE_NUDGE);
+                    let speed_ = link.timeline.bpm() / track_bpm * (1.0 + if playing { nudge } else { 0.0 });
+                    if (speed_ - last_speed).abs() > 0.0005 {
+                        last_speed = speed_;
+                        tx_player.send(PlayerCommand::Deck(deck, Box::new(PlayerCommand::Speed(speed_)))).is_ok();
+                    }
+                },
+                _ => (),
+            }
+        }
+
+        if last_status.elapsed() > Duration::from_millis(500) {
+            last_status = Instant::now();
+            tx.try_send(PlayerStatus::Link(mode, link.peers(), link.timeline.bpm(), link.start_stop.playing)).is_ok();
+        }
+    }
+}
+
+// Stand-in peer for testing against a second process or machine: joins the session, starts it at the given
+// tempo if it is alone and prints the session state every second
+pub fn peer(bpm: f64) {
+    let mut link = match Link::new(bpm) {
+        Ok(link) => link,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+    link.start_stop = StartStop { playing: true, beats: 0, timestamp: link.ghost_time() };
+    link.broadcast();
+    let mut last_print = Instant::now();
+    loop {
+        link.poll(true);
+        if last_print.elapsed() >= Duration::from_secs(1) {
+            last_print = Instant::now();
+            let beat = link.timeline.beat_at(link.ghost_time());
+            println!("peers: {} tempo: {:.2} beat: {:.2} playing: {}", link.peers(), link.timeline.bpm(), beat, link.start_stop.playing);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message() -> Message {
+        Message {
+            kind: ALIVE,
+            ttl: TTL,
+            node: [1, 2, 3, 4, 5, 6, 7, 8],
+            session: Some([8, 7, 6, 5, 4, 3, 2, 1]),
+            timeline: Some(Timeline::new(123.5, -2.25, -1_234_567_890_123)),
+            start_stop: Some(StartStop { playing: true, beats: 4_000_000, timestamp: 987_654_321 }),
+            endpoint: Some(SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 20), 50123)),
+        }
+    }
+
+    #[test]
+    fn message_roundtrip() {
+        let msg = message();
+        assert_eq!(Message::decode(&msg.encode()), Some(msg.clone()));
+        let bare = Message { kind: BYEBYE, session: None, timeline: None, start_stop: None, endpoint: None, ..msg.clone() };
+        assert_eq!(Message::decode(&bare.encode()), Some(bare));
+        // entries of newer protocol versions are skipped
+        let mut data = msg.encode();
+        put_entry(&mut data, 0x6e657773, &[1, 2, 3]);
+        assert_eq!(Message::decode(&data), Some(msg.clone()));
+        assert_eq!(Message::decode(b"_asdp_v\x02\x01\x05\x00\x00"), None);
+        assert_eq!(Message::decode(&msg.encode()[.. 14]), None);
+    }
+
+    #[test]
+    fn ping_roundtrip() {
+        let ping = Ping { kind: PING, session: None, host_time: Some(1_000_001), ghost_time: None, prev_ghost_time: Some(-5) };
+        assert_eq!(Ping::decode(&ping.encode()), Some(ping.clone()));
+        let pong = Ping { kind: PONG, session: Some([3; 8]), ghost_time: Some(42), ..ping };
+        assert_eq!(Ping::decode(&pong.encode()), Some(pong));
+        assert_eq!(Ping::decode(&message().encode()), None);
+    }
+
+    #[test]
+    fn measures_offset() {
+        // the peer's session clock is 5 s ahead of ours, pings take 300 to 700 us each way
+        let offset = 5_000_000;
+        let mut measurement = Measurement::new([0; 8], SocketAddr::from(([127, 0, 0, 1], PORT)));
+        let mut now = 1_000_000_000;
+        let mut n = 0;
+        while !measurement.done() {
+            let ping = measurement.ping(now);
+            let (there, back) = (300 + (n * 37) % 400, 300 + (n * 53) % 400);
+            let pong = Ping { kind: PONG, session: Some([0; 8]), ghost_time: Some(now + there + offset), ..ping };
+            now += there + back;
+            measurement.pong(&pong, now);
+            n += 1;
+        }
+        assert!((measurement.offset() - offset).abs() < 200, "{}", measurement.offset());
+    }
+
+    #[test]
+    fn older_session_wins() {
+        let (a, b) = ([1; 8], [2; 8]);
+        assert!(joins(-1_000, &b, -2_000_000, &a));
+        assert!(!joins(-2_000_000, &b, -1_000, &a));
+        assert!(joins(-1_000, &a, -2_000, &b));
+        assert!(!joins(-1_000, &b, -2_000, &a));
+    }
+}
@@ -23,27 +23,39 @@ mod mp3playerjack;
 mod library;
 #[allow(unused)]
 mod controller;
+mod midi;
+mod hid;
+mod script;
 #[allow(unreachable_patterns)]
 mod textui;
 #[allow(unreachable_patterns)]
 mod wsui;
+mod sampler;
+mod wav;
+mod decks;
+mod autodj;
+mod auxchannel;
+mod dvs;
+mod platter;
+mod link;
+mod midiclock;
+mod keymap;
+mod waveform;
+mod osc;
 
 use std::sync::mpsc;
 use std::sync::{Arc, Mutex};
 use std::thread;
 
 use mp3playerjack::{PlayerCommand, PlayerStatus};
+use sampler::SamplerCommand;
+use auxchannel::AuxCommand;
+use dvs::DvsCommand;
+use link::LinkCommand;
+use midiclock::ClockCommand;
 use ui::UIType;
 
 fn main() {
-    let (tx, rx) = mpsc::channel::<PlayerCommand>();
-    let (tx_r, rx_r) = mpsc::sync_channel::<PlayerStatus>(20);
-    let mut player = mp3playerjack::Mp3Player::new();
-    thread::spawn(move || {
-            player.play(rx, tx_r);
-    });
-    //player.run(rx, tx_r);
-
     let mut args = std::env::args();
     println!("Args: {:?}", std::env::args().collect::<Vec<String>>());
     args.next().unwrap();
@@ -52,6 +64,22 @@ fn main() {
             "ws" => UIType::WS,
             "tui" => UIType::Tui,
             "simple" => UIType::Simple,
+            "linkpeer" => {
+                link::peer(args.next().and_then(|s| s.parse().ok()).unwrap_or(120.0));
+                return;
+            },
+            "hidrecord" | "hidreplay" => {
+                let (a, b) = (args.next().unwrap_or_default(), args.next().unwrap_or_default());
+                let result = if s == "hidrecord" { hid::record(&a, &b) } else { hid::replay(&a, &b) };
+                if let Err(e) = result {
+                    println!("{}", e);
+                }
+                return;
+            },
+            "timecode" => {
+                timecode_wav(args.collect());
+                return;
+            },
             _ => {
                 println!("Unknown UI type: {}", s);
                 return;
@@ -60,5 +88,67 @@ fn main() {
         _ => UIType::Simple,
     };
 
-    ui::run(uitype, tx, rx_r, Arc::new(Mutex::new(library::Library::from_rb("rb_out.xml"))));
+    let library = Arc::new(Mutex::new(library::Library::from_rb("rb_out.xml")));
+
+    let (tx, rx) = mpsc::channel::<PlayerCommand>();
+    let (tx_r, rx_r) = mpsc::sync_channel::<PlayerStatus>(20);
+    let (tx_s, rx_s) = mpsc::channel::<SamplerCommand>();
+    let tx_r_ = tx_r.clone();
+    thread::spawn(move || {
+            sampler::run(rx_s, tx_r_);
+    });
+    let (tx_a, rx_a) = mpsc::channel::<AuxCommand>();
+    let tx_ = tx.clone();
+    let tx_r_ = tx_r.clone();
+    thread::spawn(move || {
+            auxchannel::run(rx_a, tx_, tx_r_);
+    });
+    let (tx_d, rx_d) = mpsc::channel::<DvsCommand>();
+    let tx_ = tx.clone();
+    let tx_r_ = tx_r.clone();
+    thread::spawn(move || {
+            dvs::run(rx_d, tx_, tx_r_);
+    });
+    let (tx_l, rx_l) = mpsc::channel::<LinkCommand>();
+    let tx_ = tx.clone();
+    let tx_r_ = tx_r.clone();
+    thread::spawn(move || {
+            link::run(rx_l, tx_, tx_r_);
+    });
+    let (tx_c, rx_c) = mpsc::channel::<ClockCommand>();
+    let tx_r_ = tx_r.clone();
+    thread::spawn(move || {
+            midiclock::run(rx_c, tx_r_);
+    });
+    let library_ = library.clone();
+    thread::spawn(move || {
+            decks::run(rx, tx_r, tx_s, tx_a, tx_d, tx_l, tx_c, library_);
+    });
+
+    ui::run(uitype, tx, rx_r, library);
+}
+
+// rustydj timecode <name> <out.wav> [start secs] [speed] [length secs]
+fn timecode_wav(args: Vec<String>) {
+    let def = match args.get(0).and_then(|name| dvs::timecode(name)) {
+        Some(def) => def,
+        None => {
+            println!("Usage: timecode <{}> <out.wav> [start] [speed] [length]",
+                     dvs::TIMECODES.iter().map(|def| def.name).collect::<Vec<_>>().join("|"));
+            return;
+        }
+    };
+    let path = match args.get(1) {
+        Some(path) => path,
+        None => {
+            println!("Missing output file");
+            return;
+        }
+    };
+    let arg = |n: usize, default: f64| args.get(n).and_then(|s| s.parse().ok()).unwrap_or(default);
+    let samples = dvs::synthesize(def, &def.bitstream(), arg(2, 0.0), arg(3, 1.0), arg(4, 60.0), 44100);
+    match dvs::write_wav(path, 44100, &samples) {
+        Ok(_) => println!("Wrote {} seconds of {} to {}", samples.len() / 44100, def.name, path),
+        Err(e) => println!("{}: {}", path, e),
+    }
 }
@@ -0,0 +1,239 @@
+extern crate jack;
+
+use std::collections::HashMap;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use jack::prelude::{AsyncClient, Client, JackControl, JackPortId, MidiInPort, MidiInSpec, MidiOutPort, MidiOutSpec,
+                    NotificationHandler, Port, PortFlags, PortSpec, ProcessHandler, ProcessScope, RawMidi,
+                    RingBuffer, RingBufferReader, RingBufferWriter, client_options};
+
+use mp3playerjack::PlayerCommand;
+use ui::UICommand;
+use controller::{Controller, Feedback, Learn, Learned, Mapping, SharedFeedback, blink_phase};
+
+const SETTLE_MS : u64 = 200; // a device registers its ports one by one, wait for the rest before rescanning
+const RING_SIZE : usize = 16384; // bytes of MIDI queued each way between the process callback and the controller thread
+const MAX_MIDI : usize = 256; // longest message passed through, longer SysEx is dropped
+const POLL_MS : u64 = 1; // how often the controller thread looks for new messages
+
+// Wakes the manager whenever a port comes or goes
+struct Watcher {
+    tx: mpsc::Sender<()>,
+}
+
+impl NotificationHandler for Watcher {
+    fn port_registration(&mut self, _: &Client, _port_id: JackPortId, _is_registered: bool) {
+        self.tx.send(()).is_ok();
+    }
+}
+
+// Queues a message with its length in front, drops it when the ring is full
+fn push(ring: &mut RingBufferWriter, bytes: &[u8]) {
+    if bytes.len() <= MAX_MIDI && ring.space() >= bytes.len() + 2 {
+        ring.write_buffer(&[(bytes.len() >> 8) as u8, bytes.len() as u8]);
+        ring.write_buffer(bytes);
+    }
+}
+
+// Length of the next message, read into buf once all of it is there
+fn pop(ring: &mut RingBufferReader, buf: &mut [u8; MAX_MIDI]) -> Option<usize> {
+    let mut header = [0; 2];
+    if ring.peek(&mut header) < 2 {
+        return None;
+    }
+    let len = (header[0] as usize) << 8 | header[1] as usize;
+    if ring.space() < len + 2 {
+        return None;
+    }
+    ring.advance(2);
+    ring.read_buffer(&mut buf[.. len]);
+    Some(len)
+}
+
+// Only moves MIDI between the ports and the controller thread, the mapping and its script run there
+struct ControllerProcess {
+    port: Port<MidiInSpec>,
+    out: Port<MidiOutSpec>,
+    input: RingBufferWriter,
+    output: RingBufferReader,
+    buf: [u8; MAX_MIDI],
+}
+
+impl ProcessHandler for ControllerProcess {
+    fn process(&mut self, _: &Client, ps: &ProcessScope) -> JackControl {
+        let buffer = MidiInPort::new(&mut self.port, ps);
+        for elem in buffer.iter() {
+            push(&mut self.input, elem.bytes);
+        }
+
+        let mut writer = MidiOutPort::new(&mut self.out, ps);
+        while let Some(len) = pop(&mut self.output, &mut self.buf) {
+            writer.write(&RawMidi { time: 0, bytes: &self.buf[.. len] }).is_ok();
+        }
+        JackControl::Continue
+    }
+}
+
+// Runs a controller on the messages of its device and queues the LED updates, until stop is dropped
+fn control(mut ctrl: Controller, mut input: RingBufferReader, mut output: RingBufferWriter, stop: mpsc::Receiver<()>,
+           tx: mpsc::Sender<PlayerCommand>, txui: mpsc::Sender<UICommand>, feedback: SharedFeedback) {
+    let started = Instant::now();
+    let mut snapshot = Feedback::new(); // copy of the shared feedback, so the lock is not held while scripts run
+    let mut buf = [0; MAX_MIDI];
+    while let Err(mpsc::RecvTimeoutError::Timeout) = stop.recv_timeout(Duration::from_millis(POLL_MS)) {
+        if let Ok(feedback) = feedback.lock() {
+            snapshot.clone_from(&feedback);
+        }
+        while let Some(len) = pop(&mut input, &mut buf) {
+            ctrl.handle_midi(&buf[.. len], &snapshot, &tx, &txui);
+        }
+
+        let elapsed = started.elapsed();
+        let ms = elapsed.as_secs() * 1000 + elapsed.subsec_nanos() as u64 / 1_000_000;
+        ctrl.tick(ms, &snapshot, &tx, &txui);
+        for bytes in ctrl.feedback(&snapshot, blink_phase(ms)) {
+            push(&mut output, &bytes);
+        }
+    }
+}
+
+// Attached controllers and their mapping names by the name of the device port they read from
+struct Manager {
+    watcher: AsyncClient<Watcher, ()>,
+    attached: HashMap<String, (String, AsyncClient<(), ControllerProcess>, mpsc::Sender<()>)>, // with the stop of its thread
+    tx: mpsc::Sender<PlayerCommand>,
+    txui: mpsc::Sender<UICommand>,
+    learn: Learn,
+    saver: mpsc::Sender<Learned>,
+    feedback: SharedFeedback,
+}
+
+impl Manager {
+    // Ports come and go while the UI holds the terminal, the messages go to it
+    fn print(&self, message: String) {
+        self.txui.send(UICommand::Print(message)).is_ok();
+    }
+
+    fn attach(&mut self, name: &str) {
+        let aliases = match self.watcher.port_by_name(name) {
+            Some(port) => port.aliases(),
+            None => return,
+        };
+        // Devices without a mapping get an empty one that learn mode can fill
+        let blank = aliases.get(0).cloned().unwrap_or(name.to_string());
+        let mut ctrl = Controller::new(aliases, self.learn.clone())
+            .unwrap_or_else(|| Controller::from_mapping(Mapping::blank(&blank), self.learn.clone()));
+        ctrl.saver = Some(self.saver.clone());
+        // The device's own MIDI input, for the LEDs
+        let sinks = self.watcher.ports(None, Some(MidiOutSpec::default().jack_port_type()), PortFlags::from_bits(0x4|0x1).unwrap());
+        let sink = sinks.iter()
+            .find(|sink| self.watcher.port_by_name(sink).map_or(false, |port| ctrl.mapping.matches(&port.aliases())))
+            .cloned();
+
+        let client = match Client::new(&format!("rustydj_midi_{}", name), client_options::NO_START_SERVER) {
+            Ok((client, _)) => client,
+            Err(e) => {
+                self.print(format!("Could not attach {}: {:?}", name, e));
+                return;
+            },
+        };
+        let port = client.register_port("in", MidiInSpec::default()).unwrap();
+        let out = client.register_port("out", MidiOutSpec::default()).unwrap();
+        let port_name = port.name().to_string();
+        let out_name = out.name().to_string();
+        let mapping_name = ctrl.mapping.name.clone();
+        let (input_reader, input_writer) = RingBuffer::new(RING_SIZE).unwrap().into_reader_writer();
+        let (output_reader, output_writer) = RingBuffer::new(RING_SIZE).unwrap().into_reader_writer();
+        let (stop, stopped) = mpsc::channel();
+        let (tx, txui, feedback) = (self.tx.clone(), self.txui.clone(), self.feedback.clone());
+        thread::spawn(move || control(ctrl, input_reader, output_writer, stopped, tx, txui, feedback));
+        let process = ControllerProcess {
+            port: port,
+            out: out,
+            input: input_writer,
+            output: output_reader,
+            buf: [0; MAX_MIDI],
+        };
+        let active_client = AsyncClient::new(client, (), process).unwrap();
+        self.print(format!("Attached {} as {}", name, mapping_name));
+        self.print(format!("Connect {} -> {}", name, port_name));
+        active_client.connect_ports_by_name(name, &port_name).is_ok();
+        if let Some(sink) = sink {
+            self.print(format!("Connect {} -> {}", out_name, sink));
+            active_client.connect_ports_by_name(&out_name, &sink).is_ok();
+        }
+        self.attached.insert(name.to_string(), (mapping_name, active_client, stop));
+    }
+
+    fn detach(&mut self, name: &str) {
+        // dropping stop ends the controller thread
+        if let Some((mapping_name, active_client, _stop)) = self.attached.remove(name) {
+            match active_client.deactivate() {
+                Ok(_) => self.print(format!("Detached {} ({})", name, mapping_name)),
+                Err(e) => self.print(format!("Could not detach {}: {:?}", name, e)),
+            }
+        }
+    }
+
+    // Attaches new MIDI sources and detaches the ones that went away, returns whether anything changed
+    fn scan(&mut self) -> bool {
+        let sources = self.watcher.ports(None, Some(MidiInSpec::default().jack_port_type()), PortFlags::from_bits(0x4|0x2).unwrap());
+        let gone : Vec<String> = self.attached.keys().filter(|name| !sources.contains(name)).cloned().collect();
+        let new : Vec<String> = sources.into_iter().filter(|name| !self.attached.contains_key(name)).collect();
+        for name in gone.iter() {
+            self.detach(name);
+        }
+        for name in new.iter() {
+            self.attach(name);
+        }
+        gone.len() > 0 || new.len() > 0
+    }
+
+    fn report(&self) {
+        let mut connected : Vec<String> = self.attached.iter()
+            .map(|(name, &(ref mapping_name, _, _))| format!("{} ({})", mapping_name, name)).collect();
+        connected.sort();
+        if connected.len() == 0 {
+            self.print("Warning no midi device".to_string());
+        }
+        self.txui.send(UICommand::Controllers(connected)).is_ok();
+    }
+}
+
+pub fn run(tx : mpsc::Sender<PlayerCommand>, txui: mpsc::Sender<UICommand>, learn: Learn, feedback: SharedFeedback) {
+    let (tn, rn) = mpsc::channel::<()>();
+    let client = Client::new("rustydj_midi", client_options::NO_START_SERVER).unwrap().0;
+    let watcher = AsyncClient::new(client, Watcher { tx: tn }, ()).unwrap();
+
+    // Learned bindings come from the controller threads, the mapping files are written here so input keeps flowing
+    let (saver, rs) = mpsc::channel::<Learned>();
+    let txui_ = txui.clone();
+    thread::spawn(move || {
+        for learned in rs.iter() {
+            learned.save(&txui_);
+        }
+    });
+
+    thread::spawn(move || {
+        let mut manager = Manager {
+            watcher: watcher,
+            attached: HashMap::new(),
+            tx: tx,
+            txui: txui,
+            learn: learn,
+            saver: saver,
+            feedback: feedback,
+        };
+        manager.scan();
+        manager.report();
+        while rn.recv().is_ok() {
+            thread::sleep(Duration::from_millis(SETTLE_MS));
+            while rn.try_recv().is_ok() {}
+            if manager.scan() {
+                manager.report();
+            }
+        }
+    });
+}
@@ -0,0 +1,223 @@
+extern crate jack;
+
+use std::sync::mpsc;
+use std::sync::mpsc::{Receiver, SyncSender};
+
+use mp3playerjack::PlayerStatus;
+
+use jack::prelude::{AsyncClient, Client, ClosureProcessHandler, JackControl, MidiOutPort, MidiOutSpec,
+                    ProcessScope, RawMidi, client_options};
+
+const PRINT : bool = true;
+const PPQN : f64 = 24.0;
+const MTC_FPS : f64 = 25.0;
+const MTC_RATE : u8 = 1; // 25 fps
+const RESYNC_BEATS : f64 = 0.05; // reanchor once the master deck drifted this far from the running clock
+const JUMP_BEATS : f64 = 0.5; // anything further is a seek and repositions the receivers
+
+const CLOCK : u8 = 0xF8;
+const START : u8 = 0xFA;
+const CONTINUE : u8 = 0xFB;
+const STOP : u8 = 0xFC;
+const SONG_POSITION : u8 = 0xF2;
+const QUARTER_FRAME : u8 = 0xF1;
+const MAX_EVENTS : usize = 256; // per process cycle, the buffer is allocated once and never grows
+
+macro_rules! printinfo {
+    ($tx:expr, $($args:expr),*) => {{
+        if PRINT {
+            println!($($args),*);
+        }
+        else {
+           $tx.try_send(PlayerStatus::Print(format!($($args),*))).is_ok();
+        }
+    }};
+}
+
+#[derive(Debug, Deserialize)]
+pub enum ClockCommand {
+    Enable(bool),
+    Mtc(bool),
+    #[serde(skip_deserializing)]
+    Master(f64, f64, f64, f64, bool), //bpm, beat, seconds, speed, playing
+}
+
+// Frame offset and message, sized for the longest one, the MTC full frame SysEx
+type Event = (u32, [u8; 10], usize);
+
+fn push(events: &mut Vec<Event>, time: u32, bytes: &[u8]) {
+    if events.len() < MAX_EVENTS {
+        let mut event = (time, [0; 10], bytes.len());
+        event.1[.. bytes.len()].copy_from_slice(bytes);
+        events.push(event);
+    }
+}
+
+// Running position of the master deck, extrapolated between updates in the audio thread
+struct Clock {
+    enabled: bool,
+    mtc: bool,
+    bpm: f64,
+    beat: f64,
+    secs: f64,
+    speed: f64,
+    playing: bool,
+    next_tick: i64, // in 1/24 beats
+    next_quarter: i64, // in quarter frames
+}
+
+impl Clock {
+    fn new() -> Clock {
+        Clock {
+            enabled: false,
+            mtc: false,
+            bpm: 0.0,
+            beat: 0.0,
+            secs: 0.0,
+            speed: 1.0,
+            playing: false,
+            next_tick: 0,
+            next_quarter: 0,
+        }
+    }
+
+    fn reposition(&mut self, events: &mut Vec<Event>) {
+        let sixteenths = (self.beat.max(0.0) * 4.0).floor() as i64;
+        self.next_tick = sixteenths * 6;
+        push(events, 0, &[SONG_POSITION, (sixteenths & 0x7F) as u8, ((sixteenths >> 7) & 0x7F) as u8]);
+        if self.mtc {
+            self.next_quarter = (self.secs.max(0.0) * MTC_FPS * 4.0).ceil() as i64;
+            let (h, m, s, f) = timecode(self.secs);
+            push(events, 0, &[0xF0, 0x7F, 0x7F, 0x01, 0x01, MTC_RATE << 5 | h, m, s, f, 0xF7]);
+        }
+    }
+
+    fn update(&mut self, bpm: f64, beat: f64, secs: f64, speed: f64, playing: bool, events: &mut Vec<Event>) {
+        let drift = (beat - self.beat).abs();
+        let anchor = !self.playing || bpm != self.bpm || drift > RESYNC_BEATS;
+        if anchor {
+            self.bpm = bpm;
+            self.beat = beat;
+            self.secs = secs;
+            self.speed = speed;
+        }
+        if !self.enabled {
+            self.playing = playing;
+            return;
+        }
+        if playing && !self.playing {
+            if beat <= 0.0 {
+                self.next_tick = 0;
+                self.next_quarter = 0;
+                push(events, 0, &[START]);
+            }
+            else {
+                self.reposition(events);
+                push(events, 0, &[CONTINUE]);
+            }
+        }
+        else if !playing && self.playing {
+            push(events, 0, &[STOP]);
+        }
+        else if playing && drift > JUMP_BEATS {
+            push(events, 0, &[STOP]);
+            self.reposition(events);
+            push(events, 0, &[CONTINUE]);
+        }
+        self.playing = playing;
+    }
+
+    fn render(&mut self, frames: u32, sample_rate: f64, events: &mut Vec<Event>) {
+        if !self.enabled || !self.playing {
+            return;
+        }
+        // clock ticks and quarter frames are merged in time order, after the update events at frame 0
+        let beats_per_frame = self.bpm / 60.0 / sample_rate;
+        let secs_per_frame = self.speed / sample_rate;
+        loop {
+            let tick = if beats_per_frame > 0.0 && self.next_tick as f64 / PPQN < self.beat + frames as f64 * beats_per_frame {
+                Some(((self.next_tick as f64 / PPQN - self.beat) / beats_per_frame).max(0.0))
+            } else { None };
+            let quarter = if self.mtc && secs_per_frame > 0.0 && self.next_quarter as f64 / MTC_FPS / 4.0 < self.secs + frames as f64 * secs_per_frame {
+                Some(((self.next_quarter as f64 / MTC_FPS / 4.0 - self.secs) / secs_per_frame).max(0.0))
+            } else { None };
+            match (tick, quarter) {
+                (Some(tick), quarter) if quarter.map_or(true, |quarter| tick <= quarter) => {
+                    push(events, tick as u32, &[CLOCK]);
+                    self.next_tick += 1;
+                },
+                (_, Some(quarter)) => {
+                    push(events, quarter as u32, &quarter_frame(self.next_quarter));
+                    self.next_quarter += 1;
+                },
+                _ => break,
+            }
+        }
+        self.beat += frames as f64 * beats_per_frame;
+        self.secs += frames as f64 * secs_per_frame;
+    }
+}
+
+fn timecode(secs: f64) -> (u8, u8, u8, u8) {
+    let frames = (secs.max(0.0) * MTC_FPS) as u64;
+    let s = frames / MTC_FPS as u64;
+    ((s / 3600 % 24) as u8, (s / 60 % 60) as u8, (s % 60) as u8, (frames % MTC_FPS as u64) as u8)
+}
+
+// Eight quarter frames carry the time of the frame in which the first of them was sent
+fn quarter_frame(quarter: i64) -> [u8; 2] {
+    let piece = (quarter % 8) as u8;
+    let (h, m, s, f) = timecode((quarter - quarter % 8) as f64 / 4.0 / MTC_FPS);
+    let nibble = match piece {
+        0 => f & 0xF,
+        1 => f >> 4,
+        2 => s & 0xF,
+        3 => s >> 4,
+        4 => m & 0xF,
+        5 => m >> 4,
+        6 => h & 0xF,
+        _ => (h >> 4) & 0x1 | MTC_RATE << 1,
+    };
+    [QUARTER_FRAME, piece << 4 | nibble]
+}
+
+pub fn run(rx: Receiver<ClockCommand>, tx: SyncSender<PlayerStatus>) {
+    let (tc, rc) = mpsc::sync_channel::<ClockCommand>(64);
+
+    let client = Client::new("rustydj_clock", client_options::NO_START_SERVER).unwrap().0;
+    let mut out = client.register_port("out", MidiOutSpec::default()).unwrap();
+    printinfo!(tx, "MIDI clock on {}", out.name());
+    let sample_rate = client.sample_rate() as f64;
+
+    let mut clock = Clock::new();
+    let mut events = Vec::with_capacity(MAX_EVENTS);
+    let process = ClosureProcessHandler::new(move |_client: &Client, ps: &ProcessScope| -> JackControl {
+        events.clear();
+        while let Ok(cmd) = rc.try_recv() {
+            match cmd {
+                ClockCommand::Enable(on) => {
+                    if !on && clock.enabled && clock.playing {
+                        push(&mut events, 0, &[STOP]);
+                    }
+                    clock.enabled = on;
+                    clock.playing = false;
+                },
+                ClockCommand::Mtc(on) => clock.mtc = on,
+                ClockCommand::Master(bpm, beat, secs, speed, playing) => clock.update(bpm, beat, secs, speed, playing, &mut events),
+            }
+        }
+        clock.render(ps.n_frames(), sample_rate, &mut events);
+
+        let mut writer = MidiOutPort::new(&mut out, ps);
+        for &(time, ref bytes, len) in events.iter() {
+            writer.write(&RawMidi { time: time.min(ps.n_frames() - 1), bytes: &bytes[.. len] }).is_ok();
+        }
+        JackControl::Continue
+    });
+    let active_client = AsyncClient::new(client, (), process).unwrap();
+
+    for cmd in rx.iter() {
+        tc.send(cmd).is_ok();
+    }
+    active_client.deactivate().unwrap();
+}
@@ -5,14 +5,28 @@ extern crate jack;
 
 use simplemad::{Decoder, Frame, MadFixed32};
 use std::time::Duration;
+use std::fs;
 use std::fs::File;
 use std::path::Path;
 use std::cell::Cell;
-use std::sync::{mpsc};
+use std::sync::{mpsc, Arc};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Instant;
+use std::fmt;
 use std::sync::mpsc::{SyncSender, Receiver};
 use std::collections::HashMap;
 
-use library::Track;
+use library::{Track, Cue};
+use sampler::SamplerCommand;
+use autodj::AutoDJCommand;
+use auxchannel::AuxCommand;
+use dvs::{DvsCommand, DvsMode};
+use platter::{Platter, PlatterConfig, PlatterEvent};
+use link::{LinkCommand, LinkMode};
+use midiclock::ClockCommand;
+use wav;
+use waveform::Waveform;
 
 use jack::prelude::{AsyncClient, AudioOutPort, AudioOutSpec, Client, ClosureProcessHandler,
                     JackControl, ProcessScope, client_options, PortFlags, PortSpec};
@@ -26,10 +40,64 @@ pub enum PlayerCommand {
     Cue(bool),
     CueMove(bool),
     HotCue(usize,bool),
+    DeleteHotCue(usize),
+    BeatJump(f64), //beats
+    Loop(f64), //beats from the last beat of the grid, 0 leaves the loop
+    LoopScale(f64), //multiplies the length of the active loop
     Speed(f64),
     SpeedDiff(f64),
     Scratch(f64),
     Open(Track),
+    Cues(Vec<Cue>), //replaces the cues of the loaded track, as edited in the UI
+    GrabSample(usize, f64), //slot, beats from the playhead when no loop is active
+    StemVolume(usize, f64),
+    StemMute(usize, bool),
+    Sampler(SamplerCommand),
+    Play(bool),
+    Volume(f64),
+    Deck(usize, Box<PlayerCommand>),
+    SelectDeck(usize),
+    SetMaster(usize),
+    Sync(bool), //locks the tempo of a deck to the master deck
+    AutoDJ(AutoDJCommand),
+    Double(usize), //source deck
+    Duck(f64),
+    Crossfader(f64), //0 plays the even decks, 1 the odd ones
+    Fade(f64), //crossfader gain of a deck
+    Aux(AuxCommand),
+    Timecode(f64, Option<f64>), //speed, record position in seconds
+    Dvs(DvsCommand),
+    JogTouch(bool),
+    Jog(f64), //revolutions, scratches while touched and nudges otherwise
+    Platter(PlatterConfig),
+    Link(LinkCommand),
+    Clock(ClockCommand),
+    #[serde(skip_deserializing)]
+    CloneTo(mpsc::Sender<PlayerCommand>),
+    #[serde(skip_deserializing)]
+    Restore(Box<DeckSnapshot>),
+}
+
+// Everything a deck needs to continue playing another deck's track, sharing its decoded audio
+pub struct DeckSnapshot {
+    track: Track,
+    frames: Arc<Vec<Frame>>,
+    stems: Arc<Vec<Vec<Frame>>>,
+    stem_names: Vec<String>,
+    stem_gains: Vec<(f64, bool)>,
+    sample_pos: f64,
+    speed: f64,
+    playing: bool,
+    cue: f64,
+    hotcues: HashMap<usize, f64>,
+    looping: Option<(f64, f64)>,
+    taken: Instant,
+}
+
+impl fmt::Debug for DeckSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "DeckSnapshot({} at {})", self.track.path, self.sample_pos)
+    }
 }
 
 #[derive(Debug, Serialize)]
@@ -38,19 +106,71 @@ pub enum PlayerStatus {
     Pos(Duration, f64),
     Speed(f64),
     Print(String),
+    Sampler(usize, Option<String>, bool), //slot, name, playing
+    Stems(Vec<(String, f64, bool)>), //name, volume, muted
+    Playing(bool),
+    Deck(usize, Box<PlayerStatus>),
+    SelectedDeck(usize),
+    Master(usize),
+    AutoDJ(bool, bool, usize), //running, paused, tracks left
+    Aux(f64, bool, bool, bool), //gain, muted, talkover, ducking
+    Dvs(usize, DvsMode, String), //deck, mode, timecode
+    Link(LinkMode, usize, f64, bool), //mode, peers, tempo, playing
+    Cue(f64), //sample position
+    HotCues(Vec<(usize, f64)>), //hot cues that are set, with their sample position
+    Tempo(f64), //pitch fader, without bends
+    Volume(f64),
+    Loop(Option<(f64, f64)>), //start and end sample of the active loop
+    Waveform(Waveform), //of the track just loaded
+    Crossfader(f64),
+    Sync(bool),
+    Level(f64), //peak of the samples played, after volume, ducking and crossfader
+}
+
+// Samples of frame i at the given volume, with the stems mixed down if the track has any
+fn mix(frames: &[Frame], stems: &[Vec<Frame>], i : usize, stem_gains : &[(f64, bool)], volume : f64) -> Option<Vec<Vec<MadFixed32>>> {
+    let f = frames.get(i)?;
+    if stems.len() == 0 && volume == 1.0 {
+        return Some(f.samples.clone());
+    }
+    let gain = |k : usize| -> f32 {
+        match stem_gains.get(k) {
+            Some(&(_, true)) => 0.0,
+            Some(&(stem_volume, false)) => (stem_volume * volume) as f32,
+            None => volume as f32,
+        }
+    };
+    let mut out : Vec<Vec<f32>> = f.samples.iter().map(|ch| ch.iter().map(|s| s.to_f32() * gain(0)).collect()).collect();
+    for (k, stem) in stems.iter().enumerate() {
+        if let Some(sf) = stem.get(i) {
+            for (out_ch, stem_ch) in out.iter_mut().zip(sf.samples.iter()) {
+                for (o, s) in out_ch.iter_mut().zip(stem_ch.iter()) {
+                    *o += s.to_f32() * gain(k + 1);
+                }
+            }
+        }
+    }
+    Some(out.into_iter().map(|ch| ch.into_iter().map(MadFixed32::from).collect()).collect())
 }
 
 pub struct Mp3Player {
-    _frames : Vec<Frame>,
+    deck : usize,
+    _frames : Arc<Vec<Frame>>,
+    _stems : Arc<Vec<Vec<Frame>>>, // additional stems, _frames holds the first one
+    stem_names : Vec<String>,
     _out_port : Option<Box<jack::port::Port<jack::port::AudioOutSpec>>>,
     _client : Option<Box<jack::client::Client>>,
     txui: Option<mpsc::SyncSender<PlayerStatus>>,
+    sampler: Option<mpsc::Sender<SamplerCommand>>,
+    loads: Arc<AtomicUsize>, // tracks loaded so far, a waveform analysed for an earlier one is not sent
 }
 
 
 const SAMPLE_SKIP : usize = 1;
 const AUTO_PLAY : bool = false;
 const PRINT : bool = true;
+const TIMECODE_DRIFT : f64 = 0.05; // seconds the deck may drift from the record before it jumps
+const LOOP_OVERSHOOT : f64 = 2304.0; // samples past the loop end that still wrap, further out the deck was jumped there
 
 macro_rules! printinfo {
     ($self:ident, $($args:expr),*) => {{
@@ -65,22 +185,58 @@ macro_rules! printinfo {
 
 impl Mp3Player {
 
-    pub fn new() -> Mp3Player {
+    pub fn new(deck : usize) -> Mp3Player {
         Mp3Player {
-            _frames : Vec::new(),
+            deck : deck,
+            _frames : Arc::new(Vec::new()),
+            _stems : Arc::new(Vec::new()),
+            stem_names : Vec::new(),
             _out_port : None,
             _client : None,
             txui : None,
+            sampler : None,
+            loads : Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn set_sampler(&mut self, sampler: mpsc::Sender<SamplerCommand>) {
+        self.sampler = Some(sampler);
+    }
+
+    fn send_sampler(&self, cmd: SamplerCommand) {
+        if let Some(ref sampler) = self.sampler {
+            sampler.send(cmd).is_ok();
         }
     }
 
     pub fn open(&mut self, filename : &str) -> bool {
         let path = Path::new(filename);
         printinfo!(self, "Playing: {}", filename);
+        if path.is_dir() {
+            return self.open_stems(path);
+        }
+        if filename.ends_with(".stem.mp4") {
+            printinfo!(self, "NI Stems files need an AAC decoder, export the stems as wav files into a directory instead: {}", filename);
+            return false;
+        }
+        if filename.ends_with(".wav") {
+            return match wav::decode(filename) {
+                Ok(frames) => {
+                    self._frames = Arc::new(frames);
+                    self._stems = Arc::new(Vec::new());
+                    self.stem_names.clear();
+                    true
+                },
+                Err(e) => {
+                    printinfo!(self, "Could not decode file: {}", e);
+                    false
+                },
+            };
+        }
         if let Ok(file) = File::open(&path) {
             if let Ok(decoder) = Decoder::decode(file) {
                 printinfo!(self, "Loading...");
-                self._frames = decoder
+                self._frames = Arc::new(decoder
                     .filter_map(|r| match r {
                         Ok(f) => {
                             if f.samples.len() != 2 || f.samples[0].len() != f.samples[1].len() {
@@ -92,7 +248,9 @@ impl Mp3Player {
                             }
                             Some(f)
                         },
-                        Err(_) => None}).collect();
+                        Err(_) => None}).collect());
+                self._stems = Arc::new(Vec::new());
+                self.stem_names.clear();
                 true
             }
             else {
@@ -106,9 +264,85 @@ impl Mp3Player {
         }
     }
 
-    fn feed<'a>(&'a mut self, sink_sample_rate: u32, rx : Receiver<PlayerCommand>, tx : SyncSender<PlayerStatus>, t : SyncSender<(Vec<Vec<MadFixed32>>, f64, f64)>, rr : Receiver<f64>) {
+    // Loads a directory of equally long wav files as the stems of one track
+    fn open_stems(&mut self, dir : &Path) -> bool {
+        let mut paths : Vec<_> = match fs::read_dir(dir) {
+            Ok(entries) => entries.filter_map(|e| e.ok()).map(|e| e.path())
+                .filter(|p| p.extension().map_or(false, |ext| ext == "wav")).collect(),
+            Err(_) => {
+                printinfo!(self, "Could not open stem directory: {:?}", dir);
+                return false;
+            }
+        };
+        paths.sort();
+        if paths.len() == 0 {
+            printinfo!(self, "No wav stems in: {:?}", dir);
+            return false;
+        }
+
+        let mut stems = Vec::new();
+        let mut names = Vec::new();
+        for p in paths.iter() {
+            match wav::decode(&p.to_string_lossy()) {
+                Ok(frames) => {
+                    if frames.len() == 0 {
+                        printinfo!(self, "Empty stem: {:?}", p);
+                        return false;
+                    }
+                    stems.push(frames);
+                    names.push(p.file_stem().unwrap().to_string_lossy().to_string());
+                },
+                Err(e) => {
+                    printinfo!(self, "Could not decode stem: {}", e);
+                    return false;
+                },
+            }
+        }
+        if stems.iter().any(|s| s[0].sample_rate != stems[0][0].sample_rate) {
+            printinfo!(self, "Stems have different sample rates: {:?}", dir);
+            return false;
+        }
+        let len = stems.iter().map(|s| s.len()).min().unwrap();
+        if stems.iter().any(|s| s.len() != len) {
+            printinfo!(self, "Warning stems differ in length, cutting to {} frames", len);
+        }
+        for s in stems.iter_mut() {
+            s.truncate(len);
+        }
+        printinfo!(self, "Loaded {} stems: {:?}", names.len(), names);
+
+        self._frames = Arc::new(stems.remove(0));
+        self._stems = Arc::new(stems);
+        self.stem_names = names;
+        true
+    }
+
+    // Frequency bands of the whole track, with every stem
+    // Analyses the waveform of the loaded audio on a thread of its own, the deck keeps playing meanwhile
+    fn waveform(&self, tx: &SyncSender<PlayerStatus>) {
+        let load = self.loads.fetch_add(1, Ordering::SeqCst) + 1;
+        let (loads, frames, stems, tx) = (self.loads.clone(), self._frames.clone(), self._stems.clone(), tx.clone());
+        thread::spawn(move || {
+            let sample_rate = frames.get(0).map_or(44100, |f| f.sample_rate);
+            let gains = vec![(1.0, false); stems.len() + 1];
+            let samples = (0 .. frames.len()).filter_map(|i| mix(&frames, &stems, i, &gains, 1.0))
+                .flat_map(|f| (0 .. f[0].len()).map(move |n| f.iter().map(|ch| ch[n].to_f32()).sum::<f32>() / f.len() as f32));
+            let waveform = Waveform::analyze(samples, sample_rate);
+            if loads.load(Ordering::SeqCst) == load {
+                tx.send(PlayerStatus::Waveform(waveform)).is_ok();
+            }
+        });
+    }
+
+    // Samples of frame i at the given volume, with the stems mixed down if the track has any
+    fn frame_samples(&self, i : usize, stem_gains : &[(f64, bool)], volume : f64) -> Option<Vec<Vec<MadFixed32>>> {
+        mix(&self._frames, &self._stems, i, stem_gains, volume)
+    }
+
+    fn feed<'a>(&'a mut self, sink_sample_rate: u32, rx : Receiver<PlayerCommand>, tx : SyncSender<PlayerStatus>, t : SyncSender<(Vec<Vec<MadFixed32>>, f64, f64)>, rr : Receiver<f64>, tp : SyncSender<PlatterEvent>) {
         let mut cur_time = Duration::new(0, 0);
         let mut sample_rate = 0;
+        let mut bpm = 0.0;
         let mut duration;
         let mut sample_time_nanos = 0.0;
 
@@ -121,6 +355,11 @@ impl Mp3Player {
         let mut playing = false;
         let mut cue_sample = 0.0;
         let mut hotcues : HashMap<usize, f64> = HashMap::new();
+        let mut stem_gains : Vec<(f64, bool)> = Vec::new();
+        let mut volume = 1.0;
+        let mut duck = 1.0;
+        let mut fade = 1.0;
+        let mut cur_track : Option<Track> = None;
         let mut cue_markers = Vec::new();
         let mut speed_factor_bend = 1.0;
         let mut speed_factor_fader = 1.0;
@@ -128,6 +367,10 @@ impl Mp3Player {
         let mut jumped = false;
         let mut loaded = false;
         let mut true_playing = false;
+        let mut jog_touched = false;
+        let mut platter_config = PlatterConfig::default();
+        let mut brake_until : Option<Instant> = None;
+        let mut looping : Option<(f64, f64)> = None;
 
         macro_rules! cur_sample {
             () => { (i*1152) as f64 + j };
@@ -149,17 +392,60 @@ impl Mp3Player {
             }};
         }
 
+        macro_rules! loaded {
+            ($track:expr) => {{
+                loaded = true;
+                sample_rate = self._frames[0].sample_rate;
+                speed_factor_resample = sample_rate as f64 / sink_sample_rate as f64;
+                printinfo!(self, "Resampling: {} -> {} ({}x)", sample_rate, sink_sample_rate, speed_factor_resample);
+                duration = self._frames.iter().map(|f| f.duration).fold(Duration::new(0, 0), |acc, dtn| acc + dtn);
+                printinfo!(self, "Start at: {:?}", self._frames[0].position);
+                sample_time_nanos = 1000000.0/(sample_rate as f64/1000.0);
+                bpm = $track.bpm();
+                cue_markers = $track.cues.iter().map(|cue| cue.start as f64).collect();
+            }};
+        }
+
+        macro_rules! stems_status {
+            () => {{
+                let status = self.stem_names.iter().zip(stem_gains.iter())
+                    .map(|(name, &(volume, mute))| (name.clone(), volume, mute)).collect();
+                tx.try_send(PlayerStatus::Stems(status)).is_ok();
+            }};
+        }
+
+        macro_rules! cues_status {
+            () => {{
+                let mut set : Vec<(usize, f64)> = hotcues.iter().map(|(idx, pos)| (*idx, *pos)).collect();
+                set.sort_by_key(|&(idx, _)| idx);
+                tx.try_send(PlayerStatus::HotCues(set)).is_ok();
+                tx.try_send(PlayerStatus::Cue(cue_sample)).is_ok();
+            }};
+        }
+
+        macro_rules! loop_status {
+            () => {{
+                tx.try_send(PlayerStatus::Loop(looping)).is_ok();
+            }};
+        }
+
         macro_rules! set_play {
             ($play:expr, $is_true_play:expr) => {{
                 let play_ = $play;
+                let was_true_playing = true_playing;
                 //printinfo!(self, "{} {} | {} {} {}", play_, $is_true_play, playing, true_playing, loaded);
                 if !(play_ && !loaded) {
+                    if play_ {
+                        brake_until = None;
+                        tp.try_send(PlatterEvent::Motor(true)).is_ok();
+                    }
                     if play_ && !playing {
                         t.send((empty_samples.clone(), 0.0, 1.0)).unwrap();
                     }
                     if !play_ && playing {
                         rr.recv().unwrap();
                         t.send((Vec::new(), 0.0, 1.0)).unwrap();
+                        tx.try_send(PlayerStatus::Level(0.0)).is_ok();
                     }
                     if !play_ && true_playing {
                         true_playing = false;
@@ -170,6 +456,9 @@ impl Mp3Player {
 
                     playing = play_;
                 }
+                if was_true_playing != true_playing {
+                    tx.try_send(PlayerStatus::Playing(true_playing)).is_ok();
+                }
                 //printinfo!(self, "=> {} {} {}", playing, true_playing, loaded);
             }};
             ($play:expr) => {
@@ -178,6 +467,13 @@ impl Mp3Player {
         }
         
         loop {
+            if let Some(until) = brake_until {
+                if Instant::now() >= until {
+                    brake_until = None;
+                    set_play!(false);
+                }
+            }
+
             // MP3 feeder
             if let Ok(j_) = rr.try_recv() {
                 if !jumped {
@@ -198,14 +494,22 @@ impl Mp3Player {
                         tx.try_send(PlayerStatus::Pos(cur_time, cur_sample!())).is_ok();
                     }
                 }
+                if let Some((start, end)) = looping {
+                    let over = cur_sample!() - end;
+                    if playing && over >= 0.0 && over < LOOP_OVERSHOOT {
+                        jump!(start + over);
+                    }
+                }
                 if playing {
-                    if let Some(f) = self._frames.get(i) {
-                        assert_eq!(f.samples.len(), 2);
-                        assert!(f.samples[0].len() > j as usize);
-                        assert_eq!(f.samples[0].len(), f.samples[1].len());
+                    if let Some(samples) = self.frame_samples(i, &stem_gains, volume * duck * fade) {
+                        assert_eq!(samples.len(), 2);
+                        assert!(samples[0].len() > j as usize);
+                        assert_eq!(samples[0].len(), samples[1].len());
                         //printinfo!(self, "Pos {:?} ({} {})", f.position, i, j);
                         let speed_factor = speed_factor_resample * speed_factor_fader * speed_factor_bend;
-                        t.send((f.samples.clone(), j, speed_factor)).unwrap(); 
+                        let peak = samples.iter().flat_map(|ch| ch.iter()).fold(0.0f32, |peak, s| peak.max(s.to_f32().abs()));
+                        t.send((samples, j, speed_factor)).unwrap(); 
+                        tx.try_send(PlayerStatus::Level(peak as f64)).is_ok();
                         tx.try_send(PlayerStatus::Speed(speed_factor_fader*speed_factor_bend)).is_ok();
                         jumped = false;
                     }
@@ -213,7 +517,12 @@ impl Mp3Player {
                         printinfo!(self, "Reached end");
                         playing = false;
                         loaded = false;
+                        if true_playing {
+                            true_playing = false;
+                            tx.try_send(PlayerStatus::Playing(false)).is_ok();
+                        }
                         t.send((Vec::new(), 0.0, 1.0)).unwrap();
+                        tx.try_send(PlayerStatus::Level(0.0)).is_ok();
                     }
                 }
             }
@@ -224,11 +533,43 @@ impl Mp3Player {
                     PlayerCommand::GetPos => {tx.send(PlayerStatus::Pos(cur_time, cur_sample!())).unwrap(); ()},
                     PlayerCommand::Seek(new_pos) => jump!(new_pos),
                     PlayerCommand::SeekS(new_pos_) => jump!((new_pos_.as_secs() as u32* sample_rate) as f64),
-                    PlayerCommand::PlayPause => set_play!(!true_playing, true),
+                    PlayerCommand::PlayPause => {
+                        if true_playing && brake_until.is_none() {
+                            // keep feeding while the platter spins down
+                            true_playing = false;
+                            tx.try_send(PlayerStatus::Playing(false)).is_ok();
+                            tp.try_send(PlatterEvent::Motor(false)).is_ok();
+                            brake_until = Some(Instant::now() + Duration::from_millis((platter_config.spin_down * 1000.0) as u64));
+                        }
+                        else {
+                            if !playing {
+                                tp.try_send(PlatterEvent::Rest).is_ok();
+                            }
+                            set_play!(true, true);
+                        }
+                    },
+                    PlayerCommand::JogTouch(on) => {
+                        jog_touched = on;
+                        tp.try_send(PlatterEvent::Touch(on)).is_ok();
+                        if on && !playing {
+                            set_play!(true);
+                        }
+                        if !on && !true_playing && brake_until.is_none() {
+                            set_play!(false);
+                        }
+                    },
+                    PlayerCommand::Jog(revs) => {
+                        tp.try_send(if jog_touched { PlatterEvent::Scratch(revs) } else { PlatterEvent::Nudge(revs) }).is_ok();
+                    },
+                    PlayerCommand::Platter(config) => {
+                        platter_config = config;
+                        tp.try_send(PlatterEvent::Config(config)).is_ok();
+                    },
                     PlayerCommand::Cue(on) => {
                         if on {
                             if cue_sample != cur_sample!() {
                                 cue_sample = cur_sample!();
+                                cues_status!();
                                 set_play!(false);
                             }
                             else {
@@ -269,10 +610,45 @@ impl Mp3Player {
                         }
                         else if on {
                             hotcues.insert(idx, cur_sample!());
+                            cues_status!();
+                        }
+                    },
+                    PlayerCommand::DeleteHotCue(idx) => {
+                        if hotcues.remove(&idx).is_some() {
+                            cues_status!();
+                        }
+                    },
+                    PlayerCommand::BeatJump(beats) => {
+                        if loaded && bpm > 0.0 {
+                            jump!(cur_sample!() + beats * 60.0 / bpm * sample_rate as f64);
+                        }
+                    },
+                    PlayerCommand::Loop(beats) => {
+                        if beats <= 0.0 {
+                            looping = None;
+                        }
+                        else if loaded && bpm > 0.0 {
+                            // starts on the grid, on the beat or the fraction of it for short loops
+                            let step = 60.0 / bpm * sample_rate as f64 * beats.min(1.0);
+                            let first = cur_track.as_ref().map_or(0.0, |track| track.first_beat as f64);
+                            let start = (first + ((cur_sample!() - first) / step).floor() * step).max(0.0);
+                            looping = Some((start, start + beats * 60.0 / bpm * sample_rate as f64));
+                        }
+                        loop_status!();
+                    },
+                    PlayerCommand::LoopScale(factor) => {
+                        if let (Some((start, end)), true) = (looping, factor > 0.0) {
+                            let end = start + (end - start) * factor;
+                            looping = Some((start, end));
+                            if cur_sample!() >= end {
+                                jump!(start + (cur_sample!() - start) % (end - start));
+                            }
+                            loop_status!();
                         }
                     },
                     PlayerCommand::Speed(speed_factor) => {
                         speed_factor_fader = speed_factor;
+                        tx.try_send(PlayerStatus::Tempo(speed_factor_fader)).is_ok();
                         tx.try_send(PlayerStatus::Speed(speed_factor_fader*speed_factor_bend)).is_ok();
                     },
                     PlayerCommand::Scratch(velocity) => { // -1 -> 1
@@ -306,29 +682,154 @@ impl Mp3Player {
                             tx.try_send(PlayerStatus::Speed(speed_factor_fader*speed_factor_bend)).is_ok();
                         }
                     }
+                    PlayerCommand::Timecode(speed, position) => {
+                        speed_factor_bend = speed;
+                        if let Some(secs) = position {
+                            let target = secs * sample_rate as f64;
+                            if (target - cur_sample!()).abs() > TIMECODE_DRIFT * sample_rate as f64 {
+                                jump!(target);
+                            }
+                        }
+                        set_play!(speed != 0.0, false);
+                        if !playing {
+                            tx.try_send(PlayerStatus::Speed(speed_factor_fader*speed_factor_bend)).is_ok();
+                        }
+                    }
                     PlayerCommand::Open(track) => {
                         let was_playing = playing;
                         let was_true_playing = true_playing;
                         set_play!(false);
                         if self.open(&*track.path) {
-                            loaded = true;
-                            sample_rate = self._frames[0].sample_rate;
-                            speed_factor_resample = sample_rate as f64 / sink_sample_rate as f64;
-                            printinfo!(self, "Resampling: {} -> {} ({}x)", sample_rate, sink_sample_rate, speed_factor_resample);
-                            duration = self._frames.iter().map(|f| f.duration).fold(Duration::new(0, 0), |acc, dtn| acc + dtn);
-                            printinfo!(self, "Start at: {:?}", self._frames[0].position);
-                            sample_time_nanos = 1000000.0/(sample_rate as f64/1000.0);
-                            cue_markers = track.cues.iter().map(|cue| cue.start as f64).collect();
-                            hotcues = track.cues.iter().enumerate().map(|(idx, cue)| (idx, (cue.start as f64))).collect();
+                            loaded!(track);
+                            looping = None;
+                            loop_status!();
+                            hotcues = track.cues.iter().filter_map(|cue| cue.hotcue.map(|idx| (idx, cue.start as f64))).collect();
+                            cue_sample = hotcues.get(&0).cloned().unwrap_or(track.first_beat as f64);
+                            stem_gains = if self._stems.len() > 0 { vec![(1.0, false); self._stems.len() + 1] } else { Vec::new() };
+                            stems_status!();
                             
                             set_play!(was_playing || AUTO_PLAY, was_true_playing || AUTO_PLAY);
                             jump!(track.first_beat as f64);
                             if let Some(cue_pos) = hotcues.get(&0) {
                                 jump!(*cue_pos); 
                             }
+                            cur_track = Some(track.clone());
                             tx.send(PlayerStatus::TrackInfo(Some(track), duration, sample_rate)).unwrap();
+                            self.waveform(&tx);
+                            // after the track info, so the hot cues are not taken for the previous track's
+                            cues_status!();
                         }
                     },
+                    PlayerCommand::Cues(cues) => {
+                        if let Some(ref mut track) = cur_track {
+                            hotcues = cues.iter().filter_map(|cue| cue.hotcue.map(|idx| (idx, cue.start as f64))).collect();
+                            cue_markers = cues.iter().map(|cue| cue.start as f64).collect();
+                            track.cues = cues;
+                            cues_status!();
+                        }
+                    },
+                    PlayerCommand::CloneTo(target) => {
+                        if let (true, Some(track)) = (loaded, cur_track.clone()) {
+                            target.send(PlayerCommand::Restore(Box::new(DeckSnapshot {
+                                track: track,
+                                frames: self._frames.clone(),
+                                stems: self._stems.clone(),
+                                stem_names: self.stem_names.clone(),
+                                stem_gains: stem_gains.clone(),
+                                sample_pos: cur_sample!(),
+                                speed: speed_factor_fader,
+                                playing: true_playing,
+                                cue: cue_sample,
+                                hotcues: hotcues.clone(),
+                                looping: looping,
+                                taken: Instant::now(),
+                            }))).is_ok();
+                        }
+                    },
+                    PlayerCommand::Restore(snapshot) => {
+                        let snapshot = *snapshot;
+                        set_play!(false);
+                        self._frames = snapshot.frames;
+                        self._stems = snapshot.stems;
+                        self.stem_names = snapshot.stem_names;
+                        loaded!(snapshot.track);
+                        looping = snapshot.looping;
+                        loop_status!();
+                        hotcues = snapshot.hotcues;
+                        cue_sample = snapshot.cue;
+                        stem_gains = snapshot.stem_gains;
+                        stems_status!();
+                        speed_factor_fader = snapshot.speed;
+                        tx.try_send(PlayerStatus::Tempo(speed_factor_fader)).is_ok();
+                        tx.try_send(PlayerStatus::Speed(speed_factor_fader*speed_factor_bend)).is_ok();
+
+                        // catch up with the source deck for the time the snapshot was on its way
+                        let mut pos = snapshot.sample_pos;
+                        if snapshot.playing {
+                            let elapsed = snapshot.taken.elapsed();
+                            let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+                            pos += secs * sample_rate as f64 * speed_factor_fader;
+                            // the source deck went round its loop in the meantime
+                            if let Some((start, end)) = looping {
+                                if snapshot.sample_pos < end && pos >= end && end > start {
+                                    pos = start + (pos - start) % (end - start);
+                                }
+                            }
+                        }
+                        set_play!(snapshot.playing, snapshot.playing);
+                        jump!(pos);
+                        printinfo!(self, "Doubled {} at {}", snapshot.track.path, pos);
+                        cur_track = Some(snapshot.track.clone());
+                        tx.send(PlayerStatus::TrackInfo(Some(snapshot.track), duration, sample_rate)).unwrap();
+                        self.waveform(&tx);
+                        cues_status!();
+                    },
+                    PlayerCommand::GrabSample(slot, beats) => {
+                        if loaded && bpm > 0.0 {
+                            // the active loop, or beats from the playhead without one
+                            let (start, len) = match looping {
+                                Some((start, end)) => (start as usize, (end - start) as usize),
+                                None => (cur_sample!() as usize, (beats * 60.0 / bpm * sample_rate as f64) as usize),
+                            };
+                            let mut samples = vec![Vec::with_capacity(len), Vec::with_capacity(len)];
+                            let mut frame = None;
+                            for n in start .. start + len {
+                                if frame.as_ref().map_or(true, |&(idx, _)| idx != n / 1152) {
+                                    frame = self.frame_samples(n / 1152, &stem_gains, 1.0).map(|f| (n / 1152, f));
+                                }
+                                if let Some((_, ref f)) = frame {
+                                    if let (Some(l), Some(r)) = (f[0].get(n % 1152), f[1].get(n % 1152)) {
+                                        samples[0].push(l.to_f32());
+                                        samples[1].push(r.to_f32());
+                                    }
+                                }
+                            }
+                            match looping {
+                                Some(_) => printinfo!(self, "Grab the loop to sampler slot {}", slot),
+                                None => printinfo!(self, "Grab {} beats to sampler slot {}", beats, slot),
+                            }
+                            self.send_sampler(SamplerCommand::Fill(slot, samples, sample_rate, bpm));
+                        }
+                    },
+                    PlayerCommand::Play(on) => set_play!(on, on),
+                    PlayerCommand::Volume(volume_) => {
+                        volume = volume_;
+                        tx.try_send(PlayerStatus::Volume(volume)).is_ok();
+                    },
+                    PlayerCommand::Duck(duck_) => duck = duck_,
+                    PlayerCommand::Fade(fade_) => fade = fade_,
+                    PlayerCommand::StemVolume(stem, volume) => {
+                        if let Some(gain) = stem_gains.get_mut(stem) {
+                            gain.0 = volume;
+                        }
+                        stems_status!();
+                    },
+                    PlayerCommand::StemMute(stem, mute) => {
+                        if let Some(gain) = stem_gains.get_mut(stem) {
+                            gain.1 = mute;
+                        }
+                        stems_status!();
+                    },
                     _ => (),
                 };
             }
@@ -338,15 +839,17 @@ impl Mp3Player {
     pub fn play<'a>(&'a mut self, rx : Receiver<PlayerCommand>, tx : SyncSender<PlayerStatus>) {
         let (t, r) = mpsc::sync_channel::<(Vec<Vec<MadFixed32>>, f64, f64)>(0);
         let (tr, rr) = mpsc::sync_channel::<f64>(0);
+        let (tp, rp) = mpsc::sync_channel::<PlatterEvent>(64);
 
         self.txui = Some(tx.clone());
         
-        let client = Client::new("rustydj", client_options::NO_START_SERVER).unwrap().0;
+        let client = Client::new(&format!("rustydj_deck{}", self.deck + 1), client_options::NO_START_SERVER).unwrap().0;
         let mut l_chan = client.register_port("out_l", AudioOutSpec::default()).unwrap();
         let mut r_chan = client.register_port("out_r", AudioOutSpec::default()).unwrap();
         let port_names = [l_chan.name().to_string(), r_chan.name().to_string()];
 
         let playing = Cell::new(false);
+        let mut platter = Platter::new(client.sample_rate() as u32);
 
         let process = ClosureProcessHandler::new(move |_client: &Client, ps: &ProcessScope| -> JackControl {
             let mut l_buffer = AudioOutPort::new(&mut l_chan, ps);
@@ -356,6 +859,9 @@ impl Mp3Player {
             let r_iter = r_buffer.iter_mut();
             let mut iter = l_iter.zip(r_iter);
 
+            while let Ok(event) = rp.try_recv() {
+                platter.handle(event);
+            }
 
             let mut samples = Vec::new(); 
             let mut s = 0.0;
@@ -394,7 +900,7 @@ impl Mp3Player {
                 //printinfo!(self, "Playing len: ({},{}), s: {}", samples[0].len(), samples[1].len(), s);
                 *l_elem = samples[0][s as usize].to_f32();
                 *r_elem = samples[1][s as usize].to_f32();
-                s += speed_factor;
+                s += speed_factor * platter.step();
                 if s < 0.0 || s as usize >= samples[0].len() {
                     tr.send(s).unwrap();
                     let (samples_, s_, speed_factor_) = r.recv().unwrap();
@@ -414,7 +920,7 @@ impl Mp3Player {
             active_client.connect_ports_by_name(src, sink).unwrap();
         }
 
-        self.feed(active_client.sample_rate() as u32, rx, tx, t, rr);
+        self.feed(active_client.sample_rate() as u32, rx, tx, t, rr, tp);
         active_client.deactivate().unwrap();
     }
 }
@@ -0,0 +1,357 @@
+extern crate serde_json;
+
+use std::fs::File;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use mp3playerjack::{PlayerCommand, PlayerStatus};
+use ui::UICommand;
+use sampler::SamplerCommand;
+use autodj::AutoDJCommand;
+use decks::{DeckState, DECKS};
+
+const CONFIG : &'static str = "osc.json";
+const POSITION_INTERVAL : u64 = 50; // ms between position updates of a deck
+const BPM_CHANGE : f64 = 0.01; // smallest bpm change worth sending
+
+fn default_port() -> u16 { 9000 }
+
+// osc.json, OSC is off without it
+#[derive(Debug, Deserialize)]
+pub struct OscConfig {
+    #[serde(default = "default_port")]
+    pub port: u16, // UDP port commands are received on, 0 for none
+    #[serde(default)]
+    pub destinations: Vec<String>, // host:port the player status is sent to
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Arg {
+    Int(i32),
+    Float(f32),
+    Str(String),
+    Bool(bool),
+}
+
+impl Arg {
+    fn value(&self) -> Option<f64> {
+        match *self {
+            Arg::Int(i) => Some(i as f64),
+            Arg::Float(f) => Some(f as f64),
+            Arg::Str(ref s) => s.parse().ok(),
+            Arg::Bool(b) => Some(if b { 1.0 } else { 0.0 }),
+        }
+    }
+}
+
+fn put_u32(out: &mut Vec<u8>, v: u32) {
+    out.extend_from_slice(&[(v >> 24) as u8, (v >> 16) as u8, (v >> 8) as u8, v as u8]);
+}
+
+// Strings are null terminated and padded to four bytes
+fn put_str(out: &mut Vec<u8>, s: &str) {
+    out.extend_from_slice(s.as_bytes());
+    out.push(0);
+    while out.len() % 4 != 0 {
+        out.push(0);
+    }
+}
+
+fn get_u32(data: &[u8], pos: usize) -> Option<u32> {
+    let b = data.get(pos .. pos + 4)?;
+    Some((b[0] as u32) << 24 | (b[1] as u32) << 16 | (b[2] as u32) << 8 | b[3] as u32)
+}
+
+fn get_u64(data: &[u8], pos: usize) -> Option<u64> {
+    Some((get_u32(data, pos)? as u64) << 32 | get_u32(data, pos + 4)? as u64)
+}
+
+fn get_str(data: &[u8], pos: &mut usize) -> Option<String> {
+    let len = data.get(*pos ..)?.iter().position(|&b| b == 0)?;
+    let s = String::from_utf8(data[*pos .. *pos + len].to_vec()).ok()?;
+    *pos = (*pos + len + 4) & !3;
+    Some(s)
+}
+
+pub fn encode(address: &str, args: &[Arg]) -> Vec<u8> {
+    let mut out = Vec::new();
+    put_str(&mut out, address);
+    let mut tags = String::from(",");
+    for arg in args.iter() {
+        tags.push(match *arg {
+            Arg::Int(_) => 'i',
+            Arg::Float(_) => 'f',
+            Arg::Str(_) => 's',
+            Arg::Bool(true) => 'T',
+            Arg::Bool(false) => 'F',
+        });
+    }
+    put_str(&mut out, &tags);
+    for arg in args.iter() {
+        match *arg {
+            Arg::Int(i) => put_u32(&mut out, i as u32),
+            Arg::Float(f) => put_u32(&mut out, f.to_bits()),
+            Arg::Str(ref s) => put_str(&mut out, s),
+            Arg::Bool(_) => (),
+        }
+    }
+    out
+}
+
+// Messages of a packet, bundles unpacked and their time tags ignored
+pub fn decode(data: &[u8]) -> Vec<(String, Vec<Arg>)> {
+    let mut messages = Vec::new();
+    decode_into(data, &mut messages);
+    messages
+}
+
+fn decode_into(data: &[u8], messages: &mut Vec<(String, Vec<Arg>)>) -> Option<()> {
+    if data.starts_with(b"#bundle\0") {
+        let mut pos = 16;
+        while pos < data.len() {
+            let size = get_u32(data, pos)? as usize;
+            decode_into(data.get(pos + 4 .. pos + 4 + size)?, messages);
+            pos += 4 + size;
+        }
+        return Some(());
+    }
+    let mut pos = 0;
+    let address = get_str(data, &mut pos)?;
+    // very old senders leave out the type tags
+    let tags = if pos < data.len() { get_str(data, &mut pos)? } else { String::from(",") };
+    let mut args = Vec::new();
+    for tag in tags.chars().skip(1) {
+        args.push(match tag {
+            'i' => { pos += 4; Arg::Int(get_u32(data, pos - 4)? as i32) },
+            'f' => { pos += 4; Arg::Float(f32::from_bits(get_u32(data, pos - 4)?)) },
+            'h' => { pos += 8; Arg::Int(get_u64(data, pos - 8)? as i64 as i32) },
+            'd' => { pos += 8; Arg::Float(f64::from_bits(get_u64(data, pos - 8)?) as f32) },
+            's' | 'S' => Arg::Str(get_str(data, &mut pos)?),
+            'T' => Arg::Bool(true),
+            'F' => Arg::Bool(false),
+            'N' | 'I' => continue,
+            _ => return None,
+        });
+    }
+    messages.push((address, args));
+    Some(())
+}
+
+// Command for an address, decks and slots count from 1. Buttons send 1 when pressed and 0 when released,
+// a message without arguments counts as a press
+pub fn command(address: &str, args: &[Arg]) -> Option<PlayerCommand> {
+    let parts : Vec<&str> = address.trim_matches('/').split('/').collect();
+    let value = args.get(0).and_then(Arg::value);
+    let on = value.map_or(true, |v| v != 0.0);
+    let index = |s: &str| s.parse::<usize>().ok().and_then(|n| n.checked_sub(1));
+    let deck = |s: &str, cmd: PlayerCommand| index(s).map(|deck| PlayerCommand::Deck(deck, Box::new(cmd)));
+    match parts[..] {
+        ["deck", n, "play"] => deck(n, if value.is_some() { PlayerCommand::Play(on) } else { PlayerCommand::PlayPause }),
+        ["deck", n, "playpause"] if on => deck(n, PlayerCommand::PlayPause),
+        ["deck", n, "cue"] => deck(n, PlayerCommand::Cue(on)),
+        ["deck", n, "hotcue", m] => deck(n, PlayerCommand::HotCue(index(m)?, on)),
+        ["deck", n, "hotcue", m, "delete"] if on => deck(n, PlayerCommand::DeleteHotCue(index(m)?)),
+        ["deck", n, "tempo"] => deck(n, PlayerCommand::Speed(value?)),
+        ["deck", n, "volume"] => deck(n, PlayerCommand::Volume(value?)),
+        ["deck", n, "jog"] => deck(n, PlayerCommand::Jog(value?)),
+        ["deck", n, "beatjump"] if on => deck(n, PlayerCommand::BeatJump(value?)),
+        ["deck", n, "select"] if on => Some(PlayerCommand::SelectDeck(index(n)?)),
+        ["deck", n, "master"] if on => Some(PlayerCommand::SetMaster(index(n)?)),
+        ["mixer", "crossfader"] => Some(PlayerCommand::Crossfader(value?)),
+        ["sampler", m] => Some(PlayerCommand::Sampler(SamplerCommand::Trigger(index(m)?, on))),
+        ["autodj", "pause"] if on => Some(PlayerCommand::AutoDJ(AutoDJCommand::Pause)),
+        ["autodj", "skip"] if on => Some(PlayerCommand::AutoDJ(AutoDJCommand::Skip)),
+        _ => None,
+    }
+}
+
+// Sends the player status to the configured destinations
+pub struct Broadcaster {
+    socket: UdpSocket,
+    destinations: Vec<SocketAddr>,
+    decks: Vec<DeckState>,
+    master: usize,
+    sent: Vec<Option<Instant>>, // last position update of each deck
+    bpm: Vec<f64>, // last bpm sent of each deck
+    beat: Option<i64>, // last beat of the master deck sent
+}
+
+impl Broadcaster {
+    fn send(&self, address: &str, args: &[Arg]) {
+        let packet = encode(address, args);
+        for destination in self.destinations.iter() {
+            self.socket.send_to(&packet, destination).is_ok();
+        }
+    }
+
+    pub fn update(&mut self, status: &PlayerStatus) {
+        match *status {
+            PlayerStatus::Deck(deck, ref status) if deck < self.decks.len() => {
+                self.decks[deck].update(status);
+                match **status {
+                    PlayerStatus::TrackInfo(ref track, _, _) => {
+                        let (title, artist) = track.as_ref().map_or((String::new(), String::new()), |track| (track.title(), track.artist()));
+                        self.send(&format!("/deck/{}/title", deck + 1), &[Arg::Str(title)]);
+                        self.send(&format!("/deck/{}/artist", deck + 1), &[Arg::Str(artist)]);
+                        self.send_bpm(deck);
+                    },
+                    PlayerStatus::Speed(_) => self.send_bpm(deck),
+                    PlayerStatus::Playing(playing) => {
+                        self.send(&format!("/deck/{}/playing", deck + 1), &[Arg::Float(if playing { 1.0 } else { 0.0 })]);
+                    },
+                    PlayerStatus::Pos(..) => self.send_position(deck),
+                    _ => (),
+                }
+            },
+            PlayerStatus::Master(deck) => {
+                self.master = deck;
+                self.send("/master", &[Arg::Int(deck as i32 + 1)]);
+            },
+            _ => (),
+        }
+    }
+
+    fn send_bpm(&mut self, deck: usize) {
+        let bpm = self.decks[deck].bpm();
+        if (bpm - self.bpm[deck]).abs() >= BPM_CHANGE {
+            self.bpm[deck] = bpm;
+            self.send(&format!("/deck/{}/bpm", deck + 1), &[Arg::Float(bpm as f32)]);
+        }
+    }
+
+    // Position and beat, at most every POSITION_INTERVAL, and every beat the master deck plays
+    fn send_position(&mut self, deck: usize) {
+        let beat = self.decks[deck].beat();
+        if deck == self.master && self.decks[deck].playing {
+            let whole = beat.map(|beat| beat.floor() as i64);
+            if whole.is_some() && whole != self.beat {
+                self.beat = whole;
+                self.send("/beat", &[Arg::Int(whole.unwrap() as i32)]);
+            }
+        }
+        if self.sent[deck].map_or(false, |sent| sent.elapsed() < Duration::from_millis(POSITION_INTERVAL)) {
+            return;
+        }
+        self.sent[deck] = Some(Instant::now());
+        let state = &self.decks[deck];
+        if state.sample_rate > 0 {
+            self.send(&format!("/deck/{}/position", deck + 1), &[Arg::Float((state.sample_pos / state.sample_rate as f64) as f32)]);
+        }
+        if let Some(beat) = beat {
+            self.send(&format!("/deck/{}/beat", deck + 1), &[Arg::Float(beat as f32)]);
+        }
+    }
+}
+
+// Messages go to the UI, it holds the terminal by the time packets come in
+fn serve(socket: UdpSocket, tx: mpsc::Sender<PlayerCommand>, txui: mpsc::Sender<UICommand>) {
+    let mut buf = [0; 4096];
+    loop {
+        let (len, from) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e) => {
+                txui.send(UICommand::Print(format!("OSC: {}", e))).is_ok();
+                return;
+            },
+        };
+        for (address, args) in decode(&buf[.. len]) {
+            match command(&address, &args) {
+                Some(cmd) => if tx.send(cmd).is_err() { return },
+                None => { txui.send(UICommand::Print(format!("OSC: unknown {} {:?} from {}", address, args, from))).is_ok(); },
+            }
+        }
+    }
+}
+
+// Starts the OSC server when osc.json exists, returns what sends the status to its destinations
+pub fn run(tx: mpsc::Sender<PlayerCommand>, txui: mpsc::Sender<UICommand>) -> Option<Broadcaster> {
+    let config : OscConfig = match File::open(CONFIG) {
+        Ok(file) => match serde_json::from_reader(file) {
+            Ok(config) => config,
+            Err(e) => {
+                println!("OSC: {}: {}", CONFIG, e);
+                return None;
+            },
+        },
+        Err(_) => return None,
+    };
+    if config.port > 0 {
+        match UdpSocket::bind(("0.0.0.0", config.port)) {
+            Ok(socket) => {
+                println!("OSC: listening on {}", config.port);
+                thread::spawn(move || serve(socket, tx, txui));
+            },
+            Err(e) => println!("OSC: port {}: {}", config.port, e),
+        }
+    }
+    let destinations : Vec<SocketAddr> = config.destinations.iter()
+        .filter_map(|d| match d.to_socket_addrs() {
+            Ok(mut addrs) => addrs.next(),
+            Err(e) => {
+                println!("OSC: {}: {}", d, e);
+                None
+            },
+        }).collect();
+    let socket = match UdpSocket::bind("0.0.0.0:0") {
+        Ok(socket) => socket,
+        Err(e) => {
+            println!("OSC: {}", e);
+            return None;
+        },
+    };
+    socket.set_broadcast(true).is_ok();
+    Some(Broadcaster {
+        socket: socket,
+        destinations: destinations,
+        decks: (0 .. DECKS).map(|_| DeckState::new()).collect(),
+        master: 0,
+        sent: vec![None; DECKS],
+        bpm: vec![0.0; DECKS],
+        beat: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let args = vec![Arg::Int(-3), Arg::Float(0.5), Arg::Str(String::from("abcd")), Arg::Bool(true), Arg::Str(String::new())];
+        let packet = encode("/deck/1/title", &args);
+        assert_eq!(packet.len() % 4, 0);
+        assert_eq!(&packet[.. 16], b"/deck/1/title\0\0\0");
+        assert_eq!(decode(&packet), vec![(String::from("/deck/1/title"), args)]);
+    }
+
+    #[test]
+    fn bundle() {
+        let mut packet = b"#bundle\0\0\0\0\0\0\0\0\x01".to_vec();
+        for message in [encode("/deck/2/cue", &[Arg::Float(1.0)]), encode("/mixer/crossfader", &[Arg::Float(0.25)])].iter() {
+            put_u32(&mut packet, message.len() as u32);
+            packet.extend_from_slice(message);
+        }
+        let cmds : Vec<String> = decode(&packet).iter()
+            .map(|&(ref address, ref args)| format!("{:?}", command(address, args))).collect();
+        assert_eq!(cmds, vec!["Some(Deck(1, Cue(true)))", "Some(Crossfader(0.25))"]);
+    }
+
+    #[test]
+    fn addresses() {
+        let cmd = |address: &str, args: &[Arg]| format!("{:?}", command(address, args));
+        assert_eq!(cmd("/deck/1/play", &[]), "Some(Deck(0, PlayPause))");
+        assert_eq!(cmd("/deck/1/play", &[Arg::Float(0.0)]), "Some(Deck(0, Play(false)))");
+        assert_eq!(cmd("/deck/2/playpause", &[Arg::Float(0.0)]), "None");
+        assert_eq!(cmd("/deck/1/hotcue/3", &[Arg::Float(1.0)]), "Some(Deck(0, HotCue(2, true)))");
+        assert_eq!(cmd("/deck/4/hotcue/8/delete", &[Arg::Int(1)]), "Some(Deck(3, DeleteHotCue(7)))");
+        assert_eq!(cmd("/deck/1/tempo", &[Arg::Str(String::from("1.5"))]), "Some(Deck(0, Speed(1.5)))");
+        assert_eq!(cmd("/deck/1/beatjump", &[Arg::Int(-4)]), "Some(Deck(0, BeatJump(-4.0)))");
+        assert_eq!(cmd("/deck/2/select", &[]), "Some(SelectDeck(1))");
+        assert_eq!(cmd("/sampler/1", &[Arg::Bool(false)]), "Some(Sampler(Trigger(0, false)))");
+        assert_eq!(cmd("/deck/0/play", &[]), "None");
+        assert_eq!(cmd("/deck/1/tempo", &[]), "None");
+        assert_eq!(cmd("/mixer/eq", &[Arg::Float(1.0)]), "None");
+    }
+}
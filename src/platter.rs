@@ -0,0 +1,147 @@
+const HAND_FOLLOW : f64 = 0.005; // seconds the record lags behind the hand
+
+#[derive(Debug, Deserialize, Clone, Copy)]
+pub struct PlatterConfig {
+    pub rpm: f64,
+    pub spin_up: f64, // seconds from rest to full speed
+    pub spin_down: f64, // seconds from full speed to rest
+    pub inertia: f64, // seconds a nudge takes to die down
+    pub nudge: f64, // speed change per revolution pushed at the rim
+}
+
+impl Default for PlatterConfig {
+    fn default() -> PlatterConfig {
+        PlatterConfig {
+            rpm: 100.0 / 3.0,
+            spin_up: 0.2,
+            spin_down: 0.5,
+            inertia: 0.3,
+            nudge: 1.0,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum PlatterEvent {
+    Touch(bool),
+    Scratch(f64), //revolutions moved by hand
+    Nudge(f64), //revolutions pushed at the rim
+    Motor(bool),
+    Rest,
+    Config(PlatterConfig),
+}
+
+// Motion of a turntable platter, stepped once per output sample by the audio thread
+pub struct Platter {
+    config: PlatterConfig,
+    sample_rate: f64,
+    touched: bool,
+    motor: bool,
+    hand: f64, // seconds of audio the hand moved the record that the platter has not followed yet
+    nudge: f64,
+    velocity: f64, // 1.0 is the motor speed
+}
+
+impl Platter {
+    pub fn new(sample_rate: u32) -> Platter {
+        Platter {
+            config: PlatterConfig::default(),
+            sample_rate: sample_rate as f64,
+            touched: false,
+            motor: true,
+            hand: 0.0,
+            nudge: 0.0,
+            velocity: 1.0,
+        }
+    }
+
+    pub fn handle(&mut self, event: PlatterEvent) {
+        let secs_per_rev = 60.0 / self.config.rpm;
+        match event {
+            PlatterEvent::Touch(touched) => {
+                self.touched = touched;
+                self.hand = 0.0;
+            },
+            PlatterEvent::Scratch(revs) => self.hand += revs * secs_per_rev,
+            PlatterEvent::Nudge(revs) => self.nudge += revs * self.config.nudge,
+            PlatterEvent::Motor(on) => self.motor = on,
+            PlatterEvent::Rest => self.velocity = 0.0,
+            PlatterEvent::Config(config) => self.config = config,
+        }
+    }
+
+    // Speed of the record for the next sample
+    pub fn step(&mut self) -> f64 {
+        if self.touched {
+            let moved = self.hand / (HAND_FOLLOW * self.sample_rate).max(1.0);
+            self.hand -= moved;
+            self.velocity = moved * self.sample_rate;
+            return self.velocity;
+        }
+        self.nudge -= self.nudge / (self.config.inertia * self.sample_rate).max(1.0);
+        let target = if self.motor { 1.0 } else { 0.0 };
+        let secs = if self.velocity.abs() < target { self.config.spin_up } else { self.config.spin_down };
+        let rate = 1.0 / (secs * self.sample_rate).max(1.0);
+        self.velocity = if (target - self.velocity).abs() <= rate { target }
+                        else { self.velocity + rate * (target - self.velocity).signum() };
+        self.velocity + self.nudge
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE : u32 = 1000;
+
+    fn steps(platter: &mut Platter, n: usize) -> f64 {
+        (0 .. n).map(|_| platter.step()).last().unwrap()
+    }
+
+    #[test]
+    fn spin_up() {
+        let mut platter = Platter::new(RATE);
+        platter.handle(PlatterEvent::Rest);
+        assert!((steps(&mut platter, 100) - 0.5).abs() < 0.01);
+        assert!((steps(&mut platter, 99) - 1.0).abs() < 0.01);
+        assert_eq!(steps(&mut platter, 10), 1.0);
+    }
+
+    #[test]
+    fn brake() {
+        let mut platter = Platter::new(RATE);
+        platter.handle(PlatterEvent::Motor(false));
+        assert!((steps(&mut platter, 250) - 0.5).abs() < 0.01);
+        assert!(steps(&mut platter, 249) < 0.01);
+        assert_eq!(steps(&mut platter, 10), 0.0);
+        // a faster brake
+        platter.handle(PlatterEvent::Config(PlatterConfig { spin_down: 0.1, ..PlatterConfig::default() }));
+        platter.handle(PlatterEvent::Motor(true));
+        steps(&mut platter, 200);
+        platter.handle(PlatterEvent::Motor(false));
+        assert_eq!(steps(&mut platter, 100), 0.0);
+    }
+
+    #[test]
+    fn release() {
+        let mut platter = Platter::new(RATE);
+        platter.handle(PlatterEvent::Touch(true));
+        assert_eq!(steps(&mut platter, 10), 0.0);
+        // the record follows the hand and ends up where it was moved
+        platter.handle(PlatterEvent::Scratch(0.01));
+        let moved : f64 = (0 .. 200).map(|_| platter.step()).sum::<f64>() / RATE as f64;
+        assert!((moved - 0.018).abs() < 0.0005, "{}", moved);
+        // let go, the motor takes it back up to speed
+        platter.handle(PlatterEvent::Touch(false));
+        assert!((steps(&mut platter, 100) - 0.5).abs() < 0.01);
+        assert_eq!(steps(&mut platter, 110), 1.0);
+    }
+
+    #[test]
+    fn nudge_dies_down() {
+        let mut platter = Platter::new(RATE);
+        platter.handle(PlatterEvent::Nudge(0.1));
+        assert!((platter.step() - 1.1).abs() < 0.001);
+        assert!(steps(&mut platter, 1000) - 1.0 < 0.01);
+    }
+}
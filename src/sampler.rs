@@ -0,0 +1,349 @@
+extern crate simplemad;
+extern crate jack;
+
+use simplemad::{Decoder, Frame};
+use std::fs::File;
+use std::mem;
+use std::path::Path;
+use std::sync::mpsc;
+use std::sync::mpsc::{SyncSender, Receiver, RecvTimeoutError, TrySendError};
+use std::time::Duration;
+
+use mp3playerjack::PlayerStatus;
+use wav;
+
+use jack::prelude::{AsyncClient, AudioOutPort, AudioOutSpec, Client, ClosureProcessHandler,
+                    JackControl, ProcessScope, client_options, PortFlags, PortSpec};
+
+pub const SLOTS : usize = 16;
+const PRINT : bool = true;
+
+macro_rules! printinfo {
+    ($tx:expr, $($args:expr),*) => {{
+        if PRINT {
+            println!($($args),*);
+        }
+        else {
+           $tx.try_send(PlayerStatus::Print(format!($($args),*))).is_ok();
+        }
+    }};
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum SampleMode {
+    OneShot,
+    Loop,
+}
+
+#[derive(Debug, Deserialize)]
+pub enum SamplerCommand {
+    Load(usize, String, Option<f64>), //slot, path, bpm
+    #[serde(skip_deserializing)]
+    Fill(usize, Vec<Vec<f32>>, u32, f64), //slot, samples, sample_rate, bpm (grabbed from a deck)
+    Trigger(usize, bool),
+    Stop(usize),
+    StopAll,
+    Clear(usize),
+    Mode(usize, SampleMode),
+    Sync(usize, bool),
+    Volume(usize, f64),
+    MasterBpm(f64),
+}
+
+struct SlotInfo {
+    name: Option<String>,
+    bpm: Option<f64>,
+    sample_rate: u32,
+    mode: SampleMode,
+    sync: bool,
+    volume: f64,
+    playing: bool,
+}
+
+// Messages from the control thread to the jack process callback
+enum VoiceMsg {
+    Samples(usize, Vec<Vec<f32>>),
+    Play(usize),
+    Stop(usize),
+    Params(usize, SampleMode, f64, f32), //slot, mode, speed, volume
+}
+
+struct Voice {
+    samples: Vec<Vec<f32>>,
+    pos: f64,
+    speed: f64,
+    volume: f32,
+    mode: SampleMode,
+    playing: bool,
+}
+
+impl Voice {
+    fn new() -> Voice {
+        Voice {
+            samples: Vec::new(),
+            pos: 0.0,
+            speed: 1.0,
+            volume: 1.0,
+            mode: SampleMode::OneShot,
+            playing: false,
+        }
+    }
+
+    fn next(&mut self) -> (f32, f32) {
+        if !self.playing || self.samples.len() != 2 || self.samples[0].len() == 0 {
+            return (0.0, 0.0);
+        }
+        let idx = self.pos as usize;
+        let out = (self.samples[0][idx] * self.volume, self.samples[1][idx] * self.volume);
+        self.pos += self.speed;
+        let len = self.samples[0].len() as f64;
+        if self.pos >= len {
+            match self.mode {
+                SampleMode::Loop => self.pos %= len,
+                SampleMode::OneShot => {
+                    self.pos = 0.0;
+                    self.playing = false;
+                },
+            }
+        }
+        out
+    }
+}
+
+impl SlotInfo {
+    fn new() -> SlotInfo {
+        SlotInfo {
+            name: None,
+            bpm: None,
+            sample_rate: 0,
+            mode: SampleMode::OneShot,
+            sync: false,
+            volume: 1.0,
+            playing: false,
+        }
+    }
+
+    fn speed(&self, sink_sample_rate: u32, master_bpm: f64) -> f64 {
+        let resample = self.sample_rate as f64 / sink_sample_rate as f64;
+        match self.bpm {
+            Some(bpm) if self.sync && bpm > 0.0 && master_bpm > 0.0 => resample * master_bpm / bpm,
+            _ => resample,
+        }
+    }
+}
+
+pub fn decode(filename: &str) -> Option<(Vec<Vec<f32>>, u32)> {
+    let frames : Vec<Frame> = if filename.ends_with(".wav") {
+        wav::decode(filename).ok()?
+    }
+    else {
+        let file = File::open(&Path::new(filename)).ok()?;
+        Decoder::decode(file).ok()?.filter_map(|r| r.ok()).collect()
+    };
+    let mut samples = vec![Vec::new(), Vec::new()];
+    let mut sample_rate = 0;
+    for frame in frames {
+        sample_rate = frame.sample_rate;
+        let right = if frame.samples.len() > 1 { 1 } else { 0 };
+        for (l, r) in frame.samples[0].iter().zip(frame.samples[right].iter()) {
+            samples[0].push(l.to_f32());
+            samples[1].push(r.to_f32());
+        }
+    }
+    if samples[0].len() == 0 {
+        return None;
+    }
+    Some((samples, sample_rate))
+}
+
+pub fn run(rx: Receiver<SamplerCommand>, tx: SyncSender<PlayerStatus>) {
+    let (tv, rv) = mpsc::sync_channel::<VoiceMsg>(SLOTS*4);
+    let (td, rd) = mpsc::sync_channel::<usize>(SLOTS);
+    let (tr, rr) = mpsc::sync_channel::<Vec<Vec<f32>>>(SLOTS*4); // replaced samples, dropped outside of the process callback
+
+    let client = Client::new("rustydj_sampler", client_options::NO_START_SERVER).unwrap().0;
+    let mut l_chan = client.register_port("out_l", AudioOutSpec::default()).unwrap();
+    let mut r_chan = client.register_port("out_r", AudioOutSpec::default()).unwrap();
+    let port_names = [l_chan.name().to_string(), r_chan.name().to_string()];
+
+    let mut voices : Vec<Voice> = (0 .. SLOTS).map(|_| Voice::new()).collect();
+    let mut retired : Option<Vec<Vec<f32>>> = None;
+
+    let process = ClosureProcessHandler::new(move |_client: &Client, ps: &ProcessScope| -> JackControl {
+        // freeing the samples of a slot takes too long here, they go back to be dropped
+        if let Some(old) = retired.take() {
+            if let Err(TrySendError::Full(old)) = tr.try_send(old) {
+                retired = Some(old);
+            }
+        }
+        while retired.is_none() {
+            let msg = match rv.try_recv() {
+                Ok(msg) => msg,
+                Err(_) => break,
+            };
+            match msg {
+                VoiceMsg::Samples(slot, samples) => {
+                    let old = mem::replace(&mut voices[slot].samples, samples);
+                    if let Err(TrySendError::Full(old)) = tr.try_send(old) {
+                        retired = Some(old);
+                    }
+                    voices[slot].pos = 0.0;
+                    voices[slot].playing = false;
+                },
+                VoiceMsg::Play(slot) => {
+                    voices[slot].pos = 0.0;
+                    voices[slot].playing = true;
+                },
+                VoiceMsg::Stop(slot) => voices[slot].playing = false,
+                VoiceMsg::Params(slot, mode, speed, volume) => {
+                    voices[slot].mode = mode;
+                    voices[slot].speed = speed;
+                    voices[slot].volume = volume;
+                },
+            }
+        }
+
+        let mut l_buffer = AudioOutPort::new(&mut l_chan, ps);
+        let mut r_buffer = AudioOutPort::new(&mut r_chan, ps);
+        for (l_elem, r_elem) in l_buffer.iter_mut().zip(r_buffer.iter_mut()) {
+            *l_elem = 0.0;
+            *r_elem = 0.0;
+            for (slot, voice) in voices.iter_mut().enumerate() {
+                let was_playing = voice.playing;
+                let (l, r) = voice.next();
+                *l_elem += l;
+                *r_elem += r;
+                if was_playing && !voice.playing {
+                    td.try_send(slot).is_ok();
+                }
+            }
+        }
+
+        JackControl::Continue
+    });
+    let active_client = AsyncClient::new(client, (), process).unwrap();
+    for (sink,src) in active_client.ports(None, Some(AudioOutSpec::default().jack_port_type()), PortFlags::from_bits(0x4|0x1).unwrap()).iter().zip(port_names.iter()) {
+        printinfo!(tx, "Connect {} -> {}", src, sink);
+        active_client.connect_ports_by_name(src, sink).unwrap();
+    }
+    let sink_sample_rate = active_client.sample_rate() as u32;
+
+    let mut slots : Vec<SlotInfo> = (0 .. SLOTS).map(|_| SlotInfo::new()).collect();
+    let mut master_bpm = 0.0;
+
+    macro_rules! params {
+        ($slot:expr) => {{
+            let slot_ = $slot;
+            let info = &slots[slot_];
+            tv.send(VoiceMsg::Params(slot_, info.mode, info.speed(sink_sample_rate, master_bpm), info.volume as f32)).unwrap();
+        }};
+    }
+
+    macro_rules! status {
+        ($slot:expr) => {{
+            let slot_ = $slot;
+            tx.try_send(PlayerStatus::Sampler(slot_, slots[slot_].name.clone(), slots[slot_].playing)).is_ok();
+        }};
+    }
+
+    loop {
+        while rr.try_recv().is_ok() {}
+        while let Ok(slot) = rd.try_recv() {
+            slots[slot].playing = false;
+            status!(slot);
+        }
+
+        let cmd = match rx.recv_timeout(Duration::from_millis(50)) {
+            Ok(cmd) => cmd,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(_) => break,
+        };
+        match cmd {
+            SamplerCommand::Load(slot, _, _) | SamplerCommand::Fill(slot, _, _, _) | SamplerCommand::Trigger(slot, _) |
+                SamplerCommand::Stop(slot) | SamplerCommand::Clear(slot) | SamplerCommand::Mode(slot, _) |
+                SamplerCommand::Sync(slot, _) | SamplerCommand::Volume(slot, _) if slot >= SLOTS => {
+                printinfo!(tx, "No sampler slot {}", slot);
+            },
+            SamplerCommand::Load(slot, path, bpm) => {
+                match decode(&path) {
+                    Some((samples, sample_rate)) => {
+                        printinfo!(tx, "Sampler slot {}: {}", slot, path);
+                        slots[slot].name = Some(path);
+                        slots[slot].bpm = bpm;
+                        slots[slot].sample_rate = sample_rate;
+                        slots[slot].playing = false;
+                        tv.send(VoiceMsg::Samples(slot, samples)).unwrap();
+                        params!(slot);
+                        status!(slot);
+                    },
+                    None => printinfo!(tx, "Could not decode sample: {}", path),
+                }
+            },
+            SamplerCommand::Fill(slot, samples, sample_rate, bpm) => {
+                slots[slot].name = Some(format!("Grab {:.1} BPM", bpm));
+                slots[slot].bpm = Some(bpm);
+                slots[slot].sample_rate = sample_rate;
+                slots[slot].mode = SampleMode::Loop;
+                slots[slot].playing = false;
+                tv.send(VoiceMsg::Samples(slot, samples)).unwrap();
+                params!(slot);
+                status!(slot);
+            },
+            SamplerCommand::Trigger(slot, on) => {
+                if on && slots[slot].name.is_some() {
+                    if slots[slot].mode == SampleMode::Loop && slots[slot].playing {
+                        slots[slot].playing = false;
+                        tv.send(VoiceMsg::Stop(slot)).unwrap();
+                    }
+                    else {
+                        slots[slot].playing = true;
+                        tv.send(VoiceMsg::Play(slot)).unwrap();
+                    }
+                    status!(slot);
+                }
+            },
+            SamplerCommand::Stop(slot) => {
+                slots[slot].playing = false;
+                tv.send(VoiceMsg::Stop(slot)).unwrap();
+                status!(slot);
+            },
+            SamplerCommand::StopAll => {
+                for slot in 0 .. SLOTS {
+                    if slots[slot].playing {
+                        slots[slot].playing = false;
+                        tv.send(VoiceMsg::Stop(slot)).unwrap();
+                        status!(slot);
+                    }
+                }
+            },
+            SamplerCommand::Clear(slot) => {
+                slots[slot] = SlotInfo::new();
+                tv.send(VoiceMsg::Samples(slot, Vec::new())).unwrap();
+                status!(slot);
+            },
+            SamplerCommand::Mode(slot, mode) => {
+                slots[slot].mode = mode;
+                params!(slot);
+            },
+            SamplerCommand::Sync(slot, sync) => {
+                slots[slot].sync = sync;
+                params!(slot);
+            },
+            SamplerCommand::Volume(slot, volume) => {
+                slots[slot].volume = volume;
+                params!(slot);
+            },
+            SamplerCommand::MasterBpm(bpm) => {
+                if bpm != master_bpm {
+                    master_bpm = bpm;
+                    for slot in 0 .. SLOTS {
+                        if slots[slot].sync {
+                            params!(slot);
+                        }
+                    }
+                }
+            },
+        }
+    }
+    active_client.deactivate().unwrap();
+}
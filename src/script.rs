@@ -0,0 +1,313 @@
+extern crate rhai;
+extern crate serde_json;
+
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use self::rhai::{Array, CallFnOptions, Dynamic, Engine, Map, Scope, AST};
+use self::serde_json::Value;
+
+use mp3playerjack::PlayerCommand;
+use ui::UICommand;
+use controller::{Feedback, State};
+
+const RELOAD_MS: u64 = 1000; // how often the script file is checked for changes
+const MAX_OPERATIONS: u64 = 100_000; // a runaway loop is cut short before it holds up the other controls of the device
+
+// What a script asked for during a call
+pub enum Output {
+    Player(PlayerCommand),
+    UI(UICommand),
+    Midi(Vec<u8>),
+    Print(String),
+}
+
+// Shared between the script and the functions it calls
+struct Context {
+    out: Vec<Output>,
+    feedback: Feedback,
+    shift: bool,
+}
+
+// Controller logic in Rhai, next to the mapping. The script defines any of
+//   fn init()                        called on (re)load
+//   fn midi(status, data1, data2)    every incoming message, returns true to skip the mapping's bindings
+//   fn tick(ms)                      periodically, for LED animations
+// and calls player(cmd), ui(cmd), midi_out(status, data1, data2) or midi_out([bytes]) to act,
+// state(deck, state), selected() and shift() to look around. `this` keeps its fields between calls.
+// Scripts have no file, module or eval access and are stopped after MAX_OPERATIONS.
+pub struct Script {
+    pub path: String,
+    engine: Engine,
+    ast: Option<AST>,
+    state: Dynamic,
+    context: Arc<Mutex<Context>>,
+    modified: Option<SystemTime>,
+    checked: Instant,
+}
+
+// Script values as JSON, which PlayerCommand and UICommand deserialize from the same as mapping actions
+fn to_json(value: &Dynamic) -> Value {
+    if let Some(b) = value.clone().try_cast::<bool>() {
+        Value::from(b)
+    }
+    else if let Some(i) = value.clone().try_cast::<i64>() {
+        Value::from(i)
+    }
+    else if let Some(f) = value.clone().try_cast::<f64>() {
+        Value::from(f)
+    }
+    else if value.is_string() {
+        Value::from(value.to_string())
+    }
+    else if let Some(items) = value.clone().try_cast::<Array>() {
+        Value::Array(items.iter().map(to_json).collect())
+    }
+    else if let Some(fields) = value.clone().try_cast::<Map>() {
+        Value::Object(fields.iter().map(|(k, v)| (k.to_string(), to_json(v))).collect())
+    }
+    else {
+        Value::Null
+    }
+}
+
+fn engine(context: &Arc<Mutex<Context>>) -> Engine {
+    let mut engine = Engine::new();
+    engine.set_max_operations(MAX_OPERATIONS);
+    engine.set_max_call_levels(32);
+    engine.set_max_expr_depths(64, 32);
+    engine.set_max_string_size(4096);
+    engine.set_max_array_size(1024);
+    engine.set_max_map_size(1024);
+    engine.set_max_modules(0);
+    engine.disable_symbol("eval");
+
+    let ctx = context.clone();
+    engine.on_print(move |s| ctx.lock().unwrap().out.push(Output::Print(s.to_string())));
+    let ctx = context.clone();
+    engine.on_debug(move |s, _, _| ctx.lock().unwrap().out.push(Output::Print(s.to_string())));
+    let ctx = context.clone();
+    engine.register_fn("player", move |cmd: Dynamic| {
+        let json = to_json(&cmd);
+        ctx.lock().unwrap().out.push(match serde_json::from_value(json.clone()) {
+            Ok(cmd) => Output::Player(cmd),
+            Err(e) => Output::Print(format!("invalid player command {}: {}", json, e)),
+        });
+    });
+    let ctx = context.clone();
+    engine.register_fn("ui", move |cmd: Dynamic| {
+        let json = to_json(&cmd);
+        ctx.lock().unwrap().out.push(match serde_json::from_value(json.clone()) {
+            Ok(cmd) => Output::UI(cmd),
+            Err(e) => Output::Print(format!("invalid UI command {}: {}", json, e)),
+        });
+    });
+    let ctx = context.clone();
+    engine.register_fn("midi_out", move |status: i64, data1: i64, data2: i64| {
+        ctx.lock().unwrap().out.push(Output::Midi(vec![status as u8, data1 as u8, data2 as u8]));
+    });
+    let ctx = context.clone();
+    engine.register_fn("midi_out", move |bytes: Array| {
+        let bytes = bytes.iter().map(|b| b.as_int().unwrap_or(0) as u8).collect();
+        ctx.lock().unwrap().out.push(Output::Midi(bytes));
+    });
+    let ctx = context.clone();
+    engine.register_fn("state", move |deck: i64, state: Dynamic| -> bool {
+        let ctx = ctx.lock().unwrap();
+        match serde_json::from_value::<State>(to_json(&state)) {
+            Ok(state) => ctx.feedback.state(deck as usize, state, ctx.shift),
+            Err(_) => false,
+        }
+    });
+    let ctx = context.clone();
+    engine.register_fn("selected", move || ctx.lock().unwrap().feedback.selected() as i64);
+    let ctx = context.clone();
+    engine.register_fn("shift", move || ctx.lock().unwrap().shift);
+    engine
+}
+
+impl Script {
+    pub fn new(path: &str) -> Script {
+        let context = Arc::new(Mutex::new(Context { out: Vec::new(), feedback: Feedback::new(), shift: false }));
+        Script {
+            path: path.to_string(),
+            engine: engine(&context),
+            ast: None,
+            state: Dynamic::from(Map::new()),
+            context: context,
+            modified: None,
+            checked: Instant::now() - Duration::from_millis(RELOAD_MS),
+        }
+    }
+
+    // Compiles the script again when the file changed, keeps the old one if the new one does not compile
+    fn reload(&mut self) {
+        if self.checked.elapsed() < Duration::from_millis(RELOAD_MS) {
+            return;
+        }
+        self.checked = Instant::now();
+        let modified = fs::metadata(&self.path).and_then(|m| m.modified()).ok();
+        if modified.is_none() || modified == self.modified {
+            return;
+        }
+        self.modified = modified;
+        let source = match fs::read_to_string(&self.path) {
+            Ok(source) => source,
+            Err(e) => return self.print(format!("{}: {}", self.path, e)),
+        };
+        match self.engine.compile(&source) {
+            Ok(ast) => {
+                let reloaded = self.ast.is_some();
+                self.ast = Some(ast);
+                self.state = Dynamic::from(Map::new());
+                self.call("init", Vec::new());
+                if reloaded {
+                    self.print(format!("{}: reloaded", self.path));
+                }
+            },
+            Err(e) => self.print(format!("{}: {}", self.path, e)),
+        }
+    }
+
+    fn print(&mut self, message: String) {
+        self.context.lock().unwrap().out.push(Output::Print(message));
+    }
+
+    // Calls a script function if it is defined, with `this` bound to the script state
+    fn call(&mut self, name: &str, args: Vec<Dynamic>) -> Option<Dynamic> {
+        let defined = match self.ast {
+            Some(ref ast) => ast.iter_functions().any(|f| f.name == name && f.params.len() == args.len()),
+            None => false,
+        };
+        if !defined {
+            return None;
+        }
+        let ast = self.ast.as_ref().unwrap();
+        let options = CallFnOptions::new().eval_ast(false).bind_this_ptr(&mut self.state);
+        match self.engine.call_fn_with_options::<Dynamic>(options, &mut Scope::new(), ast, name, args) {
+            Ok(result) => Some(result),
+            Err(e) => {
+                let message = format!("{}: {}: {}", self.path, name, e);
+                self.print(message);
+                None
+            },
+        }
+    }
+
+    fn prepare(&mut self, feedback: &Feedback, shift: bool) {
+        let mut context = self.context.lock().unwrap();
+        context.feedback.clone_from(feedback);
+        context.shift = shift;
+    }
+
+    fn output(&mut self) -> Vec<Output> {
+        self.context.lock().unwrap().out.drain(..).collect()
+    }
+
+    // Runs midi() for a message, returns whether the script handled it and what it asked for
+    pub fn midi(&mut self, bytes: &[u8], feedback: &Feedback, shift: bool) -> (bool, Vec<Output>) {
+        self.reload();
+        self.prepare(feedback, shift);
+        let data = |n: usize| Dynamic::from(bytes.get(n).cloned().unwrap_or(0) as i64);
+        let handled = self.call("midi", vec![data(0), data(1), data(2)])
+            .and_then(|result| result.try_cast::<bool>())
+            .unwrap_or(false);
+        (handled, self.output())
+    }
+
+    pub fn tick(&mut self, ms: u64, feedback: &Feedback, shift: bool) -> Vec<Output> {
+        self.reload();
+        self.prepare(feedback, shift);
+        self.call("tick", vec![Dynamic::from(ms as i64)]);
+        self.output()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::io::Write;
+
+    fn script(name: &str, source: &str) -> Script {
+        let path = env::temp_dir().join(format!("rustydj_{}.rhai", name));
+        fs::File::create(&path).unwrap().write_all(source.as_bytes()).unwrap();
+        Script::new(path.to_str().unwrap())
+    }
+
+    fn printed(out: &[Output]) -> Vec<String> {
+        out.iter().filter_map(|o| match *o { Output::Print(ref s) => Some(s.clone()), _ => None }).collect()
+    }
+
+    #[test]
+    fn state_persists() {
+        let mut script = script("state", "
+            fn init() { this.count = 0; }
+            fn midi(status, data1, data2) {
+                this.count += 1;
+                player(#{ Deck: [this.count, #{ HotCue: [data1, data2 > 0] }] });
+                midi_out(status, data1, this.count);
+                true
+            }");
+        let feedback = Feedback::new();
+        script.midi(&[0x90, 3, 127], &feedback, false);
+        let (handled, out) = script.midi(&[0x90, 4, 0], &feedback, false);
+        assert!(handled);
+        assert_eq!(out.len(), 2);
+        match out[0] {
+            Output::Player(ref cmd) => assert_eq!(format!("{:?}", cmd), "Deck(2, HotCue(4, false))"),
+            _ => panic!("expected a player command"),
+        }
+        match out[1] {
+            Output::Midi(ref bytes) => assert_eq!(bytes, &vec![0x90, 4, 2]),
+            _ => panic!("expected MIDI output"),
+        }
+    }
+
+    #[test]
+    fn hot_reload() {
+        let mut script = script("reload", "fn tick(ms) { midi_out(0x90, 1, ms) }");
+        let feedback = Feedback::new();
+        match script.tick(5, &feedback, false)[0] {
+            Output::Midi(ref bytes) => assert_eq!(bytes, &vec![0x90, 1, 5]),
+            _ => panic!("expected MIDI output"),
+        }
+        fs::File::create(&script.path).unwrap().write_all(b"fn tick(ms) { midi_out(0x90, 2, ms) }").unwrap();
+        script.modified = None;
+        script.checked -= Duration::from_millis(RELOAD_MS);
+        let out = script.tick(6, &feedback, false);
+        assert_eq!(printed(&out).len(), 1);
+        match out[1] {
+            Output::Midi(ref bytes) => assert_eq!(bytes, &vec![0x90, 2, 6]),
+            _ => panic!("expected MIDI output"),
+        }
+        // a broken edit keeps the script that works
+        fs::File::create(&script.path).unwrap().write_all(b"fn tick(ms) {").unwrap();
+        script.modified = None;
+        script.checked -= Duration::from_millis(RELOAD_MS);
+        let out = script.tick(7, &feedback, false);
+        assert_eq!(printed(&out).len(), 1);
+        match out[1] {
+            Output::Midi(ref bytes) => assert_eq!(bytes, &vec![0x90, 2, 7]),
+            _ => panic!("expected MIDI output"),
+        }
+    }
+
+    #[test]
+    fn sandboxed() {
+        let mut script = script("sandbox", "
+            fn midi(status, data1, data2) { loop { } }
+            fn tick(ms) { eval(\"1\") }");
+        let feedback = Feedback::new();
+        let (handled, out) = script.midi(&[0x90, 3, 127], &feedback, false);
+        assert!(!handled);
+        assert_eq!(printed(&out).len(), 1);
+        assert!(script.ast.is_none());
+
+        let mut script = self::script("sandbox_loop", "fn midi(status, data1, data2) { loop { } }");
+        let (handled, out) = script.midi(&[0x90, 3, 127], &feedback, false);
+        assert!(!handled);
+        assert_eq!(printed(&out).len(), 1);
+    }
+}
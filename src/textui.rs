@@ -1,5 +1,6 @@
 extern crate termion;
 extern crate tui;
+extern crate serde_json;
 
 use std::io;
 
@@ -8,12 +9,14 @@ use termion::input::TermRead;
 
 use tui::Terminal;
 use tui::backend::{RawBackend, Backend};
-use tui::widgets::{Block, Borders, Row, Table, Widget, Paragraph, Gauge, Tabs};
+use tui::buffer::Buffer;
+use tui::widgets::{Block, Borders, Row, Table, Widget, Paragraph, Tabs};
 use tui::layout::{Direction, Group, Rect, Size};
 use tui::style::{Color, Modifier, Style};
 
 use std::collections::HashMap;
-use std::cmp::{min, max};
+use std::cmp::{min, max, Ordering};
+use std::fs::File;
 use std::time::{Duration, Instant};
 use std::ops::{DerefMut, Div};
 use std::sync::{Arc, Mutex};
@@ -21,8 +24,109 @@ use std::sync::mpsc;
 use std::thread;
 use std::fmt;
 use mp3playerjack::{PlayerCommand, PlayerStatus};
+use autodj::AutoDJCommand;
 use ui::UICommand;
-use library::{Library, Track};
+use keymap::{Keymap, KeyCommand};
+use controller::{Learn, learn_targets};
+use library::{Library, Track, Cue};
+use waveform::Waveform;
+use decks::DECKS;
+
+const SEARCH : usize = ::std::usize::MAX; // playlist id of the search results
+const COLUMNS : &'static str = "columns.json";
+const DETAIL_SECONDS : f64 = 8.0; // shown by the scrolling waveform
+const BAR_CHARS : [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+const METER_WIDTH : usize = 10;
+const CROSSFADER_WIDTH : usize = 21;
+// Colours offered in turn for cues, the rekordbox hot cue colours
+const CUE_COLOURS : [(u8, u8, u8); 8] = [(222, 68, 207), (230, 40, 40), (255, 127, 0), (195, 175, 4),
+                                          (16, 177, 118), (0, 192, 255), (48, 90, 255), (170, 114, 255)];
+// Fields offered in turn when changing or adding a column, any other Track::info field can be put in columns.json
+const FIELDS : [&'static str; 17] = ["Id", "Title", "Artist", "Album", "Key", "Bpm", "Genre", "Rating", "Comments", "Year",
+                                     "TotalTime", "PlayCount", "DateAdded", "Label", "Remixer", "Composer", "BitRate"];
+
+// Track table layout, saved to columns.json whenever it changes
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Columns {
+    pub shown: Vec<String>, // Track::get fields, in order
+    pub sort: String, // field the tracks are sorted by
+    pub descending: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ColumnCommand {
+    Select(i32), // moves the column cursor
+    Move(i32), // moves the column under the cursor
+    Change(i32), // shows the next or previous field not shown instead
+    Add, // shows the next field not shown after the cursor
+    Remove,
+    Sort, // sorts by the column under the cursor, again for descending
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum CueCommand {
+    Select(i32), // moves the cue cursor
+    Set(Option<usize>), // hot cue slot or memory cue at the playhead
+    Name(Option<String>), // name being typed for the cue under the cursor, None drops it
+    Rename, // gives the cue under the cursor the typed name
+    Colour(i32), // next or previous colour
+    Move(f64), // beats
+    Delete,
+}
+
+impl Columns {
+    fn load() -> Columns {
+        let default = Columns {
+            shown: ["Id", "Title", "Artist", "Key", "Bpm"].iter().map(|f| f.to_string()).collect(),
+            sort: "Key".to_string(),
+            descending: false,
+        };
+        match File::open(COLUMNS) {
+            Ok(file) => match serde_json::from_reader::<_, Columns>(file) {
+                Ok(ref columns) if columns.shown.len() == 0 => default,
+                Ok(columns) => columns,
+                Err(e) => {
+                    println!("{}: {}", COLUMNS, e);
+                    default
+                },
+            },
+            Err(_) => default,
+        }
+    }
+
+    fn save(&self) -> Result<(), String> {
+        let file = File::create(COLUMNS).map_err(|e| format!("{}: {}", COLUMNS, e))?;
+        serde_json::to_writer_pretty(file, self).map_err(|e| format!("{}: {}", COLUMNS, e))
+    }
+
+    // Cycles through FIELDS from a field, skipping the ones shown
+    fn next_field(&self, from: &str, step: i32) -> Option<String> {
+        let n = FIELDS.len() as i32;
+        let dir = if step < 0 { -1 } else { 1 };
+        let start = FIELDS.iter().position(|f| *f == from).map_or(if dir < 0 { 0 } else { -1 }, |i| i as i32);
+        (1 .. n + 1).map(|k| FIELDS[((start + k * dir) % n + n) as usize % FIELDS.len()])
+            .find(|f| !self.shown.iter().any(|s| s == f))
+            .map(|f| f.to_string())
+    }
+}
+
+// Numbers compare as numbers, everything else case insensitive
+fn compare(a: &str, b: &str) -> Ordering {
+    match (a.parse::<f64>(), b.parse::<f64>()) {
+        (Ok(a), Ok(b)) => a.partial_cmp(&b).unwrap_or(Ordering::Equal),
+        _ => a.to_lowercase().cmp(&b.to_lowercase()),
+    }
+}
+
+// Columns that fit their content, the others share the rest of the width
+fn column_width(field: &str) -> Option<u16> {
+    match field {
+        "Id" | "BitRate" | "Rating" | "TotalTime" => Some(5),
+        "Key" | "Bpm" | "Year" | "PlayCount" => Some(4),
+        "DateAdded" => Some(10),
+        _ => None,
+    }
+}
 
 impl fmt::Display for Duration_ {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -62,19 +166,21 @@ impl<'a,'b> Div<&'a Duration_> for &'b Duration_ {
     }
 }
 
-struct LibraryRender<'a> {
+struct LibraryRender {
     size: Rect,
     tracks: HashMap<usize, Vec<String>>,
     item_indexes: Vec<usize>,
     items: HashMap<usize, Vec<String>>,
     selected: usize,
     selected_id: usize,
-    headers: Vec<&'a str>,
+    columns: Columns,
+    column: usize, // column cursor
     top: usize,
     bottom: usize,
     playlists: HashMap<usize, (bool, HashMap<usize, Vec<String>>)>,
     playlist_stack: Vec<usize>,
     playlist_names: Vec<String>,
+    search: String, // query of the search results
 }
 
 struct TrackRender {
@@ -82,52 +188,247 @@ struct TrackRender {
     position: Duration_,
     sample_pos: f64,
     speed: f64,
+    sample_rate: u32,
     track: Option<Track>,
+    stems: Vec<(String, f64, bool)>,
+    looping: Option<(f64, f64)>,
+    waveform: Option<Waveform>,
+    hotcues: Vec<(usize, f64)>,
+    cue: f64,
+    tempo: f64, // pitch fader
+    volume: f64,
+    playing: bool,
+    synced: bool, // tempo locked to the master deck
+    level: f64, // peak of what the deck plays, 0-1
+}
+
+// Hot cues in slot order, then memory cues in track order
+struct CueRender {
+    selected: usize,
+    naming: Option<String>,
+}
+
+struct MixerRender {
+    crossfader: Option<f64>, // None until it is moved, the decks play at full volume until then
+}
+
+// A stretch of the waveform, a marker row on top of bars coloured by their low, mid and high parts as red,
+// green and blue. The playhead is highlighted, the loop shaded.
+struct WaveView<'a> {
+    waveform: &'a Waveform,
+    start: f64, // sample at the left edge
+    column_samples: f64,
+    playhead: f64,
+    beats: Option<(f64, f64)>, // first beat and beat length in samples, for ticks, bars every 4 beats
+    hotcues: &'a [(usize, f64)],
+    cue: f64,
+    looping: Option<(f64, f64)>,
+}
+
+impl<'a> Widget for WaveView<'a> {
+    fn draw(&mut self, area: &Rect, buf: &mut Buffer) {
+        if area.height < 2 || self.column_samples <= 0.0 {
+            return;
+        }
+        let rows = area.height - 1;
+        let column = |pos: f64| if pos < self.start { None } else { Some(((pos - self.start) / self.column_samples) as u16) };
+        for x in 0 .. area.width {
+            let from = self.start + x as f64 * self.column_samples;
+            let to = from + self.column_samples;
+            if let Some((first, len)) = self.beats {
+                let beat = ((from - first) / len).ceil();
+                if len > 0.0 && first + beat * len < to {
+                    buf.get_mut(area.left() + x, area.top()).set_char(if beat as i64 % 4 == 0 { '|' } else { '·' }).set_fg(Color::Gray);
+                }
+            }
+            let looped = self.looping.map_or(false, |(start, end)| to > start && from < end);
+            let playhead = self.playhead >= from && self.playhead < to;
+            let peak = self.waveform.peak(from, to);
+            let (l, m, h) = peak.unwrap_or((0, 0, 0));
+            let loudest = max(l, max(m, h));
+            let colour = if loudest > 0 {
+                let c = |v: u8| (v as u16 * 255 / loudest as u16) as u8;
+                Color::Rgb(c(l), c(m), c(h))
+            } else { Color::Reset };
+            let eighths = loudest as u32 * rows as u32 * 8 / 255;
+            for r in 0 .. rows {
+                let cell = buf.get_mut(area.left() + x, area.bottom() - 1 - r);
+                let fill = eighths.saturating_sub(r as u32 * 8).min(8);
+                if fill > 0 {
+                    cell.set_char(BAR_CHARS[fill as usize - 1]).set_fg(colour);
+                }
+                if playhead {
+                    cell.set_bg(Color::White);
+                }
+                else if looped {
+                    cell.set_bg(Color::DarkGray);
+                }
+            }
+        }
+        let mut marker = |pos: f64, symbol: char, colour: Color| {
+            if let Some(x) = column(pos) {
+                if x < area.width {
+                    buf.get_mut(area.left() + x, area.top()).set_char(symbol).set_fg(colour);
+                }
+            }
+        };
+        marker(self.cue, 'C', Color::Yellow);
+        for &(idx, pos) in self.hotcues.iter() {
+            marker(pos, (b'A' + (idx % 26) as u8) as char, Color::Green);
+        }
+    }
 }
 
 struct DebugRender {
     buffer: Vec<String>
 }
 
-struct App<'a> {
+struct App {
     size: Rect,
+    deck: usize,
+    master: usize,
     terminal: Terminal<RawBackend>,
     debugr: DebugRender,
-    trackr: TrackRender,
-    libraryr: LibraryRender<'a>,
+    decks: Vec<TrackRender>,
+    mixerr: MixerRender,
+    cuer: CueRender,
+    libraryr: LibraryRender,
+    learn: Learn,
+    learn_targets: Vec<String>,
+    learning: Option<usize>, // selected learn target while in learn mode
 }
 
-impl<'a> App<'a> {
-    fn new(library: &Library) -> App<'a> {
+impl App {
+    fn new(library: &Library, learn: Learn) -> App {
         // Terminal initialization
         //let backend = MouseBackend::new().unwrap();
         let backend = RawBackend::new().unwrap();
         App { 
             size: Rect::default(),
+            deck: 0,
+            master: 0,
             terminal: Terminal::new(backend).unwrap(),
-            libraryr: LibraryRender::new(library),
+            libraryr: LibraryRender::new(library, Columns::load()),
             debugr: DebugRender::new(),
-            trackr: TrackRender::new(),
+            decks: (0 .. DECKS).map(|_| TrackRender::new()).collect(),
+            mixerr: MixerRender { crossfader: None },
+            cuer: CueRender { selected: 0, naming: None },
+            learn: learn,
+            learn_targets: learn_targets().into_iter().map(|target| target.label).collect(),
+            learning: None,
+        }
+    }
+
+    // Arms a learn target, the next control moved on a controller is bound to it
+    fn set_learning(&mut self, learning: Option<usize>) {
+        let learning = learning.map(|i| min(i, self.learn_targets.len() - 1));
+        *self.learn.lock().unwrap() = learning;
+        self.learning = learning;
+        match learning {
+            Some(i) => self.debugr.println(format!("Learn: move a control for {}", self.learn_targets[i])),
+            None => self.debugr.println("Learn mode off".to_string()),
         }
     }
 
+    // Edits the cues of the track on the selected deck, in the library and on every deck playing it
+    fn cue(&mut self, cmd: CueCommand, tx: &mpsc::Sender<PlayerCommand>, library: &mut Library) -> Result<(), String> {
+        let trackr = &self.decks[self.deck];
+        let track = match trackr.track {
+            Some(ref track) => track,
+            None => return Err("No track loaded".to_string()),
+        };
+        let order = CueRender::order(&track.cues);
+        self.cuer.selected = min(self.cuer.selected, max(order.len(), 1) - 1);
+        let selected = order.get(self.cuer.selected).cloned();
+        let mut cues = track.cues.clone();
+        match cmd {
+            CueCommand::Select(step) => {
+                self.cuer.selected = min(max(self.cuer.selected as i32 + step, 0) as usize, max(order.len(), 1) - 1);
+                self.cuer.naming = None;
+                return Ok(());
+            },
+            CueCommand::Name(name) => {
+                self.cuer.naming = name;
+                return Ok(());
+            },
+            CueCommand::Set(slot) => {
+                let start = trackr.sample_pos.max(0.0) as usize;
+                if let Some(slot) = slot {
+                    cues.retain(|cue| cue.hotcue != Some(slot));
+                }
+                cues.push(Cue { name: String::new(), start: start, type_: 0, hotcue: slot, colour: None });
+                self.cuer.selected = CueRender::order(&cues).iter().position(|&i| i == cues.len() - 1).unwrap_or(0);
+            },
+            CueCommand::Rename => {
+                let name = self.cuer.naming.take().unwrap_or_default();
+                cues[selected.ok_or("No cue selected")?].name = name;
+            },
+            CueCommand::Colour(step) => {
+                let cue = &mut cues[selected.ok_or("No cue selected")?];
+                let n = CUE_COLOURS.len() as i32;
+                let next = match cue.colour.and_then(|colour| CUE_COLOURS.iter().position(|c| *c == colour)) {
+                    Some(i) => ((i as i32 + step) % n + n) % n,
+                    None if step < 0 => n - 1,
+                    None => 0,
+                };
+                cue.colour = Some(CUE_COLOURS[next as usize]);
+            },
+            CueCommand::Move(beats) => {
+                if track.bpm() <= 0.0 {
+                    return Err("No beat grid".to_string());
+                }
+                let index = selected.ok_or("No cue selected")?;
+                let start = cues[index].start as f64 + beats * 60.0 / track.bpm() * track.sample_rate as f64;
+                cues[index].start = start.max(0.0) as usize;
+                self.cuer.selected = CueRender::order(&cues).iter().position(|&i| i == index).unwrap_or(0);
+            },
+            CueCommand::Delete => {
+                cues.remove(selected.ok_or("No cue selected")?);
+            },
+        }
+        let id = track.id;
+        for (deck, trackr) in self.decks.iter_mut().enumerate() {
+            if let Some(ref mut track) = trackr.track {
+                if track.id == id {
+                    track.cues = cues.clone();
+                    tx.send(PlayerCommand::Deck(deck, Box::new(PlayerCommand::Cues(cues.clone())))).unwrap();
+                }
+            }
+        }
+        library.set_cues(id, cues)
+    }
+
     fn draw(&mut self) {
-        let trackr = &mut self.trackr;
+        let decks = &mut self.decks;
+        let mixerr = &self.mixerr;
+        let cuer = &self.cuer;
         let debugr = &mut self.debugr;
         let libraryr = &mut self.libraryr;
+        let (selected, master) = (self.deck, self.master);
         Group::default()
             .direction(Direction::Vertical)
-            .sizes(&[Size::Max(15), Size::Percent(50)])
+            .sizes(&[Size::Max(18), Size::Fixed(4), Size::Percent(50)])
             .margin(1)
             .render(&mut self.terminal, &self.size.clone(), |t, chunks| {
-                trackr.render(t, &chunks[0]);
+                let deck_sizes : Vec<Size> = (0 .. DECKS).map(|_| Size::Percent((100 / DECKS) as u16)).collect();
+                Group::default()
+                    .direction(Direction::Horizontal)
+                    .sizes(&deck_sizes)
+                    .margin(0)
+                    .render(t, &chunks[0], |t, chunks| {
+                        for (deck, trackr) in decks.iter_mut().enumerate() {
+                            trackr.render(t, &chunks[deck], deck, deck == selected, deck == master);
+                        }
+                    });
+                mixerr.render(t, &chunks[1], decks);
                 Group::default()
                     .direction(Direction::Horizontal)
-                    .sizes(&[Size::Max(chunks[1].width), Size::Percent(20)])
+                    .sizes(&[Size::Max(chunks[2].width), Size::Fixed(32), Size::Percent(20)])
                     .margin(0)
-                    .render(t, &chunks[1], |t, chunks| {
+                    .render(t, &chunks[2], |t, chunks| {
                         libraryr.render(t, &chunks[0]);
-                        debugr.render(t, &chunks[1]);
+                        cuer.render(t, &chunks[1], &decks[selected]);
+                        debugr.render(t, &chunks[2]);
                     });
             });
         //debugr.println(format!("Top: {}, Bottom: {}, Selected: {}, Selected_ID: {}", libraryr.top, libraryr.bottom, libraryr.selected, libraryr.selected_id));
@@ -157,61 +458,189 @@ impl TrackRender {
             position: Duration_::default(),
             sample_pos: 0.0,
             speed: 1.0,
+            sample_rate: 0,
             track : None,
+            stems: Vec::new(),
+            looping: None,
+            synced: false,
+            waveform: None,
+            hotcues: Vec::new(),
+            cue: 0.0,
+            tempo: 1.0,
+            volume: 1.0,
+            playing: false,
+            level: 0.0,
         }
     }
 
-    fn render<T: Backend>(&mut self, t: &mut Terminal<T>, chunk: &Rect) {
-        let mut to_print = String::new();
-        let mut track_str = String::new();
+    fn update(&mut self, status: PlayerStatus) {
+        match status {
+            PlayerStatus::Pos(pos, sample_pos) => {
+                self.position = Duration_::new(pos);
+                self.sample_pos = sample_pos;
+            }
+            PlayerStatus::TrackInfo(track, duration, sample_rate) => { 
+                self.track = track;  
+                self.duration = Duration_::new(duration);
+                self.sample_rate = sample_rate;
+                self.waveform = None;
+            }
+            PlayerStatus::Waveform(waveform) => self.waveform = Some(waveform),
+            PlayerStatus::HotCues(hotcues) => self.hotcues = hotcues,
+            PlayerStatus::Cue(cue) => self.cue = cue,
+            PlayerStatus::Loop(looping) => self.looping = looping,
+            PlayerStatus::Speed(speed) => self.speed = speed,
+            PlayerStatus::Tempo(tempo) => self.tempo = tempo,
+            PlayerStatus::Volume(volume) => self.volume = volume,
+            PlayerStatus::Playing(playing) => self.playing = playing,
+            PlayerStatus::Sync(synced) => self.synced = synced,
+            PlayerStatus::Level(level) => self.level = level,
+            PlayerStatus::Stems(stems) => self.stems = stems,
+            _ => (),
+        }
+    }
+
+    fn render<T: Backend>(&mut self, t: &mut Terminal<T>, chunk: &Rect, deck: usize, selected: bool, master: bool) {
+        let mut info = String::new();
         if let Some(ref track_) = self.track {
-            to_print.push_str(&format!("Speed: {:.2} ({:.3}x)", self.speed * track_.bpm() as f64, self.speed));
-            track_str.push_str(&format!("Artist: {}\nTitle: {}\nAlbum: {}\nKey: {}\nBPM: {}", 
-                                        track_.artist(), track_.title(), track_.album(), track_.key(), track_.bpm()));
+            let remaining = self.duration.unwrap().checked_sub(*self.position.unwrap()).unwrap_or_default();
+            let mut state = vec![if self.playing { "Playing" } else { "Paused" }.to_string()];
+            if master {
+                state.push("Master".to_string());
+            }
+            else if self.synced {
+                state.push("Sync".to_string());
+            }
+            if let (Some((start, end)), true) = (self.looping, track_.bpm() > 0.0 && self.sample_rate > 0) {
+                state.push(format!("Loop {:.2}", (end - start) / (60.0 / track_.bpm() as f64 * self.sample_rate as f64)));
+            }
+            info.push_str(&format!("{}\n{}\n{} -{}\nBPM: {:.2} ({:+.2}%)\nKey: {}\n{}",
+                                   track_.title(), track_.artist(), self.position, Duration_::new(remaining),
+                                   self.speed * track_.bpm() as f64, (self.tempo - 1.0) * 100.0, track_.key(), state.join("  ")));
+            for (i, &(ref name, volume, mute)) in self.stems.iter().enumerate() {
+                info.push_str(&format!("\nStem {} {}: {}", i + 1, name, if mute {"muted".to_string()} else {format!("{:.0}%", volume * 100.0)}));
+            }
         }
 
+        let title = format!("Deck {}", deck + 1);
+        let border = if selected { Style::default().fg(Color::Yellow) } else { Style::default() };
         Block::default()
-            .title("Now playing")
+            .title(&title)
             .borders(Borders::ALL)
+            .border_style(border)
             .render(t, chunk);
+        let sample_rate = self.sample_rate as f64;
+        let beats = match self.track {
+            Some(ref track) if track.bpm() > 0.0 && sample_rate > 0.0 => Some((track.first_beat as f64, 60.0 / track.bpm() * sample_rate)),
+            _ => None,
+        };
         Group::default()
             .direction(Direction::Vertical)
-            .sizes(&[Size::Max(10), Size::Max(5)])
+            .sizes(&[Size::Min(6), Size::Fixed(5), Size::Fixed(3)])
             .margin(1)
             .render(t, chunk, |t, chunks| {
-                Group::default()
-                    .direction(Direction::Horizontal)
-                    .sizes(&[Size::Percent(40), Size::Percent(30)])
-                    .margin(0)
-                    .render(t, &chunks[0], |t, chunks| {
-                        Paragraph::default()
-                            .block(Block::default()
-                                   .title("Track")
-                                   .borders(Borders::ALL))
-                            .text(&track_str)
-                            .render(t, &chunks[0]);
-                        Paragraph::default()
-                            .block(Block::default()
-                                   .title("Info")
-                                   .borders(Borders::ALL))
-                            .text(&to_print)
-                            .render(t, &chunks[1]);
-                    });
-                Gauge::default()
-                    .block(Block::default())
-                    .style(
-                        Style::default()
-                        .fg(Color::Magenta)
-                        .bg(Color::Black)
-                        .modifier(Modifier::Italic),
-                        )
-                    .label(&format!("{}/{} |{:013.2}|", self.position, self.duration, self.sample_pos))
-                    .percent((&self.position/&self.duration * 100.0) as u16)
-                    .render(t, &chunks[1]);
+                Paragraph::default()
+                    .wrap(false)
+                    .text(&info)
+                    .render(t, &chunks[0]);
+                if let (Some(waveform), true) = (self.waveform.as_ref(), sample_rate > 0.0) {
+                    let detail = DETAIL_SECONDS * sample_rate / max(chunks[1].width, 1) as f64;
+                    WaveView {
+                        waveform: waveform,
+                        start: self.sample_pos - chunks[1].width as f64 / 2.0 * detail,
+                        column_samples: detail,
+                        playhead: self.sample_pos,
+                        beats: beats,
+                        hotcues: &self.hotcues,
+                        cue: self.cue,
+                        looping: self.looping,
+                    }.render(t, &chunks[1]);
+                    WaveView {
+                        waveform: waveform,
+                        start: 0.0,
+                        column_samples: (waveform.bins.len() * waveform.bin_samples) as f64 / max(chunks[2].width, 1) as f64,
+                        playhead: self.sample_pos,
+                        beats: None,
+                        hotcues: &self.hotcues,
+                        cue: self.cue,
+                        looping: self.looping,
+                    }.render(t, &chunks[2]);
+                }
             });
     }
 }
 
+impl CueRender {
+    // Indexes into the cues in the order they are listed
+    fn order(cues: &[Cue]) -> Vec<usize> {
+        let mut order : Vec<usize> = (0 .. cues.len()).collect();
+        order.sort_by_key(|&i| (cues[i].hotcue.is_none(), cues[i].hotcue, cues[i].start));
+        order
+    }
+
+    fn render<T: Backend>(&self, t: &mut Terminal<T>, chunk: &Rect, trackr: &TrackRender) {
+        let no_cues = Vec::new();
+        let (cues, sample_rate) = match trackr.track {
+            Some(ref track) => (&track.cues, max(track.sample_rate, 1) as f64),
+            None => (&no_cues, 1.0),
+        };
+        let order = CueRender::order(cues);
+        let selected = min(self.selected, max(order.len(), 1) - 1);
+        let rows = max(chunk.height as isize - 3, 1) as usize;
+        let top = if selected >= rows { selected + 1 - rows } else { 0 };
+        let selected_style = Style::default().bg(Color::Yellow).modifier(Modifier::Bold);
+        let styles : Vec<Style> = order.iter().enumerate().map(|(i, &cue)| {
+            let style = if i == selected { selected_style.clone() } else { Style::default() };
+            match cues[cue].colour {
+                Some((r, g, b)) => style.fg(Color::Rgb(r, g, b)),
+                None => style,
+            }
+        }).collect();
+        let data : Vec<[String; 3]> = order.iter().enumerate().map(|(i, &cue)| {
+            let cue = &cues[cue];
+            let secs = cue.start as f64 / sample_rate;
+            let name = match self.naming {
+                Some(ref name) if i == selected => format!("{}_", name),
+                _ => cue.name.clone(),
+            };
+            [cue.hotcue.map_or("M".to_string(), |slot| ((b'A' + slot as u8) as char).to_string()),
+             format!("{:02}:{:05.2}", secs as u64 / 60, secs % 60.0), name]
+        }).collect();
+        Table::new(
+            ["", "Time", "Name"].iter(),
+            data.iter().zip(styles.iter()).skip(top).take(rows).map(|(row, style)| Row::StyledData(row.iter(), style))
+            ).block(Block::default().borders(Borders::ALL).title("Cues"))
+            .widths(&[1, 8, 17])
+            .render(t, chunk);
+    }
+}
+
+impl MixerRender {
+    fn render<T: Backend>(&self, t: &mut Terminal<T>, chunk: &Rect, decks: &[TrackRender]) {
+        let meters : Vec<String> = decks.iter().enumerate().map(|(deck, trackr)| {
+            let lit = ((trackr.level * METER_WIDTH as f64).round() as usize).min(METER_WIDTH);
+            format!("{} {}{} {:3.0}%", deck + 1, "█".repeat(lit), "·".repeat(METER_WIDTH - lit), trackr.volume * 100.0)
+        }).collect();
+        let crossfader = match self.crossfader {
+            Some(position) => {
+                let knob = (position.max(0.0).min(1.0) * (CROSSFADER_WIDTH - 1) as f64).round() as usize;
+                format!("├{}●{}┤", "─".repeat(knob), "─".repeat(CROSSFADER_WIDTH - 1 - knob))
+            },
+            None => "not used".to_string(),
+        };
+        let even : Vec<String> = (0 .. decks.len()).filter(|d| d % 2 == 0).map(|d| (d + 1).to_string()).collect();
+        let odd : Vec<String> = (0 .. decks.len()).filter(|d| d % 2 == 1).map(|d| (d + 1).to_string()).collect();
+        let text = format!("{}\nCrossfader {} {} {}", meters.join("   "), even.join(" "), crossfader, odd.join(" "));
+        Paragraph::default()
+            .block(Block::default()
+                   .title("Mixer")
+                   .borders(Borders::ALL))
+            .wrap(false)
+            .text(&text)
+            .render(t, chunk);
+    }
+}
+
 impl DebugRender {
     fn new() -> DebugRender {
         DebugRender {
@@ -235,11 +664,10 @@ impl DebugRender {
     }
 }
 
-impl<'a> LibraryRender<'a> {
-    fn new(library: &Library) -> LibraryRender<'a> {
+impl LibraryRender {
+    fn new(library: &Library, columns: Columns) -> LibraryRender {
         let tracks_ : &HashMap<usize, Track> = &library.tracks;
-        let headers = vec!["Idx", "Id", "Title", "Artist", "Key", "Bpm"];
-        let tracks : HashMap<usize, Vec<String>> = tracks_.iter().map(|(i, track) : (&usize, &Track) | (*i, headers[1..].iter().map(|header| track.get(header)).collect())).collect();
+        let tracks : HashMap<usize, Vec<String>> = tracks_.iter().map(|(i, track) : (&usize, &Track) | (*i, columns.shown.iter().map(|header| track.get(header)).collect())).collect();
 
         let root_playlist = library.root_playlist.unwrap();
         let pl_names : Vec<String> = library.playlists.iter().map(|pl| pl.name.clone()).collect();
@@ -256,7 +684,8 @@ impl<'a> LibraryRender<'a> {
             tracks: tracks,
             items: HashMap::new(),
             item_indexes: vec![],
-            headers: headers,
+            columns: columns,
+            column: 0,
             selected: 0,
             top: 0,
             bottom: 0,
@@ -264,6 +693,7 @@ impl<'a> LibraryRender<'a> {
             playlists: playlists,
             playlist_stack: vec![root_playlist],
             playlist_names: pl_names,
+            search: String::new(),
         };
         out.set_playlist(root_playlist);
         return out;
@@ -281,6 +711,9 @@ impl<'a> LibraryRender<'a> {
         let cur_pl_id = self.playlist_stack.last().unwrap().clone();
         let cur_pl = self.playlists[&cur_pl_id].clone();
         let is_tracklist = cur_pl.0;
+        if self.item_indexes.len() == 0 {
+            return;
+        }
         match  is_tracklist {
             true => tx.send(PlayerCommand::Open((*library).get(self.selected_id))).unwrap(),
             false => {
@@ -294,15 +727,132 @@ impl<'a> LibraryRender<'a> {
         }
     }
 
+    fn load(&self, deck: usize, tx: &mpsc::Sender<PlayerCommand>, library: &mut Library) {
+        let cur_pl_id = self.playlist_stack.last().unwrap().clone();
+        if self.playlists[&cur_pl_id].0 && self.item_indexes.len() > 0 {
+            tx.send(PlayerCommand::Deck(deck, Box::new(PlayerCommand::Open((*library).get(self.selected_id))))).unwrap();
+        }
+    }
+
+    // Shows search results as a playlist on top of the one they were searched from
+    fn search(&mut self, query: &str, ids: Vec<usize>) {
+        let items = ids.iter().filter_map(|id| self.tracks.get(id).map(|row| (*id, row.clone()))).collect();
+        self.playlists.insert(SEARCH, (true, items));
+        if self.playlist_stack.last() != Some(&SEARCH) {
+            self.playlist_stack.push(SEARCH);
+        }
+        self.search = query.to_string();
+        self.set_playlist(SEARCH);
+    }
+
     fn set_playlist(&mut self, id: usize) {
         self.items = self.playlists[&id].1.clone();
-        let sort_column = if self.items.values().next().unwrap().len()>=3 {3} else {1};
-        let mut indexes : Vec<(&usize, &Vec<String>)> = self.items.iter().collect();
-        indexes.sort_unstable_by(|&(_, val1), &(_, val2)| val1[sort_column].cmp(&val2[sort_column]));
-        self.item_indexes = indexes.iter().map(|x| *x.0).collect();
+        let mut indexes : Vec<usize> = self.items.keys().cloned().collect();
+        indexes.sort();
+        // playlists by name, tracks by the sort column if it is shown
+        let sort_column = if self.playlists[&id].0 { self.columns.shown.iter().position(|f| *f == self.columns.sort) } else { Some(1) };
+        if let Some(column) = sort_column {
+            let items = &self.items;
+            indexes.sort_by(|a, b| compare(&items[a][column], &items[b][column]));
+            if self.columns.descending && self.playlists[&id].0 {
+                indexes.reverse();
+            }
+        }
+        self.item_indexes = indexes;
+        self.top = 0;
         self.bottom = min(self.items.len(), self.size.height as usize);
         self.selected = 0;
-        self.selected_id = self.item_indexes[self.selected];
+        self.selected_id = self.item_indexes.get(0).cloned().unwrap_or(0);
+    }
+
+    fn column(&mut self, cmd: ColumnCommand, library: &Library) -> Result<(), String> {
+        let last = self.columns.shown.len() as i32 - 1;
+        let column = self.column;
+        match cmd {
+            ColumnCommand::Select(step) => {
+                self.column = min(max(column as i32 + step, 0), last) as usize;
+                return Ok(());
+            },
+            ColumnCommand::Move(step) => {
+                self.column = min(max(column as i32 + step, 0), last) as usize;
+                self.columns.shown.swap(column, self.column);
+            },
+            ColumnCommand::Change(step) => {
+                if let Some(field) = self.columns.next_field(&self.columns.shown[column], step) {
+                    self.columns.shown[column] = field;
+                }
+            },
+            ColumnCommand::Add => {
+                if let Some(field) = self.columns.next_field(&self.columns.shown[column], 1) {
+                    self.columns.shown.insert(column + 1, field);
+                    self.column += 1;
+                }
+            },
+            ColumnCommand::Remove => {
+                if last > 0 {
+                    self.columns.shown.remove(column);
+                    self.column = min(column, last as usize - 1);
+                }
+            },
+            ColumnCommand::Sort => {
+                if self.columns.sort == self.columns.shown[column] {
+                    self.columns.descending = !self.columns.descending;
+                }
+                else {
+                    self.columns.sort = self.columns.shown[column].clone();
+                    self.columns.descending = false;
+                }
+            },
+        }
+        self.set_columns(library);
+        self.columns.save()
+    }
+
+    // Fills the track rows again after the columns changed, keeping the selected row
+    fn set_columns(&mut self, library: &Library) {
+        let shown = &self.columns.shown;
+        self.tracks = library.tracks.iter().map(|(i, track)| (*i, shown.iter().map(|header| track.get(header)).collect())).collect();
+        for &mut (is_tracklist, ref mut items) in self.playlists.values_mut() {
+            if is_tracklist {
+                for (id, row) in items.iter_mut() {
+                    if let Some(track_row) = self.tracks.get(id) {
+                        *row = track_row.clone();
+                    }
+                }
+            }
+        }
+        let selected_id = self.selected_id;
+        let playlist_id = *self.playlist_stack.last().unwrap();
+        self.set_playlist(playlist_id);
+        if let Some(i) = self.item_indexes.iter().position(|id| *id == selected_id) {
+            self.shift_select(i as isize);
+        }
+    }
+
+    fn headers(&self) -> Vec<String> {
+        let mut headers = vec!["Idx".to_string()];
+        for (i, field) in self.columns.shown.iter().enumerate() {
+            let mut header = field.clone();
+            if *field == self.columns.sort {
+                header.push_str(if self.columns.descending { " v" } else { " ^" });
+            }
+            headers.push(if i == self.column { format!("[{}]", header) } else { header });
+        }
+        headers
+    }
+
+    fn widths(&self, width: u16) -> Vec<u16> {
+        let headers = self.headers();
+        let fixed : Vec<Option<u16>> = self.columns.shown.iter().zip(headers[1 ..].iter())
+            .map(|(field, header)| column_width(field).map(|w| max(w, header.chars().count() as u16))).collect();
+        // a title is worth two of the other flexible columns
+        let weight = |field: &str| if field == "Title" { 2 } else { 1 };
+        let weights : u16 = self.columns.shown.iter().zip(fixed.iter()).filter(|&(_, w)| w.is_none()).map(|(f, _)| weight(f)).sum();
+        let used : u16 = 4 + fixed.iter().map(|w| w.unwrap_or(0)).sum::<u16>() + self.columns.shown.len() as u16 + 3;
+        let share = width.saturating_sub(used) / max(weights, 1);
+        let mut widths = vec![4];
+        widths.extend(self.columns.shown.iter().zip(fixed.iter()).map(|(field, w)| w.unwrap_or(share * weight(field))));
+        widths
     }
 
     fn shift_select(&mut self, vector: isize) {
@@ -311,6 +861,9 @@ impl<'a> LibraryRender<'a> {
         let bot = self.bottom as isize;
         let mut selected = self.selected as isize;
         let items = self.items.len() as isize;
+        if items == 0 {
+            return;
+        }
         //self.selected = ((items + selected + vector) % items) as usize;
         self.selected = min(max(selected + vector, 0), items-1) as usize;
         selected = self.selected as isize;
@@ -353,7 +906,7 @@ impl<'a> LibraryRender<'a> {
             .render(t, chunk, |t, chunks| {
                 Tabs::default()
                     .block(Block::default().borders(Borders::ALL).title("Library"))
-                    .titles(&self.playlist_stack.iter().map(|k| self.playlist_names[*k].clone()).collect::<Vec<String>>()[..])
+                    .titles(&self.playlist_stack.iter().map(|k| if *k == SEARCH { format!("/{}", self.search) } else { self.playlist_names[*k].clone() }).collect::<Vec<String>>()[..])
                     .style(Style::default().fg(Color::Green))
                     .highlight_style(Style::default().fg(Color::Yellow))
                     .select(self.playlist_stack.len()-1)
@@ -367,8 +920,10 @@ impl<'a> LibraryRender<'a> {
                 let selected_style = Style::default().fg(Color::Yellow).modifier(Modifier::Bold);
                 let normal_style = Style::default().fg(Color::White);
                 let row_ids : Vec<Vec<String>> = (self.top..self.bottom).into_iter().map(|i| vec![i.to_string()]).collect();
+                let headers = if is_tracklist { self.headers() } else { vec!["Idx".to_string(), "Id".to_string(), "Name".to_string()] };
+                let widths = if is_tracklist { self.widths(chunk.width) } else { vec![4, 4, chunk.width.saturating_sub(14)] };
                 Table::new(
-                    if is_tracklist {self.headers.iter()} else {["Idx", "Id", "Name"].iter()},
+                    headers.iter(),
                     self.item_indexes[self.top .. self.bottom].iter().enumerate().map(|(i_, key)| {
                         let i = self.top + i_;
                         let item = &self.items[key];
@@ -378,68 +933,219 @@ impl<'a> LibraryRender<'a> {
                         Row::StyledData(iter, style)
                     })
                     ).block(Block::default().borders(Borders::ALL).title(if is_tracklist {"Tracks"} else {"Playlists"}))
-                    .widths(&(if is_tracklist {[
-                            4, 
-                            4,
-                            ((chunk.width-10-6-6) as f32 * 2.0/3.0) as u16, 
-                            ((chunk.width-10-6-6) as f32 * 1.0/3.0) as u16, 
-                            3, 
-                            3]} else {[4,4,chunk.width-14,0,0,0]}))
+                    .widths(&widths)
                     .render(t, chunk);
             });
     }
 }
 
-fn handle_keyboard(txui: &mpsc::Sender<UICommand>, txplayer: &mpsc::Sender<PlayerCommand>, key: termion::event::Key) -> bool {
-    match key {
-        event::Key::Char('q') => {
-            txui.send(UICommand::Quit).unwrap();
-            return false;
+fn key_name(key: event::Key) -> Option<String> {
+    Some(match key {
+        event::Key::Char(' ') => "Space".to_string(),
+        event::Key::Char('\n') => "Enter".to_string(),
+        event::Key::Char('\t') => "Tab".to_string(),
+        event::Key::Char(c) => c.to_string(),
+        event::Key::Ctrl(c) => format!("Ctrl-{}", c),
+        event::Key::Alt(c) => format!("Alt-{}", c),
+        event::Key::F(n) => format!("F{}", n),
+        event::Key::Esc => "Esc".to_string(),
+        event::Key::Backspace => "Backspace".to_string(),
+        event::Key::Up => "Up".to_string(),
+        event::Key::Down => "Down".to_string(),
+        event::Key::Left => "Left".to_string(),
+        event::Key::Right => "Right".to_string(),
+        event::Key::Home => "Home".to_string(),
+        event::Key::End => "End".to_string(),
+        event::Key::PageUp => "PageUp".to_string(),
+        event::Key::PageDown => "PageDown".to_string(),
+        event::Key::Insert => "Insert".to_string(),
+        event::Key::Delete => "Delete".to_string(),
+        _ => return None,
+    })
+}
+
+// What typed characters go to instead of the keymap
+enum Typing {
+    Search,
+    CueName,
+}
+
+impl Typing {
+    fn typed(&self, text: String) -> UICommand {
+        match *self {
+            Typing::Search => UICommand::Search(Some(text)),
+            Typing::CueName => UICommand::Cues(CueCommand::Name(Some(text))),
         }
-        event::Key::Down => txui.send(UICommand::Scroll(1)).unwrap(),
-        event::Key::Up => txui.send(UICommand::Scroll(-1)).unwrap(),
-        event::Key::Char('\n') => txui.send(UICommand::Enter).unwrap(),
-        event::Key::Char(' ') => txplayer.send(PlayerCommand::PlayPause).unwrap(),
-        event::Key::Backspace => txui.send(UICommand::Back).unwrap(),
-        //event::Key::Char('c') => txplayer.send(PlayerCommand::Cue).unwrap(),
-        //event::Key::Char('1') => txplayer.send(PlayerCommand::HotCue(0)).unwrap(),
-        //event::Key::Char('2') => txplayer.send(PlayerCommand::HotCue(1)).unwrap(),
-        //event::Key::Char('3') => txplayer.send(PlayerCommand::HotCue(2)).unwrap(),
-        //event::Key::Char('4') => txplayer.send(PlayerCommand::HotCue(3)).unwrap(),
-        //event::Key::Char('5') => txplayer.send(PlayerCommand::HotCue(4)).unwrap(),
-        //event::Key::Char('6') => txplayer.send(PlayerCommand::HotCue(5)).unwrap(),
-        //event::Key::Char('7') => txplayer.send(PlayerCommand::HotCue(6)).unwrap(),
-        //event::Key::Char('8') => txplayer.send(PlayerCommand::HotCue(7)).unwrap(),
-        _ => {}
+    }
+
+    fn done(&self) -> Vec<UICommand> {
+        match *self {
+            Typing::Search => vec![UICommand::Search(None)],
+            Typing::CueName => vec![UICommand::Cues(CueCommand::Rename)],
+        }
+    }
+
+    fn cancel(&self) -> Vec<UICommand> {
+        match *self {
+            Typing::Search => vec![UICommand::Search(None), UICommand::Back],
+            Typing::CueName => vec![UICommand::Cues(CueCommand::Name(None))],
+        }
+    }
+}
+
+// While searching or naming a cue, typed characters go to the text instead of the keymap. Enter keeps it,
+// Esc or Backspace on an empty text go back, searches to where they started.
+fn handle_keyboard(txui: &mpsc::Sender<UICommand>, txplayer: &mpsc::Sender<PlayerCommand>, keymap: &Keymap,
+                   typing: &mut Option<(Typing, String)>, key: termion::event::Key) -> bool {
+    if let Some((kind, mut typed)) = typing.take() {
+        match key {
+            event::Key::Char('\n') => {
+                for cmd in kind.done() {
+                    txui.send(cmd).unwrap();
+                }
+                return true;
+            },
+            event::Key::Esc | event::Key::Backspace if typed.len() == 0 || key == event::Key::Esc => {
+                for cmd in kind.cancel() {
+                    txui.send(cmd).unwrap();
+                }
+                return true;
+            },
+            event::Key::Backspace => {
+                typed.pop();
+                txui.send(kind.typed(typed.clone())).unwrap();
+                *typing = Some((kind, typed));
+                return true;
+            },
+            event::Key::Char(c) => {
+                typed.push(c);
+                txui.send(kind.typed(typed.clone())).unwrap();
+                *typing = Some((kind, typed));
+                return true;
+            },
+            _ => *typing = Some((kind, typed)),
+        }
+    }
+    let name = match key_name(key) {
+        Some(name) => name,
+        None => return true,
     };
+    for cmd in keymap.commands(&name) {
+        match cmd {
+            KeyCommand::Player(cmd) => txplayer.send(cmd).unwrap(),
+            KeyCommand::UI(UICommand::Quit) => {
+                txui.send(UICommand::Quit).unwrap();
+                return false;
+            },
+            KeyCommand::UI(UICommand::Search(typed)) => {
+                *typing = typed.clone().map(|typed| (Typing::Search, typed));
+                txui.send(UICommand::Search(typed)).unwrap();
+            },
+            KeyCommand::UI(UICommand::Cues(CueCommand::Name(typed))) => {
+                *typing = typed.clone().map(|typed| (Typing::CueName, typed));
+                txui.send(UICommand::Cues(CueCommand::Name(typed))).unwrap();
+            },
+            KeyCommand::UI(cmd) => txui.send(cmd).unwrap(),
+        }
+    }
     true
 }
 
+fn handle_status(status : PlayerStatus, app : &mut App, library: &mut Library) {
+    match status {
+        PlayerStatus::Deck(deck, status) => {
+            match *status {
+                PlayerStatus::Print(msg) => app.debugr.println(msg),
+                // hot cues set or deleted on the deck go to the library
+                PlayerStatus::HotCues(ref hotcues) if deck < app.decks.len() => {
+                    let mut synced = None;
+                    if let Some(ref mut track) = app.decks[deck].track {
+                        if track.sync_hotcues(hotcues) {
+                            synced = Some((track.id, track.cues.clone()));
+                        }
+                    }
+                    if let Some((id, cues)) = synced {
+                        for trackr in app.decks.iter_mut() {
+                            if let Some(ref mut track) = trackr.track {
+                                if track.id == id {
+                                    track.cues = cues.clone();
+                                }
+                            }
+                        }
+                        if let Err(e) = library.set_cues(id, cues) {
+                            app.debugr.println(e);
+                        }
+                    }
+                    app.decks[deck].update(PlayerStatus::HotCues(hotcues.clone()));
+                },
+                status => if let Some(trackr) = app.decks.get_mut(deck) { trackr.update(status) },
+            }
+        },
+        PlayerStatus::SelectedDeck(deck) => {
+            app.deck = deck;
+            app.debugr.println(format!("Deck {} selected", deck + 1));
+        },
+        PlayerStatus::Master(deck) => app.master = deck,
+        PlayerStatus::Crossfader(position) => app.mixerr.crossfader = Some(position),
+        PlayerStatus::Print(msg) => app.debugr.println(msg),
+        PlayerStatus::Sampler(slot, name, playing) => app.debugr.println(
+            format!("Sampler {}: {} {}", slot + 1, name.unwrap_or("-".to_string()), if playing {"playing"} else {"stopped"})),
+        PlayerStatus::Aux(gain, muted, talkover, ducking) => app.debugr.println(
+            format!("Aux: gain {:.2}{}{}{}", gain, if muted {" muted"} else {""}, if talkover {" talkover"} else {""}, if ducking {" ducking"} else {""})),
+        PlayerStatus::AutoDJ(running, paused, left) => app.debugr.println(
+            format!("Auto DJ: {} ({} tracks left)", if !running {"stopped"} else if paused {"paused"} else {"running"}, left)),
+        _ => (),
+    }
+}
+
 fn handle_event(cmd : UICommand, app : &mut App, tx: &mpsc::Sender<PlayerCommand>, library: &mut Library) -> bool {
     match cmd {
         UICommand::Enter => app.libraryr.select(tx, library),
+        UICommand::LoadDeck(deck) => app.libraryr.load(deck, tx, library),
+        UICommand::Columns(cmd) => {
+            if let Err(e) = app.libraryr.column(cmd, library) {
+                app.debugr.println(e);
+            }
+        },
+        UICommand::Cues(cmd) => {
+            if let Err(e) = app.cue(cmd, tx, library) {
+                app.debugr.println(e);
+            }
+        },
+        UICommand::Search(Some(query)) => {
+            let ids = library.search(&query);
+            app.libraryr.search(&query, ids);
+        },
+        UICommand::Back if app.learning.is_some() => app.set_learning(None),
         UICommand::Back => app.libraryr.back(),
         UICommand::Scroll(value) => {
-            app.libraryr.shift_select(value as isize);
+            match app.learning {
+                Some(i) => app.set_learning(Some(max(i as isize + value as isize, 0) as usize)),
+                None => app.libraryr.shift_select(value as isize),
+            }
+        },
+        UICommand::Controllers(connected) => app.debugr.println(
+            if connected.len() == 0 { "No controllers".to_string() } else { format!("Controllers: {}", connected.join(", ")) }),
+        UICommand::Learn(target) => app.set_learning(target),
+        UICommand::Learned(target, mapping, error) => {
+            match error {
+                Some(e) => app.debugr.println(format!("Learned {} in {}, but could not save it: {}", target, mapping, e)),
+                None => app.debugr.println(format!("Learned {} in {}", target, mapping)),
+            }
+            // Carry on with the next target, so a row of pads is learned by pressing them in turn
+            if let Some(i) = app.learning {
+                let next = if i + 1 < app.learn_targets.len() { Some(i + 1) } else { None };
+                app.set_learning(next);
+            }
         },
         UICommand::Quit => {
             app.destr();
             return false;
         },
-        UICommand::ForwardStatus(playerstatus) => {
-            match playerstatus {
-                PlayerStatus::Pos(pos, sample_pos) => {
-                    app.trackr.position = Duration_::new(pos);
-                    app.trackr.sample_pos = sample_pos;
-                }
-                PlayerStatus::TrackInfo(track, duration, _sample_rate_) => { 
-                    app.trackr.track = track;  
-                    app.trackr.duration = Duration_::new(duration);
-                }
-                PlayerStatus::Speed(speed) => app.trackr.speed = speed,
-                PlayerStatus::Print(msg) => app.debugr.println(msg),
-                _ => (),
-            }
+        UICommand::ForwardStatus(playerstatus) => handle_status(playerstatus, app, library),
+        UICommand::AutoDJStart => {
+            let playlist = *app.libraryr.playlist_stack.last().unwrap();
+            tx.send(PlayerCommand::AutoDJ(AutoDJCommand::Start(playlist))).unwrap();
         },
         UICommand::PitchRange(low, high) => app.debugr.println(format!("Pitch range: [{} -> {}]", low, high)), 
         UICommand::Print(msg) => app.debugr.println(msg),
@@ -448,10 +1154,10 @@ fn handle_event(cmd : UICommand, app : &mut App, tx: &mpsc::Sender<PlayerCommand
     true
 }
 
-pub fn run(tx : mpsc::Sender<PlayerCommand>, rx_r : mpsc::Receiver<PlayerStatus>, rxui: mpsc::Receiver<UICommand>, txui: mpsc::Sender<UICommand>, library: Arc<Mutex<Library>>) {
+pub fn run(tx : mpsc::Sender<PlayerCommand>, rx_r : mpsc::Receiver<PlayerStatus>, rxui: mpsc::Receiver<UICommand>, txui: mpsc::Sender<UICommand>, library: Arc<Mutex<Library>>, learn: Learn) {
 
     // App
-    let app_ = Arc::new(Mutex::new(App::new(&library.lock().unwrap()))); 
+    let app_ = Arc::new(Mutex::new(App::new(&library.lock().unwrap(), learn))); 
     {
         let mut app = app_.lock().unwrap();
         // First draw call
@@ -465,9 +1171,11 @@ pub fn run(tx : mpsc::Sender<PlayerCommand>, rx_r : mpsc::Receiver<PlayerStatus>
     let tx_ = tx.clone();
     // KEYBOARD
     thread::spawn(move || {
+        let keymap = Keymap::load();
+        let mut typing = None;
         let stdin = io::stdin();
         for c in stdin.keys() {
-            if !handle_keyboard(&txui_, &tx_, c.unwrap()) {
+            if !handle_keyboard(&txui_, &tx_, &keymap, &mut typing, c.unwrap()) {
                 break;
             }
         }
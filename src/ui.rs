@@ -11,7 +11,12 @@ use library::{Library, Track, LibraryCommand, LibraryResponse};
 use mp3playerjack::{PlayerCommand, PlayerStatus};
 
 use wsui;
+use midi;
+use hid;
+use osc;
+use controller::{Feedback, Learn, SharedFeedback};
 use textui;
+use textui::{ColumnCommand, CueCommand};
 
 pub enum UIType {
     Simple,
@@ -19,7 +24,7 @@ pub enum UIType {
     WS,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub enum UICommand {
     Enter,
     Back,
@@ -27,7 +32,21 @@ pub enum UICommand {
     Quit,
     PitchRange(f64, f64),
     Print(String),
+    AutoDJStart,
+    Search(Option<String>), //library search typed in the TUI, None leaves it
+    LoadDeck(usize), //loads the selected library track on a deck
+    Columns(ColumnCommand), //track table columns of the TUI
+    Cues(CueCommand), //cues of the track on the selected deck in the TUI
+    #[serde(skip_deserializing)]
+    Controllers(Vec<String>), //mapping and device port of every attached controller
+    Learn(Option<usize>), //learn target, None leaves learn mode
+    #[serde(skip_deserializing)]
+    LearnTargets(Vec<String>),
+    #[serde(skip_deserializing)]
+    Learned(String, String, Option<String>), //target, mapping, error saving it
+    #[serde(skip_deserializing)]
     ForwardStatus(PlayerStatus),
+    #[serde(skip_deserializing)]
     ForwardLibrary(LibraryResponse)
 }
 
@@ -37,6 +56,8 @@ pub enum UIBackCommand {
     ForwardPlayerCommand(PlayerCommand),
     SetupMIDI(String),
     MIDI(String, [u8;3]),
+    LearnTargets,
+    Learn(Option<usize>),
 }
 
 impl UICommand {
@@ -56,11 +77,31 @@ impl UIBackCommand {
 
 pub fn run(ui_type: UIType, tx : mpsc::Sender<PlayerCommand>, rx_r : mpsc::Receiver<PlayerStatus>, library: Arc<Mutex<Library>>) {
     let (txui, rxui) = mpsc::channel::<UICommand>();
-    midi(tx.clone(), txui.clone());
+    let learn : Learn = Arc::new(Mutex::new(None));
+    let feedback : SharedFeedback = Arc::new(Mutex::new(Feedback::new()));
+    midi::run(tx.clone(), txui.clone(), learn.clone(), feedback.clone());
+    hid::run(tx.clone(), txui.clone(), learn.clone(), feedback.clone());
+    let mut osc = osc::run(tx.clone(), txui.clone());
+
+    // Player status passes the controller feedback and OSC on its way to the UI
+    let (tx_status, rx_r_) = mpsc::channel::<PlayerStatus>();
+    let feedback_ = feedback.clone();
+    thread::spawn(move || {
+        for status in rx_r.iter() {
+            feedback_.lock().unwrap().update(&status);
+            if let Some(ref mut osc) = osc {
+                osc.update(&status);
+            }
+            if tx_status.send(status).is_err() {
+                break;
+            }
+        }
+    });
+    let rx_r = rx_r_;
     match ui_type {
         UIType::Simple => text(tx, rx_r, rxui, library),
-        UIType::Tui => textui::run(tx, rx_r, rxui, txui, library),
-        UIType::WS => wsui::run(tx, rx_r, rxui, txui, library),
+        UIType::Tui => textui::run(tx, rx_r, rxui, txui, library, learn),
+        UIType::WS => wsui::run(tx, rx_r, rxui, txui, library, learn, feedback),
     }
 }
 
@@ -72,8 +113,14 @@ fn text(mut tx : mpsc::Sender<PlayerCommand>, rx_r : mpsc::Receiver<PlayerStatus
         let mut duration = Duration::from_secs(0);
         let mut speed = 1.0;
         let mut track : Option<Track> = None;
+        let mut deck = 0;
         loop {
             if let Ok(cmd) = rx_r.recv() {
+                let cmd = match cmd {
+                    PlayerStatus::Deck(deck_, status) => if deck_ == deck { *status } else { continue },
+                    PlayerStatus::SelectedDeck(deck_) => { deck = deck_; continue },
+                    cmd => cmd,
+                };
                 match cmd {
                     PlayerStatus::Pos(pos_, sample_pos_) => { pos = pos_; sample_pos = sample_pos_; },
                     PlayerStatus::TrackInfo(track_, duration_, _sample_rate_) => { track = track_; duration = duration_; }
@@ -130,54 +177,3 @@ fn text(mut tx : mpsc::Sender<PlayerCommand>, rx_r : mpsc::Receiver<PlayerStatus
         };
     }
 }
-
-use jack::prelude::{AsyncClient, MidiInPort, MidiInSpec, Client, ClosureProcessHandler,
-                    JackControl, ProcessScope, client_options, PortFlags, PortSpec};
-use controller::Controller;
-fn midi(tx : mpsc::Sender<PlayerCommand>, txui: mpsc::Sender<UICommand>) {
-    let client = Client::new("rustydj_midi", client_options::NO_START_SERVER).unwrap().0;
-    let port_names = client.ports(None, Some(MidiInSpec::default().jack_port_type()), PortFlags::from_bits(0x4|0x2).unwrap());
-    let ports = port_names.iter().filter_map(|name| {client.port_by_name(name)});
-    println!("Midi sources:"); 
-    let controllers = Box::new(
-            ports.filter_map(|port| {
-                let name = port.name().to_string();
-                let names = port.aliases();
-                println!("{} ({:?})", name, names);
-                match Controller::new(names) {
-                    Some(ctrl) => Some((name, ctrl)),
-                    None => None,
-                }
-            }).collect::<Vec<(String, Controller)>>()
-            );
-
-
-    if controllers.len() == 0 {
-        println!("Warning no midi device");
-        return;
-    }
-
-    let controllers = Box::into_raw(controllers);
-    for &mut(ref name, ref mut ctrl) in unsafe {(*controllers).iter_mut()} { 
-        let tx_ = tx.clone();
-        let txui_ = txui.clone();
-
-        let client = Client::new(&format!("rustydj_midi_{}",name), client_options::NO_START_SERVER).unwrap().0;
-        let mut port = client.register_port("in", MidiInSpec::default()).unwrap();
-        let port_name = port.name().to_string();
-
-        let process = ClosureProcessHandler::new(move |_client: &Client, ps: &ProcessScope| -> JackControl {
-            let buffer = MidiInPort::new(&mut port, ps);
-
-            for elem in buffer.iter() {
-                ctrl.handle_midi(elem.bytes, &tx_, &txui_);
-            }
-
-            JackControl::Continue
-        });
-        let active_client = Box::new(AsyncClient::new(client, (), process).unwrap());
-        println!("Connect {} -> {}", name, port_name);
-        active_client.connect_ports_by_name(&name, &port_name).unwrap();
-        Box::into_raw(active_client);
-    }
-}
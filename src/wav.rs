@@ -0,0 +1,222 @@
+extern crate simplemad;
+
+use simplemad::{Frame, MadFixed32};
+use std::fs::File;
+use std::io::Read;
+use std::time::Duration;
+
+const FRAME_SAMPLES : usize = 1152;
+
+fn u16_at(data: &[u8], pos: usize) -> u16 {
+    data[pos] as u16 | (data[pos+1] as u16) << 8
+}
+
+fn u32_at(data: &[u8], pos: usize) -> u32 {
+    u16_at(data, pos) as u32 | (u16_at(data, pos+2) as u32) << 16
+}
+
+fn samples_to_duration(samples: usize, sample_rate: u32) -> Duration {
+    let secs = samples as u64 / sample_rate as u64;
+    let rest = samples as u64 % sample_rate as u64;
+    Duration::new(secs, (rest * 1_000_000_000 / sample_rate as u64) as u32)
+}
+
+// Decodes a PCM (16/24/32 bit) or 32 bit float wav file into frames shaped like the mp3 decoder output
+pub fn decode(filename: &str) -> Result<Vec<Frame>, String> {
+    let mut data = Vec::new();
+    File::open(filename).and_then(|mut f| f.read_to_end(&mut data)).map_err(|e| format!("{}: {}", filename, e))?;
+    parse(&data, filename)
+}
+
+fn parse(data: &[u8], filename: &str) -> Result<Vec<Frame>, String> {
+    if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"WAVE" {
+        return Err(format!("{}: not a wav file", filename));
+    }
+
+    let mut format = None;
+    let mut samples = None;
+    let mut pos = 12;
+    while pos + 8 <= data.len() {
+        let id = &data[pos .. pos+4];
+        let len = u32_at(data, pos+4) as usize;
+        let body = pos + 8;
+        let end = if body + len > data.len() { data.len() } else { body + len };
+        match id {
+            b"fmt " => {
+                if len < 16 || body + 16 > data.len() {
+                    return Err(format!("{}: truncated fmt chunk", filename));
+                }
+                let mut tag = u16_at(data, body);
+                // WAVE_FORMAT_EXTENSIBLE, the format tag is the start of the SubFormat GUID
+                if tag == 0xFFFE {
+                    if len < 40 || body + 40 > data.len() {
+                        return Err(format!("{}: truncated fmt chunk", filename));
+                    }
+                    tag = u16_at(data, body+24);
+                }
+                format = Some((
+                    tag,                    //format tag
+                    u16_at(data, body+2),   //channels
+                    u32_at(data, body+4),   //sample rate
+                    u16_at(data, body+14),  //bits per sample
+                    ));
+            },
+            b"data" => samples = Some(&data[body .. end]),
+            _ => (),
+        }
+        pos = body + len + (len & 1);
+    }
+
+    let (tag, channels, sample_rate, bits) = format.ok_or(format!("{}: missing fmt chunk", filename))?;
+    let samples = samples.ok_or(format!("{}: missing data chunk", filename))?;
+    let float = match (tag, bits) {
+        (1, 16) | (1, 24) | (1, 32) => false,
+        (3, 32) => true,
+        _ => return Err(format!("{}: unsupported wav format {} with {} bits", filename, tag, bits)),
+    };
+    if channels == 0 || sample_rate == 0 {
+        return Err(format!("{}: invalid wav header", filename));
+    }
+
+    let width = bits as usize / 8;
+    let block = width * channels as usize;
+    let read = |offset: usize| -> f32 {
+        let b = &samples[offset .. offset + width];
+        match (float, width) {
+            (true, _) => f32::from_bits(u32_at(b, 0)),
+            (false, 2) => u16_at(b, 0) as i16 as f32 / 32768.0,
+            (false, 3) => ((u32_at(&[0, b[0], b[1], b[2]], 0)) as i32 >> 8) as f32 / 8388608.0,
+            (false, _) => u32_at(b, 0) as i32 as f32 / 2147483648.0,
+        }
+    };
+
+    let total = samples.len() / block;
+    let right = if channels > 1 { width } else { 0 };
+    let mut frames = Vec::with_capacity(total / FRAME_SAMPLES + 1);
+    let mut start = 0;
+    while start < total {
+        let len = if total - start < FRAME_SAMPLES { total - start } else { FRAME_SAMPLES };
+        let mut l = Vec::with_capacity(len);
+        let mut r = Vec::with_capacity(len);
+        for n in start .. start + len {
+            l.push(MadFixed32::from(read(n * block)));
+            r.push(MadFixed32::from(read(n * block + right)));
+        }
+        frames.push(Frame {
+            sample_rate: sample_rate,
+            bit_rate: sample_rate * bits as u32 * channels as u32,
+            layer: Default::default(),
+            mode: Default::default(),
+            samples: vec![l, r],
+            duration: samples_to_duration(len, sample_rate),
+            position: samples_to_duration(start, sample_rate),
+        });
+        start += len;
+    }
+    Ok(frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wav(tag: u16, channels: u16, sample_rate: u32, bits: u16, samples: &[u8]) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend(b"RIFF");
+        data.extend(&((36 + samples.len()) as u32).to_le_bytes());
+        data.extend(b"WAVEfmt ");
+        data.extend(&16u32.to_le_bytes());
+        data.extend(&tag.to_le_bytes());
+        data.extend(&channels.to_le_bytes());
+        data.extend(&sample_rate.to_le_bytes());
+        data.extend(&(sample_rate * channels as u32 * bits as u32 / 8).to_le_bytes());
+        data.extend(&(channels * bits / 8).to_le_bytes());
+        data.extend(&bits.to_le_bytes());
+        data.extend(b"data");
+        data.extend(&(samples.len() as u32).to_le_bytes());
+        data.extend(samples);
+        data
+    }
+
+    // WAVE_FORMAT_EXTENSIBLE with the format in its SubFormat GUID
+    fn extensible(subformat: u16, channels: u16, sample_rate: u32, bits: u16, samples: &[u8]) -> Vec<u8> {
+        let mut data = wav(0xFFFE, channels, sample_rate, bits, &[]);
+        data.truncate(data.len() - 8);
+        data[16 .. 20].copy_from_slice(&40u32.to_le_bytes());
+        data.extend(&22u16.to_le_bytes()); //extension size
+        data.extend(&bits.to_le_bytes()); //valid bits
+        data.extend(&3u32.to_le_bytes()); //channel mask
+        data.extend(&subformat.to_le_bytes());
+        data.extend(&[0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xAA, 0x00, 0x38, 0x9B, 0x71]);
+        data.extend(b"data");
+        data.extend(&(samples.len() as u32).to_le_bytes());
+        data.extend(samples);
+        data
+    }
+
+    fn left_right(frames: &[Frame]) -> Vec<(f32, f32)> {
+        frames.iter().flat_map(|f| f.samples[0].iter().zip(f.samples[1].iter()).map(|(l, r)| (l.to_f32(), r.to_f32()))).collect()
+    }
+
+    #[test]
+    fn pcm_16() {
+        let samples : Vec<u8> = [16384i16, -16384, 0, 32767].iter().flat_map(|s| s.to_le_bytes().to_vec()).collect();
+        let frames = parse(&wav(1, 2, 44100, 16, &samples), "test").unwrap();
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].sample_rate, 44100);
+        let decoded = left_right(&frames);
+        assert_eq!(decoded.len(), 2);
+        assert!((decoded[0].0 - 0.5).abs() < 1e-4 && (decoded[0].1 + 0.5).abs() < 1e-4, "{:?}", decoded);
+        assert!(decoded[1].0.abs() < 1e-4 && (decoded[1].1 - 1.0).abs() < 1e-3, "{:?}", decoded);
+    }
+
+    #[test]
+    fn pcm_24_mono_frames() {
+        // -0.25 as 24 bit, mono plays on both sides
+        let sample = [0x00, 0x00, 0xE0];
+        let samples : Vec<u8> = (0 .. FRAME_SAMPLES + 10).flat_map(|_| sample.to_vec()).collect();
+        let frames = parse(&wav(1, 1, 48000, 24, &samples), "test").unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].samples[0].len(), FRAME_SAMPLES);
+        assert_eq!(frames[1].samples[0].len(), 10);
+        assert_eq!(frames[1].position, samples_to_duration(FRAME_SAMPLES, 48000));
+        for (l, r) in left_right(&frames) {
+            assert!((l + 0.25).abs() < 1e-4 && (r + 0.25).abs() < 1e-4, "{} {}", l, r);
+        }
+    }
+
+    #[test]
+    fn float_32() {
+        let samples : Vec<u8> = [0.75f32, -0.125].iter().flat_map(|s| s.to_bits().to_le_bytes().to_vec()).collect();
+        let decoded = left_right(&parse(&wav(3, 2, 44100, 32, &samples), "test").unwrap());
+        assert_eq!(decoded.len(), 1);
+        assert!((decoded[0].0 - 0.75).abs() < 1e-4 && (decoded[0].1 + 0.125).abs() < 1e-4, "{:?}", decoded);
+    }
+
+    #[test]
+    fn extensible_32() {
+        let samples : Vec<u8> = [0.75f32, -0.125].iter().flat_map(|s| s.to_bits().to_le_bytes().to_vec()).collect();
+        let decoded = left_right(&parse(&extensible(3, 2, 44100, 32, &samples), "test").unwrap());
+        assert!((decoded[0].0 - 0.75).abs() < 1e-4 && (decoded[0].1 + 0.125).abs() < 1e-4, "{:?}", decoded);
+        let samples : Vec<u8> = [1i32 << 30, -(1i32 << 29)].iter().flat_map(|s| s.to_le_bytes().to_vec()).collect();
+        let decoded = left_right(&parse(&extensible(1, 2, 44100, 32, &samples), "test").unwrap());
+        assert!((decoded[0].0 - 0.5).abs() < 1e-4 && (decoded[0].1 + 0.25).abs() < 1e-4, "{:?}", decoded);
+    }
+
+    #[test]
+    fn rejects_truncated_fmt() {
+        let data = wav(1, 2, 44100, 16, &[]);
+        assert!(parse(&data[.. 30], "test").unwrap_err().contains("truncated fmt chunk"));
+        let data = extensible(3, 2, 44100, 32, &[]);
+        assert!(parse(&data[.. 50], "test").unwrap_err().contains("truncated fmt chunk"));
+    }
+
+    #[test]
+    fn rejects_unsupported() {
+        assert!(parse(&wav(1, 2, 44100, 8, &[0, 0]), "test").unwrap_err().contains("unsupported wav format 1 with 8 bits"));
+        assert!(parse(&wav(2, 2, 44100, 4, &[0, 0]), "test").unwrap_err().contains("unsupported"));
+        assert!(parse(&wav(1, 0, 44100, 16, &[0, 0]), "test").unwrap_err().contains("invalid wav header"));
+        assert!(parse(b"RIFF\0\0\0\0WAVE", "test").unwrap_err().contains("missing fmt chunk"));
+        assert!(parse(b"not a wav", "test").unwrap_err().contains("not a wav file"));
+    }
+}
@@ -0,0 +1,114 @@
+use std::f32::consts::PI;
+
+const BINS_PER_SECOND : u32 = 100;
+const LOW_HZ : f32 = 250.0; // below is low
+const HIGH_HZ : f32 = 2500.0; // above is high, in between mid
+
+// Peaks of the low, mid and high frequencies of a track, scaled to 0-255 by the loudest of them
+#[derive(Debug, Serialize, Clone)]
+pub struct Waveform {
+    pub bin_samples: usize, // samples in a bin
+    pub bins: Vec<(u8, u8, u8)>, // low, mid, high
+}
+
+// One pole lowpass, enough to tell kicks from hats
+struct Lowpass {
+    a: f32,
+    y: f32,
+}
+
+impl Lowpass {
+    fn new(hz: f32, sample_rate: u32) -> Lowpass {
+        Lowpass { a: 1.0 - (-2.0 * PI * hz / sample_rate as f32).exp(), y: 0.0 }
+    }
+
+    fn next(&mut self, x: f32) -> f32 {
+        self.y += self.a * (x - self.y);
+        self.y
+    }
+}
+
+impl Waveform {
+    pub fn analyze<I: Iterator<Item = f32>>(samples: I, sample_rate: u32) -> Waveform {
+        let bin_samples = (sample_rate / BINS_PER_SECOND).max(1) as usize;
+        let mut low = Lowpass::new(LOW_HZ, sample_rate);
+        let mut high = Lowpass::new(HIGH_HZ, sample_rate);
+        let mut peaks : Vec<(f32, f32, f32)> = Vec::new();
+        let mut peak = (0.0f32, 0.0f32, 0.0f32);
+        let mut n = 0;
+        for x in samples {
+            let l = low.next(x);
+            let below_high = high.next(x);
+            peak.0 = peak.0.max(l.abs());
+            peak.1 = peak.1.max((below_high - l).abs());
+            peak.2 = peak.2.max((x - below_high).abs());
+            n += 1;
+            if n == bin_samples {
+                peaks.push(peak);
+                peak = (0.0, 0.0, 0.0);
+                n = 0;
+            }
+        }
+        if n > 0 {
+            peaks.push(peak);
+        }
+        let max = peaks.iter().fold(0.0f32, |m, p| m.max(p.0).max(p.1).max(p.2));
+        let scale = |v: f32| if max > 0.0 { (v / max * 255.0).round() as u8 } else { 0 };
+        Waveform {
+            bin_samples: bin_samples,
+            bins: peaks.into_iter().map(|(l, m, h)| (scale(l), scale(m), scale(h))).collect(),
+        }
+    }
+
+    // Loudest bands of the bins covering [start, end) in samples, at least the bin at start
+    pub fn peak(&self, start: f64, end: f64) -> Option<(u8, u8, u8)> {
+        if start < 0.0 {
+            return None;
+        }
+        let first = start as usize / self.bin_samples;
+        let last = ((end.max(start) as usize + self.bin_samples - 1) / self.bin_samples).max(first + 1).min(self.bins.len());
+        let bins = self.bins.get(first .. last)?;
+        if bins.len() == 0 {
+            return None;
+        }
+        Some(bins.iter().fold((0, 0, 0), |p, b| (p.0.max(b.0), p.1.max(b.1), p.2.max(b.2))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(hz: f32, secs: f32, gain: f32) -> Vec<f32> {
+        (0 .. (secs * 44100.0) as usize).map(|n| (2.0 * PI * hz * n as f32 / 44100.0).sin() * gain).collect()
+    }
+
+    #[test]
+    fn bands() {
+        let mut samples = sine(50.0, 1.0, 1.0);
+        samples.extend(sine(1000.0, 1.0, 1.0));
+        samples.extend(sine(10000.0, 1.0, 1.0));
+        samples.extend(sine(10000.0, 0.5, 0.25));
+        let waveform = Waveform::analyze(samples.into_iter(), 44100);
+        assert_eq!(waveform.bin_samples, 441);
+        assert_eq!(waveform.bins.len(), 350);
+        let (l, m, h) = waveform.bins[50];
+        assert!(l > 200 && m < l / 2 && h < l / 4, "{:?}", (l, m, h));
+        let (l, m, h) = waveform.bins[150];
+        assert!(m > 100 && l < m && h < m, "{:?}", (l, m, h));
+        let (l, m, h) = waveform.bins[250];
+        assert!(h > 150 && l < h / 4 && m < h, "{:?}", (l, m, h));
+        let quiet = waveform.bins[320];
+        assert!(quiet.2 < h / 3, "{:?}", quiet);
+    }
+
+    #[test]
+    fn peak() {
+        let waveform = Waveform { bin_samples: 10, bins: vec![(1, 0, 0), (0, 5, 0), (0, 0, 7), (2, 2, 2)] };
+        assert_eq!(waveform.peak(0.0, 5.0), Some((1, 0, 0)));
+        assert_eq!(waveform.peak(5.0, 25.0), Some((1, 5, 7)));
+        assert_eq!(waveform.peak(30.0, 100.0), Some((2, 2, 2)));
+        assert_eq!(waveform.peak(40.0, 50.0), None);
+        assert_eq!(waveform.peak(-10.0, 5.0), None);
+    }
+}
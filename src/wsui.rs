@@ -10,7 +10,8 @@ use std::thread;
 use mp3playerjack::{PlayerCommand, PlayerStatus};
 use ui::{UICommand,UIBackCommand};
 use library::{Library};
-use controller::Controller;
+use controller::{Controller, Learn, Mapping, SharedFeedback, learn_targets};
+use decks::DECKS;
 use std::collections::HashMap;
 
 struct Server {
@@ -20,6 +21,8 @@ struct Server {
     txui: mpsc::Sender<UICommand>,
     library: Arc<Mutex<Library>>,
     controller: HashMap<String, Controller>,
+    learn: Learn,
+    feedback: SharedFeedback,
 }
 
 impl Server {
@@ -64,48 +67,64 @@ impl Handler for Server {
                 UIBackCommand::ForwardPlayerCommand(playercmd) => self.tx.send(playercmd).unwrap(),
                 UIBackCommand::SetupMIDI(ctrl_name) => { 
                     let ctrl_name_ = ctrl_name.clone();
-                    if let Some(ctrl) = Controller::new(vec![ctrl_name]) {
-                        self.controller.insert(ctrl_name_.clone(), ctrl); 
-                        println!("Added WS Midi controller: {}", ctrl_name_);
-                    }},
+                    let ctrl = Controller::new(vec![ctrl_name], self.learn.clone())
+                        .unwrap_or_else(|| Controller::from_mapping(Mapping::blank(&ctrl_name_), self.learn.clone()));
+                    self.controller.insert(ctrl_name_.clone(), ctrl); 
+                    println!("Added WS Midi controller: {}", ctrl_name_);
+                    },
                 UIBackCommand::MIDI(ctrl_name, bytes) => { 
                     if let Some(ctrl) = self.controller.get_mut(&ctrl_name) { 
-                        ctrl.handle_midi(&bytes, &self.tx, &self.txui);
+                        ctrl.handle_midi(&bytes, &self.feedback.lock().unwrap(), &self.tx, &self.txui);
                     }},
+                UIBackCommand::LearnTargets => {
+                    let labels = learn_targets().into_iter().map(|target| target.label).collect();
+                    self.out.send(UICommand::LearnTargets(labels).serialize())?;
+                },
+                UIBackCommand::Learn(target) => *self.learn.lock().unwrap() = target,
             }
         }
         Ok(())
     }
 }
 
-pub fn run(tx : mpsc::Sender<PlayerCommand>, rx_r : mpsc::Receiver<PlayerStatus>, rxui: mpsc::Receiver<UICommand>, txui: mpsc::Sender<UICommand>, library: Arc<Mutex<Library>>) {
+pub fn run(tx : mpsc::Sender<PlayerCommand>, rx_r : mpsc::Receiver<PlayerStatus>, rxui: mpsc::Receiver<UICommand>, txui: mpsc::Sender<UICommand>, library: Arc<Mutex<Library>>, learn: Learn, feedback: SharedFeedback) {
     // MP3Player Status
     let txui_ = txui.clone();
     thread::spawn(move || {
-        let mut last_speed = 0.0;
-        let mut last_dur = Duration::default();
+        let mut last_speed = [0.0; DECKS];
+        let mut last_dur = [Duration::default(); DECKS];
         let threshold = Duration::from_millis(10);
-        let mut now = Instant::now();
+        let mut now = [Instant::now(); DECKS];
         loop {
             if let Ok(cmd) = rx_r.recv() {
-                match cmd {
-                    PlayerStatus::Pos(dur, sampl) => {
-                        if (dur > last_dur && dur - last_dur > threshold) || 
-                            (dur < last_dur && last_dur - dur > threshold) {
-                            last_dur = dur;
-                            txui_.send(UICommand::ForwardStatus(cmd)).unwrap();
+                let (deck, status) = match cmd {
+                    PlayerStatus::Deck(deck, status) => (deck, *status),
+                    cmd => {
+                        txui_.send(UICommand::ForwardStatus(cmd)).unwrap();
+                        continue;
+                    },
+                };
+                let forward = match status {
+                    PlayerStatus::Pos(dur, _sampl) => {
+                        if (dur > last_dur[deck] && dur - last_dur[deck] > threshold) || 
+                            (dur < last_dur[deck] && last_dur[deck] - dur > threshold) {
+                            last_dur[deck] = dur;
+                            true
                         }
+                        else { false }
                     },
                     PlayerStatus::Speed(speed) => {
-                        if last_speed != speed {
-                            last_speed = speed;
-                            if now.elapsed().subsec_nanos() > 100_000000 || now.elapsed().as_secs() > 0 {
-                                txui_.send(UICommand::ForwardStatus(cmd)).unwrap();
-                                now = Instant::now();
-                            }
+                        if last_speed[deck] != speed && (now[deck].elapsed().subsec_nanos() > 100_000000 || now[deck].elapsed().as_secs() > 0) {
+                            last_speed[deck] = speed;
+                            now[deck] = Instant::now();
+                            true
                         }
+                        else { false }
                     }, 
-                    _ => txui_.send(UICommand::ForwardStatus(cmd)).unwrap(),
+                    _ => true,
+                };
+                if forward {
+                    txui_.send(UICommand::ForwardStatus(PlayerStatus::Deck(deck, Box::new(status)))).unwrap();
                 }
             }
         }
@@ -115,6 +134,6 @@ pub fn run(tx : mpsc::Sender<PlayerCommand>, rx_r : mpsc::Receiver<PlayerStatus>
     
     listen("127.0.0.1:2794", |out| {
     //listen("0.0.0.0:2794", |out| {
-        Server {out: out, rxui: rxui_.clone(), tx: tx.clone(), library: library.clone(), txui: txui.clone(), controller: HashMap::new()}
+        Server {out: out, rxui: rxui_.clone(), tx: tx.clone(), library: library.clone(), txui: txui.clone(), controller: HashMap::new(), learn: learn.clone(), feedback: feedback.clone()}
     }).unwrap();
 }
@@ -1,6 +1,7 @@
 window.trackLength=0;
 window.speed=1.0;
 window.bpm=128.0;
+window.deck=0;
 var waitingToPlayID=0;
 
 var exampleSocket = new WebSocket("ws://10.2.0.175:2794");
@@ -59,6 +60,15 @@ exampleSocket.onmessage = function (event) {
             });
         }
     }
+    else if ("AutoDJStart" === uicmd) {
+        send({
+            ForwardPlayerCommand: {
+                AutoDJ: {
+                    Start: p.playlist.id
+                }
+            }
+        });
+    }
     else if ("Back" === uicmd) {
         id = p.playlist.parent;
         if (id != null) {
@@ -72,7 +82,38 @@ exampleSocket.onmessage = function (event) {
         }
     }
     else if (typeof uicmd === "object") {
-        if ("Scroll" in uicmd) {
+        if ("Controllers" in uicmd) {
+            document.getElementById("controllers").innerHTML = uicmd.Controllers.join(", ");
+        }
+        else if ("LearnTargets" in uicmd) {
+            var select = document.getElementById("learnTarget");
+            select.innerHTML = "";
+            for (let label of uicmd.LearnTargets) {
+                var option = document.createElement("option");
+                option.text = label;
+                select.appendChild(option);
+            }
+            document.getElementById("learn").style.display = "";
+            learnTarget(0);
+        }
+        else if ("Learned" in uicmd) {
+            var learned = uicmd.Learned;
+            var status = "Learned " + learned[0] + " in " + learned[1];
+            if (learned[2] != null) {
+                status += ", could not save: " + learned[2];
+            }
+            document.getElementById("learnStatus").innerHTML = status;
+            // Carry on with the next target, stop after the last one
+            var select = document.getElementById("learnTarget");
+            if (select.selectedIndex + 1 < select.options.length) {
+                select.selectedIndex += 1;
+                learnTarget(select.selectedIndex);
+            }
+            else {
+                learnStop();
+            }
+        }
+        else if ("Scroll" in uicmd) {
             var vector = uicmd.Scroll;
             shift_item(vector); 
         }
@@ -174,7 +215,20 @@ exampleSocket.onmessage = function (event) {
         }
         else if ("ForwardStatus" in uicmd) {
             var statuscmd = uicmd.ForwardStatus;
-            if ("TrackInfo" in statuscmd) {
+            if (typeof statuscmd === "object" && "Deck" in statuscmd) {
+                if (statuscmd.Deck[0] != window.deck) {
+                    return;
+                }
+                statuscmd = statuscmd.Deck[1];
+            }
+            if (typeof statuscmd !== "object") {
+                console.log(uicmd);
+            }
+            else if ("SelectedDeck" in statuscmd) {
+                window.deck = statuscmd.SelectedDeck;
+                document.getElementById("playerID").innerHTML = window.deck + 1;
+            }
+            else if ("TrackInfo" in statuscmd) {
                 var trackinfo = statuscmd.TrackInfo;
                 var track = trackinfo[0];
                 var duration = trackinfo[1];
@@ -201,6 +255,30 @@ exampleSocket.onmessage = function (event) {
     }
 }
 
+function learnStart() {
+    send("LearnTargets");
+}
+
+function learnTarget(index) {
+    document.getElementById("learnTarget").selectedIndex = index;
+    send({
+        Learn: index
+    });
+}
+
+function learnStop() {
+    send({
+        Learn: null
+    });
+    document.getElementById("learn").style.display = "none";
+}
+
+document.addEventListener("keydown", function(event) {
+    if (event.key === "l") {
+        learnStart();
+    }
+});
+
 function initializeUI() {
     clearTime();
     document.getElementById("playhead").style.left="0%";